@@ -6,8 +6,8 @@ mod string_key;
 use criterion::{BenchmarkId, Criterion, Throughput};
 use rand::{thread_rng, Rng};
 use nam_sparse_merkle_tree::{
-    sha256::Sha256Hasher, default_store::DefaultStore,
-    tree::SparseMerkleTree, H256, Hash
+    sha256::Sha256Hasher, default_store::{BuildIdentityHasher, DefaultStore},
+    traits::Store, tree::SparseMerkleTree, H256, Hash
 };
 use string_key::{IBC_KEY_LIMIT, StringKey, random_stringkey};
 
@@ -63,6 +63,26 @@ fn bench_hashes(c: &mut Criterion) {
     }
     group.finish();
 
+    let mut group = c.benchmark_group("ShaSmt update_all");
+    for size in [100, 10_000].iter() {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(size),
+            size,
+            |b, &size| {
+                let mut rng = thread_rng();
+                let leaves: Vec<(Hash, H256)> = (0..size)
+                    .map(|_| (random_h256(&mut rng).into(), random_h256(&mut rng)))
+                    .collect();
+                b.iter(|| {
+                    let mut smt = ShaSmt::default();
+                    smt.update_all(leaves.clone()).unwrap();
+                    smt
+                });
+            }
+        );
+    }
+    group.finish();
+
     let mut group = c.benchmark_group("ShaSmt get");
     for size in [5_000, 10_000].iter() {
         group.bench_with_input(
@@ -150,7 +170,7 @@ fn bench_strings(c: &mut Criterion) {
                 let mut rng = thread_rng();
                 let (smt, _keys) = random_stringsmt(size, &mut rng);
                 b.iter(|| {
-                    let key = random_stringkey(&mut rng).into();
+                    let key = random_stringkey(&mut rng);
                     smt.get(&key).unwrap();
                 });
             }
@@ -204,9 +224,65 @@ fn bench_strings(c: &mut Criterion) {
 
 }
 
+// Compares `DefaultStore` with the default SipHash maps against
+// `BuildIdentityHasher`. Run `make bench-hasher` for this group only.
+fn bench_store_hashers(c: &mut Criterion) {
+    type SipStore = DefaultStore<Hash, H256, 32>;
+    type IdentityStore = DefaultStore<Hash, H256, 32, BuildIdentityHasher>;
+
+    fn random_pairs(size: usize, rng: &mut impl Rng) -> Vec<(Hash, H256)> {
+        (0..size)
+            .map(|_| (random_h256(rng).into(), random_h256(rng)))
+            .collect()
+    }
+
+    fn build<S: Store<Hash, H256, 32>>(pairs: &[(Hash, H256)]) -> SparseMerkleTree<Sha256Hasher, Hash, H256, S, 32> {
+        let mut smt = SparseMerkleTree::default();
+        for (key, value) in pairs {
+            smt.update(*key, *value).unwrap();
+        }
+        smt
+    }
+
+    let mut group = c.benchmark_group("DefaultStore hasher update");
+    for size in [100, 10_000].iter() {
+        let pairs = random_pairs(*size, &mut thread_rng());
+        group.throughput(Throughput::Elements(*size as u64));
+        group.bench_with_input(BenchmarkId::new("siphash", size), &pairs, |b, pairs| {
+            b.iter(|| build::<SipStore>(pairs));
+        });
+        group.bench_with_input(BenchmarkId::new("identity", size), &pairs, |b, pairs| {
+            b.iter(|| build::<IdentityStore>(pairs));
+        });
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("DefaultStore hasher get");
+    for size in [5_000, 10_000].iter() {
+        let mut rng = thread_rng();
+        let pairs = random_pairs(*size, &mut rng);
+        // look up as many missing keys as present ones
+        let keys: Vec<Hash> = pairs
+            .iter()
+            .map(|(k, _v)| *k)
+            .chain(random_pairs(*size, &mut rng).into_iter().map(|(k, _v)| k))
+            .collect();
+        group.throughput(Throughput::Elements(keys.len() as u64));
+        let smt = build::<SipStore>(&pairs);
+        group.bench_with_input(BenchmarkId::new("siphash", size), &keys, |b, keys| {
+            b.iter(|| keys.iter().filter(|k| !smt.get(k).unwrap().is_zero()).count());
+        });
+        let smt = build::<IdentityStore>(&pairs);
+        group.bench_with_input(BenchmarkId::new("identity", size), &keys, |b, keys| {
+            b.iter(|| keys.iter().filter(|k| !smt.get(k).unwrap().is_zero()).count());
+        });
+    }
+    group.finish();
+}
+
 criterion_group!(
     name = benches;
     config = Criterion::default().sample_size(10);
-    targets = bench_hashes, bench_strings
+    targets = bench_hashes, bench_strings, bench_store_hashers
 );
 criterion_main!(benches);
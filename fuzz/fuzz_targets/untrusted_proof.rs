@@ -0,0 +1,6 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use nam_sparse_merkle_tree::testing::verify_untrusted_proof;
+
+fuzz_target!(|data: &[u8]| verify_untrusted_proof(data));
@@ -0,0 +1,213 @@
+//! A sparse merkle tree over an `AsyncStore`.
+//!
+//! The traversals are the ones `SparseMerkleTree` runs, so for the same
+//! leaves both trees compute the same roots and proofs.
+
+use crate::{
+    error::Result,
+    merkle_proof::{
+        CompiledMerkleProof, EmptySubtreeProof, MerkleProof, RangeProof, SubtreeProof,
+    },
+    traits::{AsyncStore, Hasher, Value},
+    tree::{write_all, BranchNode, Diff, LeafNode, NodeReader, NodeWriter, StoreOp, TreeView},
+    vec::Vec,
+    Key, H256,
+};
+use core::{marker::PhantomData, ops::Deref};
+use ics23::CommitmentProof;
+
+/// Reads nodes from an `AsyncStore`
+struct AsyncStoreReader<'a, S>(&'a S);
+
+/// A node read from an `AsyncStore`, owned by the reader
+struct Owned<T>(T);
+
+impl<T> Deref for Owned<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<'a, K, V, S, const N: usize> NodeReader<K, V, N> for AsyncStoreReader<'a, S>
+where
+    K: Key<N>,
+    S: AsyncStore<K, V, N>,
+{
+    type Branch = Owned<BranchNode<K, N>>;
+    type Leaf = Owned<LeafNode<K, V, N>>;
+
+    async fn branch(&self, node: &H256) -> Result<Option<Self::Branch>> {
+        Ok(self.0.get_branch(node).await?.map(Owned))
+    }
+    async fn leaf(&self, leaf_hash: &H256) -> Result<Option<Self::Leaf>> {
+        Ok(self.0.get_leaf(leaf_hash).await?.map(Owned))
+    }
+}
+
+/// Writes nodes to an `AsyncStore`
+struct AsyncStoreWriter<'a, S>(&'a mut S);
+
+impl<'a, K, V, S, const N: usize> NodeWriter<K, V, N> for AsyncStoreWriter<'a, S>
+where
+    K: Key<N>,
+    S: AsyncStore<K, V, N>,
+{
+    async fn write(&mut self, op: StoreOp<K, V, N>) -> Result<()> {
+        match op {
+            StoreOp::InsertBranch(node, branch) => self.0.insert_branch(node, branch).await,
+            StoreOp::InsertLeaf(leaf_hash, leaf) => self.0.insert_leaf(leaf_hash, leaf).await,
+            StoreOp::RemoveBranch(node) => self.0.remove_branch(&node).await,
+            StoreOp::RemoveLeaf(leaf_hash) => self.0.remove_leaf(&leaf_hash).await,
+        }
+    }
+}
+
+/// Sparse merkle tree with an async backend store
+#[derive(Debug)]
+pub struct AsyncSparseMerkleTree<H, K, V, S, const N: usize> {
+    store: S,
+    root: H256,
+    phantom: PhantomData<(H, K, V)>,
+}
+
+impl<H, K, V, S, const N: usize> AsyncSparseMerkleTree<H, K, V, S, N>
+where
+    H: Hasher + Default,
+    K: Key<N>,
+    V: Value + core::cmp::PartialEq,
+    S: AsyncStore<K, V, N>,
+{
+    /// Build a merkle tree from root and store
+    pub fn new(root: H256, store: S) -> AsyncSparseMerkleTree<H, K, V, S, N> {
+        AsyncSparseMerkleTree {
+            root,
+            store,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Merkle root
+    pub fn root(&self) -> &H256 {
+        &self.root
+    }
+
+    /// Check empty of the tree
+    pub fn is_empty(&self) -> bool {
+        self.root.is_zero()
+    }
+
+    /// Destroy current tree and retake store
+    pub fn take_store(self) -> S {
+        self.store
+    }
+
+    /// Get backend store
+    pub fn store(&self) -> &S {
+        &self.store
+    }
+
+    /// Get mutable backend store
+    pub fn store_mut(&mut self) -> &mut S {
+        &mut self.store
+    }
+
+    /// Update a leaf, return new merkle root
+    /// set to zero value to delete a key
+    ///
+    /// The new root and the writes are worked out before the first write,
+    /// and a failing write is undone like in `SparseMerkleTree::update`, so
+    /// on error both the store and the root are left untouched.
+    pub async fn update(&mut self, key: K, value: V) -> Result<&H256> {
+        let (root, writes) = self.view().plan_update(key, value).await?;
+        write_all(&mut AsyncStoreWriter(&mut self.store), writes).await?;
+        self.root = root;
+        Ok(&self.root)
+    }
+
+    /// Get value of a leaf
+    /// return zero value if leaf not exists
+    pub async fn get(&self, key: &K) -> Result<V> {
+        self.view().get(key).await
+    }
+
+    /// Get the values of several leaves at once, in the order of `keys`
+    pub async fn get_many(&self, keys: &[K]) -> Result<Vec<V>> {
+        self.view().get_many(keys).await
+    }
+
+    /// Generate merkle proof
+    pub async fn merkle_proof(&self, keys: Vec<K>) -> Result<MerkleProof> {
+        self.view().merkle_proof(keys).await
+    }
+
+    /// Generate compiled merkle proof, without building the merkle proof
+    /// first
+    pub async fn compiled_merkle_proof(&self, keys: Vec<K>) -> Result<CompiledMerkleProof> {
+        self.view().compiled_merkle_proof(keys).await
+    }
+
+    /// Generate ICS 23 commitment proof for the existing key
+    pub async fn membership_proof(&self, key: &K) -> Result<CommitmentProof> {
+        self.view().membership_proof(key).await
+    }
+
+    /// Generate ICS 23 commitment proof for the non-existing key
+    pub async fn non_membership_proof(&self, key: &K) -> Result<CommitmentProof> {
+        self.view().non_membership_proof(key).await
+    }
+
+    /// Get the root of the subtree covering `prefix_key.parent_path(height)`
+    /// return zero if the subtree holds no leaves
+    pub async fn subtree_root(&self, prefix_key: &K, height: usize) -> Result<H256> {
+        self.view().subtree(prefix_key, height).await
+    }
+
+    /// Generate proof of the root of the subtree covering
+    /// `prefix_key.parent_path(height)`
+    pub async fn subtree_proof(&self, prefix_key: &K, height: usize) -> Result<SubtreeProof<K, V>> {
+        self.view().subtree_proof(prefix_key, height).await
+    }
+
+    /// Generate proof that no leaf lies in the subtree covering
+    /// `prefix_key.parent_path(height)`
+    pub async fn empty_subtree_proof(
+        &self,
+        prefix_key: &K,
+        height: usize,
+    ) -> Result<EmptySubtreeProof<K, V>> {
+        self.view().empty_subtree_proof(prefix_key, height).await
+    }
+
+    /// Generate ICS 23 commitment proof that no leaf lies in the subtree
+    /// covering `prefix_key.parent_path(height)`
+    pub async fn empty_subtree_commitment_proof(
+        &self,
+        prefix_key: &K,
+        height: usize,
+    ) -> Result<CommitmentProof> {
+        self.view()
+            .empty_subtree_commitment_proof(prefix_key, height)
+            .await
+    }
+
+    /// Generate proof of the leaves with keys in `start..end`
+    ///
+    /// Returns InvalidRange error if `start` is greater than `end`.
+    pub async fn range_proof(&self, start: &K, end: &K) -> Result<RangeProof<K, V>> {
+        self.view().range_proof(start, end).await
+    }
+
+    /// List the leaves that differ between `root_a` and `root_b` in key
+    /// order, the store has to hold the nodes of both roots
+    pub async fn diff(&self, root_a: H256, root_b: H256) -> Result<Vec<Diff<K, V>>> {
+        TreeView::<H, K, V, _, N>::new(AsyncStoreReader(&self.store), root_a)
+            .diff(root_b)
+            .await
+    }
+
+    fn view(&self) -> TreeView<H, K, V, AsyncStoreReader<'_, S>, N> {
+        TreeView::new(AsyncStoreReader(&self.store), self.root)
+    }
+}
@@ -0,0 +1,164 @@
+//! Bottom-up construction of subtrees from a sorted batch, shared by
+//! `SparseMerkleTree::update_all` and `SparseMerkleTree::validate`.
+
+use crate::{
+    merge::{hash_leaf, merge},
+    traits::{Hasher, MaybeSync, Value},
+    tree::{BranchNode, LeafNode, StoreOp},
+    vec::Vec,
+    InternalKey, Key, H256,
+};
+
+/// Subtrees with fewer items than this are hashed on the current thread
+#[cfg_attr(not(feature = "parallel"), allow(dead_code))]
+const PARALLEL_THRESHOLD: usize = 512;
+
+/// A leaf to write, or a subtree of the current tree left as is
+pub(crate) struct Item<K, V, const N: usize> {
+    /// Orders the items, only the bits above the subtree root are meaningful
+    path: InternalKey<N>,
+    /// Key of the branches built above the item
+    key: K,
+    node: ItemNode<V>,
+}
+
+enum ItemNode<V> {
+    Leaf(V),
+    Subtree(H256),
+}
+
+impl<K, V, const N: usize> Item<K, V, N>
+where
+    K: Key<N>,
+{
+    pub fn leaf(key: K, value: V) -> Self {
+        Item {
+            path: *key,
+            key,
+            node: ItemNode::Leaf(value),
+        }
+    }
+
+    /// `key` only has to agree with `path` above the root of the subtree
+    pub fn subtree(path: InternalKey<N>, key: K, node: H256) -> Self {
+        Item {
+            path,
+            key,
+            node: ItemNode::Subtree(node),
+        }
+    }
+
+    pub fn path(&self) -> &InternalKey<N> {
+        &self.path
+    }
+}
+
+/// Hash `items`, which must be sorted by path and lie in disjoint subtrees
+///
+/// Returns the root and, if `writes` is set, the nodes to insert into the
+/// store, children before their parents. Leaf values are moved out of the
+/// items in that case.
+pub(crate) fn build<H, K, V, const N: usize>(
+    items: &mut [Item<K, V, N>],
+    writes: bool,
+) -> (H256, Vec<StoreOp<K, V, N>>)
+where
+    H: Hasher + Default,
+    K: Key<N> + MaybeSync,
+    V: Value + MaybeSync,
+{
+    match items {
+        [] => (H256::zero(), Vec::new()),
+        [item] => match &mut item.node {
+            ItemNode::Subtree(node) => (*node, Vec::new()),
+            ItemNode::Leaf(value) => {
+                let leaf_hash = hash_leaf::<H, K, V, N>(&item.key, value);
+                let mut out = Vec::new();
+                // zero values are deletions, nothing to store
+                if writes && !leaf_hash.is_zero() {
+                    let value = core::mem::replace(value, V::zero());
+                    out.push(StoreOp::InsertLeaf(
+                        leaf_hash,
+                        LeafNode {
+                            key: item.key,
+                            value,
+                        },
+                    ));
+                    out.push(StoreOp::InsertBranch(
+                        leaf_hash,
+                        BranchNode {
+                            key: item.key,
+                            fork_height: 0,
+                            node: leaf_hash,
+                            sibling: H256::zero(),
+                        },
+                    ));
+                }
+                (leaf_hash, out)
+            }
+        },
+        _ => {
+            let len = items.len();
+            let height = items[0].path.fork_height(&items[len - 1].path);
+            debug_assert_ne!(items[0].path, items[len - 1].path);
+            let key = items[0].key;
+            let split = items.partition_point(|item| !item.path.get_bit(height));
+            let (left_items, right_items) = items.split_at_mut(split);
+            let ((left, mut out), (right, right_out)) = join(
+                len >= PARALLEL_THRESHOLD,
+                || build::<H, K, V, N>(left_items, writes),
+                || build::<H, K, V, N>(right_items, writes),
+            );
+            out.extend(right_out);
+            // a side where every leaf was deleted collapses into the other
+            if left.is_zero() {
+                return (right, out);
+            }
+            if right.is_zero() {
+                return (left, out);
+            }
+            let parent = merge::<H>(&left, &right);
+            if writes {
+                let (node, sibling) = if key.get_bit(height) {
+                    (right, left)
+                } else {
+                    (left, right)
+                };
+                out.push(StoreOp::InsertBranch(
+                    parent,
+                    BranchNode {
+                        fork_height: height,
+                        key,
+                        node,
+                        sibling,
+                    },
+                ));
+            }
+            (parent, out)
+        }
+    }
+}
+
+#[cfg(feature = "parallel")]
+fn join<A, B, RA, RB>(parallel: bool, a: A, b: B) -> (RA, RB)
+where
+    A: FnOnce() -> RA + Send,
+    B: FnOnce() -> RB + Send,
+    RA: Send,
+    RB: Send,
+{
+    if parallel {
+        rayon::join(a, b)
+    } else {
+        (a(), b())
+    }
+}
+
+#[cfg(not(feature = "parallel"))]
+fn join<A, B, RA, RB>(_parallel: bool, a: A, b: B) -> (RA, RB)
+where
+    A: FnOnce() -> RA,
+    B: FnOnce() -> RB,
+{
+    (a(), b())
+}
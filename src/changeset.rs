@@ -0,0 +1,212 @@
+//! Change sets recorded by a tree and replayed on replicas.
+
+use crate::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet},
+    error::{Error, Result},
+    traits::{Hasher, Store, Value},
+    tree::{BranchNode, LeafNode, StoreOp},
+    vec::Vec,
+    Key, H256,
+};
+#[cfg(feature = "borsh")]
+use borsh::{BorshDeserialize, BorshSerialize};
+
+/// The store writes that took a tree from `old_root` to `new_root`
+#[derive(Debug, Eq, PartialEq, Clone)]
+#[cfg_attr(feature = "borsh", derive(BorshSerialize, BorshDeserialize))]
+pub struct ChangeSet<K, V, const N: usize>
+where
+    K: Key<N>,
+{
+    pub old_root: H256,
+    pub new_root: H256,
+    /// Writes in the order they were applied
+    pub ops: Vec<StoreOp<K, V, N>>,
+}
+
+impl<K, V, const N: usize> ChangeSet<K, V, N>
+where
+    K: Key<N>,
+{
+    /// An empty change set starting at `root`
+    pub fn new(root: H256) -> Self {
+        ChangeSet {
+            old_root: root,
+            new_root: root,
+            ops: Vec::new(),
+        }
+    }
+
+    /// Check if the change set holds no writes
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
+
+/// The nodes of a store after a change set, without writing to the store
+struct Overlay<'a, K, V, S, const N: usize>
+where
+    K: Key<N>,
+{
+    store: &'a S,
+    /// Last write of each branch, none for removals
+    branches: BTreeMap<H256, Option<&'a BranchNode<K, N>>>,
+    /// Last write of each leaf, none for removals
+    leaves: BTreeMap<H256, Option<&'a LeafNode<K, V, N>>>,
+}
+
+impl<'a, K, V, S, const N: usize> Overlay<'a, K, V, S, N>
+where
+    K: Key<N>,
+    V: Value,
+    S: Store<K, V, N>,
+{
+    fn new(store: &'a S, ops: &'a [StoreOp<K, V, N>]) -> Self {
+        let mut branches = BTreeMap::new();
+        let mut leaves = BTreeMap::new();
+        for op in ops {
+            match op {
+                StoreOp::InsertBranch(node, branch) => {
+                    branches.insert(*node, Some(branch));
+                }
+                StoreOp::InsertLeaf(leaf_hash, leaf) => {
+                    leaves.insert(*leaf_hash, Some(leaf));
+                }
+                StoreOp::RemoveBranch(node) => {
+                    branches.insert(*node, None);
+                }
+                StoreOp::RemoveLeaf(leaf_hash) => {
+                    leaves.insert(*leaf_hash, None);
+                }
+            }
+        }
+        Overlay {
+            store,
+            branches,
+            leaves,
+        }
+    }
+
+    fn get_branch(&self, node: &H256) -> Result<Option<Cow<'a, BranchNode<K, N>>>> {
+        match self.branches.get(node) {
+            Some(branch) => Ok(branch.map(Cow::Borrowed)),
+            None => self.store.get_branch_ref(node),
+        }
+    }
+
+    fn get_leaf(&self, leaf_hash: &H256) -> Result<Option<Cow<'a, LeafNode<K, V, N>>>> {
+        match self.leaves.get(leaf_hash) {
+            Some(leaf) => Ok(leaf.map(Cow::Borrowed)),
+            None => self.store.get_leaf_ref(leaf_hash),
+        }
+    }
+
+    /// Nodes of the store the change set removes
+    fn removed(&self) -> Result<(BTreeSet<H256>, BTreeSet<H256>)> {
+        let mut branches = BTreeSet::new();
+        for (node, _) in self.branches.iter().filter(|(_, b)| b.is_none()) {
+            if self.store.get_branch_ref(node)?.is_some() {
+                branches.insert(*node);
+            }
+        }
+        let mut leaves = BTreeSet::new();
+        for (leaf_hash, _) in self.leaves.iter().filter(|(_, l)| l.is_none()) {
+            if self.store.get_leaf_ref(leaf_hash)?.is_some() {
+                leaves.insert(*leaf_hash);
+            }
+        }
+        Ok((branches, leaves))
+    }
+}
+
+/// Check that applying `changeset` to `store`, which holds a valid tree
+/// under `changeset.old_root`, leaves a valid tree under
+/// `changeset.new_root`
+///
+/// The nodes the change set writes are checked like in `load_verified`,
+/// the nodes it keeps are trusted. A removed node has to sit on a path of
+/// removed nodes from the old root, so that it can not belong to a subtree
+/// the new tree keeps, and every written node has to be part of the new
+/// tree.
+pub(crate) fn verify<H, K, V, S, const N: usize>(
+    store: &S,
+    changeset: &ChangeSet<K, V, N>,
+) -> Result<()>
+where
+    H: Hasher + Default,
+    K: Key<N>,
+    V: Value,
+    S: Store<K, V, N>,
+{
+    let overlay = Overlay::new(store, &changeset.ops);
+
+    // walk the old tree through the removed nodes
+    let (mut removed_branches, mut removed_leaves) = overlay.removed()?;
+    let mut stack = Vec::new();
+    if removed_branches.remove(&changeset.old_root) {
+        stack.push(changeset.old_root);
+    }
+    while let Some(node) = stack.pop() {
+        let branch = store
+            .get_branch_ref(&node)?
+            .ok_or(Error::MissingBranch(node))?;
+        if branch.is_leaf(&node) {
+            removed_leaves.remove(&node);
+            continue;
+        }
+        let (left, right) = branch.branch(branch.fork_height);
+        for child in [left, right] {
+            if removed_branches.remove(child) {
+                stack.push(*child);
+            }
+        }
+    }
+    if let Some(node) = removed_branches.iter().chain(removed_leaves.iter()).next() {
+        return Err(Error::UnexpectedRemoval(*node));
+    }
+
+    // walk the new tree through the written nodes
+    let mut written_branches: BTreeSet<_> = overlay
+        .branches
+        .iter()
+        .filter(|(_, b)| b.is_some())
+        .map(|(node, _)| *node)
+        .collect();
+    let mut written_leaves: BTreeSet<_> = overlay
+        .leaves
+        .iter()
+        .filter(|(_, l)| l.is_some())
+        .map(|(leaf_hash, _)| *leaf_hash)
+        .collect();
+    let mut stack = Vec::new();
+    if !changeset.new_root.is_zero() {
+        stack.push((changeset.new_root, None));
+    }
+    while let Some((node, parent)) = stack.pop() {
+        let branch = overlay
+            .get_branch(&node)?
+            .ok_or(Error::MissingBranch(node))?;
+        if let Some((key, height, is_right)) = parent {
+            branch.check_position(&node, (&key, height, is_right))?;
+        }
+        if !written_branches.remove(&node) {
+            // kept from the old tree
+            continue;
+        }
+        if branch.is_leaf(&node) {
+            let leaf = overlay.get_leaf(&node)?.ok_or(Error::MissingLeaf(node))?;
+            branch.check_leaf::<H, V>(&node, &leaf)?;
+            written_leaves.remove(&node);
+            continue;
+        }
+        branch.check_children::<H>(&node)?;
+        let (left, right) = branch.branch(branch.fork_height);
+        stack.push((*left, Some((branch.key, branch.fork_height, false))));
+        stack.push((*right, Some((branch.key, branch.fork_height, true))));
+    }
+    if let Some(node) = written_branches.iter().chain(written_leaves.iter()).next() {
+        return Err(Error::UnreachableNode(*node));
+    }
+    Ok(())
+}
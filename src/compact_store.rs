@@ -0,0 +1,186 @@
+use crate::{
+    borrow::Cow,
+    default_store::Map,
+    error::Error,
+    string::ToString,
+    traits::Store,
+    tree::{BranchNode, LeafNode},
+    Key, H256,
+};
+#[cfg(feature = "borsh")]
+use borsh::{BorshDeserialize, BorshSerialize};
+use core::convert::TryFrom;
+use core::ops::Deref;
+use itertools::Itertools;
+
+/// A branch that does not own a copy of its key
+///
+/// Only the key bits from `fork_height` up are meaningful for a branch, and
+/// every leaf below the branch shares them. Instead of a full key, the node
+/// points at one of those leaves and reads the key from the leaf entry.
+#[derive(Debug, Eq, PartialEq, Clone)]
+#[cfg_attr(feature = "borsh", derive(BorshSerialize, BorshDeserialize))]
+pub struct CompactBranchNode {
+    pub fork_height: u16,
+    /// Hash of a leaf below this branch, on the `node` side
+    pub leaf: H256,
+    pub node: H256,
+    pub sibling: H256,
+}
+
+impl CompactBranchNode {
+    /// Drop the key of `branch`, `leaf` must be the hash of a leaf below
+    /// `branch.node`
+    ///
+    /// Returns `None` if the fork height does not fit in a `u16`.
+    pub fn from_branch<K, const N: usize>(branch: &BranchNode<K, N>, leaf: H256) -> Option<Self>
+    where
+        K: Key<N>,
+    {
+        Some(CompactBranchNode {
+            fork_height: u16::try_from(branch.fork_height).ok()?,
+            leaf,
+            node: branch.node,
+            sibling: branch.sibling,
+        })
+    }
+
+    /// Rebuild the full branch with the key of the leaf `self.leaf` points at
+    pub fn into_branch<K, const N: usize>(self, key: K) -> BranchNode<K, N>
+    where
+        K: Key<N>,
+    {
+        BranchNode {
+            fork_height: self.fork_height as usize,
+            key,
+            node: self.node,
+            sibling: self.sibling,
+        }
+    }
+}
+
+/// An in-memory store that keeps each key once, in its leaf
+///
+/// Branches are kept as `CompactBranchNode`s and the branch that every leaf
+/// gets at height 0 is not stored at all, it is rebuilt from the leaf.
+/// Children must be inserted before their parents, which
+/// `SparseMerkleTree` always does.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "borsh", derive(BorshSerialize, BorshDeserialize))]
+pub struct CompactStore<K, V, const N: usize>
+where
+    K: Key<N>,
+{
+    branches_map: Map<H256, CompactBranchNode>,
+    leaves_map: Map<H256, LeafNode<K, V, N>>,
+}
+
+impl<K, V, const N: usize> Default for CompactStore<K, V, N>
+where
+    K: Key<N>,
+{
+    fn default() -> Self {
+        Self {
+            branches_map: Map::default(),
+            leaves_map: Map::default(),
+        }
+    }
+}
+
+impl<K, V, const N: usize> CompactStore<K, V, N>
+where
+    K: Key<N>,
+{
+    pub fn branches_map(&self) -> &Map<H256, CompactBranchNode> {
+        &self.branches_map
+    }
+    pub fn leaves_map(&self) -> &Map<H256, LeafNode<K, V, N>> {
+        &self.leaves_map
+    }
+    pub fn clear(&mut self) {
+        self.branches_map.clear();
+        self.leaves_map.clear();
+    }
+
+    /// Find the leaf a new branch should point at through its `node` child
+    fn leaf_below(&self, node: &H256) -> Option<H256> {
+        if self.leaves_map.contains_key(node) {
+            Some(*node)
+        } else {
+            self.branches_map.get(node).map(|branch| branch.leaf)
+        }
+    }
+}
+
+impl<K, V: Clone, const N: usize> Store<K, V, N> for CompactStore<K, V, N>
+where
+    K: Key<N>,
+{
+    fn get_branch(&self, node: &H256) -> Result<Option<BranchNode<K, N>>, Error> {
+        if let Some(branch) = self.branches_map.get(node) {
+            let leaf = self
+                .leaves_map
+                .get(&branch.leaf)
+                .ok_or_else(|| Error::Store("missing leaf of compact branch".to_string()))?;
+            return Ok(Some(branch.clone().into_branch(leaf.key)));
+        }
+        Ok(self.leaves_map.get(node).map(|leaf| BranchNode {
+            fork_height: 0,
+            key: leaf.key,
+            node: *node,
+            sibling: H256::zero(),
+        }))
+    }
+    fn get_leaf(&self, leaf_hash: &H256) -> Result<Option<LeafNode<K, V, N>>, Error> {
+        Ok(self.leaves_map.get(leaf_hash).cloned())
+    }
+    fn get_leaf_ref(&self, leaf_hash: &H256) -> Result<Option<Cow<'_, LeafNode<K, V, N>>>, Error> {
+        Ok(self.leaves_map.get(leaf_hash).map(Cow::Borrowed))
+    }
+    fn insert_branch(&mut self, node: H256, branch: BranchNode<K, N>) -> Result<(), Error> {
+        let is_leaf_branch =
+            branch.fork_height == 0 && branch.node == node && branch.sibling.is_zero();
+        if is_leaf_branch && self.leaves_map.contains_key(&node) {
+            // rebuilt from the leaf on read
+            return Ok(());
+        }
+        let leaf = self
+            .leaf_below(&branch.node)
+            .ok_or_else(|| Error::Store("branch inserted before its child".to_string()))?;
+        let compact = CompactBranchNode::from_branch(&branch, leaf)
+            .ok_or_else(|| Error::Store("fork height does not fit in u16".to_string()))?;
+        self.branches_map.insert(node, compact);
+        Ok(())
+    }
+    fn insert_leaf(&mut self, leaf_hash: H256, leaf: LeafNode<K, V, N>) -> Result<(), Error> {
+        self.leaves_map.insert(leaf_hash, leaf);
+        Ok(())
+    }
+    fn remove_branch(&mut self, node: &H256) -> Result<(), Error> {
+        self.branches_map.remove(node);
+        Ok(())
+    }
+    fn remove_leaf(&mut self, leaf_hash: &H256) -> Result<(), Error> {
+        self.leaves_map.remove(leaf_hash);
+        Ok(())
+    }
+
+    fn sorted_leaves<'a>(&'a self) -> impl Iterator<Item = (K, &'a V)>
+    where
+        V: 'a,
+    {
+        self.leaves_map
+            .iter()
+            .sorted_by_key(|(_, v)| <K as Deref>::deref(&v.key))
+            .map(|(_, v)| (v.key, &v.value))
+    }
+
+    fn size(&self) -> usize {
+        self.leaves_map.len()
+    }
+
+    fn branch_count(&self) -> usize {
+        // the branches right above the leaves are rebuilt from them
+        self.branches_map.len() + self.leaves_map.len()
+    }
+}
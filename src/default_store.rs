@@ -1,38 +1,82 @@
-use std::ops::Deref;
-use crate::{collections, error::Error, traits::Store, tree::{BranchNode, LeafNode}, Key, H256};
+use core::ops::Deref;
+use crate::{borrow::Cow, collections, error::Error, traits::Store, tree::{BranchNode, LeafNode}, Key, H256};
 #[cfg(feature = "borsh")]
 use borsh::{BorshDeserialize, BorshSerialize};
+#[cfg(not(feature = "std"))]
+use core::marker::PhantomData;
 use itertools::Itertools;
 
+/// In-memory store keeping the nodes in maps keyed by node hash
+///
+/// Under `std` the maps are `HashMap`s built with `S`, SipHash by default.
+/// `DefaultStore<K, V, N, BuildIdentityHasher>` skips SipHash, see
+/// `IdentityHasher` for when that is safe. Without `std` the maps are
+/// `BTreeMap`s and `S` is unused.
 #[derive(Debug, Clone)]
 #[cfg_attr(feature = "borsh", derive(BorshSerialize, BorshDeserialize))]
-pub struct DefaultStore<K, V, const N: usize>
+pub struct DefaultStore<K, V, const N: usize, S = DefaultHashBuilder>
 where
     K: Key<N>,
+    S: BuildNodeHasher,
 {
+    // the maps need no bounds on `S` beyond those of the struct
+    #[cfg(feature = "std")]
+    #[cfg_attr(
+        feature = "borsh",
+        borsh(bound(serialize = "K: BorshSerialize", deserialize = "K: BorshDeserialize"))
+    )]
+    branches_map: Map<H256, BranchNode<K, N>, S>,
+    #[cfg(feature = "std")]
+    #[cfg_attr(
+        feature = "borsh",
+        borsh(bound(
+            serialize = "K: BorshSerialize, V: BorshSerialize",
+            deserialize = "K: BorshDeserialize, V: BorshDeserialize"
+        ))
+    )]
+    leaves_map: Map<H256, LeafNode<K, V, N>, S>,
+    #[cfg(not(feature = "std"))]
     branches_map: Map<H256, BranchNode<K, N>>,
+    #[cfg(not(feature = "std"))]
     leaves_map: Map<H256, LeafNode<K, V, N>>,
+    #[cfg(not(feature = "std"))]
+    #[cfg_attr(feature = "borsh", borsh(skip))]
+    hasher: PhantomData<S>,
 }
 
-impl<K, V, const N: usize> Default for DefaultStore<K, V, N>
+impl<K, V, const N: usize, S> Default for DefaultStore<K, V, N, S>
 where
     K: Key<N>,
+    S: BuildNodeHasher,
 {
     fn default() -> Self {
         Self {
-            branches_map: Map::new(),
-            leaves_map: Map::new(),
+            branches_map: Map::default(),
+            leaves_map: Map::default(),
+            #[cfg(not(feature = "std"))]
+            hasher: PhantomData,
         }
     }
 }
 
-impl<K, V, const N: usize> DefaultStore<K, V, N>
+impl<K, V, const N: usize, S> DefaultStore<K, V, N, S>
 where
     K: Key<N>,
+    S: BuildNodeHasher,
 {
+    #[cfg(feature = "std")]
+    pub fn branches_map(&self) -> &Map<H256, BranchNode<K, N>, S> {
+        &self.branches_map
+    }
+    #[cfg(feature = "std")]
+    pub fn leaves_map(&self) -> &Map<H256, LeafNode<K, V, N>, S> {
+        &self.leaves_map
+    }
+    #[cfg(not(feature = "std"))]
     pub fn branches_map(&self) -> &Map<H256, BranchNode<K, N>> {
         &self.branches_map
     }
+    #[cfg(not(feature = "std"))]
     pub fn leaves_map(&self) -> &Map<H256, LeafNode<K, V, N>> {
         &self.leaves_map
     }
@@ -42,15 +86,22 @@ where
     }
 }
 
-impl<K, V: Clone, const N: usize> Store<K, V, N> for DefaultStore<K, V, N>
+impl<K, V: Clone, const N: usize, S> Store<K, V, N> for DefaultStore<K, V, N, S>
 where
     K: Key<N>,
+    S: BuildNodeHasher,
 {
     fn get_branch(&self, node: &H256) -> Result<Option<BranchNode<K, N>>, Error> {
-        Ok(self.branches_map.get(node).map(Clone::clone))
+        Ok(self.branches_map.get(node).cloned())
     }
     fn get_leaf(&self, leaf_hash: &H256) -> Result<Option<LeafNode<K, V, N>>, Error> {
-        Ok(self.leaves_map.get(leaf_hash).map(Clone::clone))
+        Ok(self.leaves_map.get(leaf_hash).cloned())
+    }
+    fn get_branch_ref(&self, node: &H256) -> Result<Option<Cow<'_, BranchNode<K, N>>>, Error> {
+        Ok(self.branches_map.get(node).map(Cow::Borrowed))
+    }
+    fn get_leaf_ref(&self, leaf_hash: &H256) -> Result<Option<Cow<'_, LeafNode<K, V, N>>>, Error> {
+        Ok(self.leaves_map.get(leaf_hash).map(Cow::Borrowed))
     }
     fn insert_branch(&mut self, node: H256, branch: BranchNode<K, N>) -> Result<(), Error> {
         self.branches_map.insert(node, branch);
@@ -79,14 +130,62 @@ where
     fn size(&self) -> usize {
         self.leaves_map.len()
     }
+
+    fn branch_count(&self) -> usize {
+        self.branches_map.len()
+    }
 }
 
+/// A `core::hash::Hasher` for maps keyed by `H256`
+///
+/// The keys are already uniformly distributed hashes, so their first eight
+/// bytes are used as the hash instead of running SipHash over all of them.
+/// Node hashes are not chosen freely, but whoever picks the leaves can grind
+/// their hashes until enough of them share the bits a map buckets by, and
+/// slow every lookup down to a scan (HashDoS). Only use it when the leaves
+/// come from trusted parties.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct IdentityHasher(u64);
+
+impl core::hash::Hasher for IdentityHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        let mut buf = [0u8; 8];
+        let len = core::cmp::min(bytes.len(), buf.len());
+        buf[..len].copy_from_slice(&bytes[..len]);
+        self.0 = self.0.rotate_left(5) ^ u64::from_le_bytes(buf);
+    }
+
+    fn write_usize(&mut self, _i: usize) {
+        // `H256` writes its length before its bytes, the same for every key
+    }
+}
+
+pub type BuildIdentityHasher = core::hash::BuildHasherDefault<IdentityHasher>;
+
 cfg_if::cfg_if! {
     if #[cfg(feature = "std")] {
-        pub type Map<K, V> = collections::HashMap<K, V>;
+        pub type Map<K, V, S = DefaultHashBuilder> = collections::HashMap<K, V, S>;
         pub type Entry<'a, K, V> = collections::hash_map::Entry<'a, K, V>;
+        /// The hasher `DefaultStore` builds its maps with unless told otherwise
+        pub type DefaultHashBuilder = collections::hash_map::RandomState;
+
+        /// `BuildHasher + Default` under `std`, where `DefaultStore` hashes
+        /// its keys with it, and `Default` otherwise
+        pub trait BuildNodeHasher: core::hash::BuildHasher + Default {}
+        impl<S: core::hash::BuildHasher + Default> BuildNodeHasher for S {}
     } else {
         pub type Map<K, V> = collections::BTreeMap<K, V>;
         pub type Entry<'a, K, V> = collections::btree_map::Entry<'a, K, V>;
+        /// The hasher `DefaultStore` builds its maps with unless told otherwise
+        pub type DefaultHashBuilder = BuildIdentityHasher;
+
+        /// `BuildHasher + Default` under `std`, where `DefaultStore` hashes
+        /// its keys with it, and `Default` otherwise
+        pub trait BuildNodeHasher: Default {}
+        impl<S: Default> BuildNodeHasher for S {}
     }
 }
@@ -0,0 +1,229 @@
+//! Streaming dump and load of a whole tree.
+//!
+//! A dump is laid out as
+//!
+//! ```txt
+//! magic "NSMTDUMP" | version: u16 | hasher: H256 | N: u32 | value encoding: u8
+//! | root: H256 | leaf count: u64
+//! | leaf count * (key length: u32 | key bytes | borsh value)
+//! | sha256 of all the bytes above
+//! ```
+//!
+//! with integers in little endian and the leaves in key order. The key bytes
+//! are the user's key from `Key::as_slice`, which may be longer than `N`,
+//! and at most `MAX_DUMP_KEY_LEN` bytes long. The hasher is recorded as the
+//! hash of a fixed message, which tells different hash functions and
+//! personalizations apart.
+
+use crate::{
+    error::{Error, Result},
+    string::{String, ToString},
+    traits::{Hasher, MaybeSync, Store, Value},
+    vec::Vec,
+    Key, SparseMerkleTree, H256,
+};
+use borsh::{BorshDeserialize, BorshSerialize};
+use core::convert::TryInto;
+use sha2::{Digest, Sha256};
+use std::io::{Read, Write};
+
+const MAGIC: &[u8; 8] = b"NSMTDUMP";
+/// Version of the dump format
+pub const DUMP_VERSION: u16 = 1;
+/// Values are borsh encoded
+const BORSH_VALUES: u8 = 1;
+/// Longest user key a dump holds, bounds what a corrupted key length makes
+/// `load` allocate
+pub const MAX_DUMP_KEY_LEN: usize = 1 << 16;
+/// Leaves read before they are added to the tree with `update_all`, bounds
+/// the memory `load` takes besides the tree itself
+const LOAD_BATCH_SIZE: usize = 1 << 12;
+
+/// Hash of a fixed message, identifies the hasher of a dump
+fn hasher_fingerprint<H: Hasher + Default>() -> H256 {
+    let mut hasher = H::default();
+    hasher.write_bytes(b"nam-sparse-merkle-tree dump");
+    hasher.finish()
+}
+
+fn io_error(err: std::io::Error) -> Error {
+    Error::Io(err.to_string())
+}
+
+fn invalid(reason: &str) -> Error {
+    Error::InvalidDump(String::from(reason))
+}
+
+/// Writes through to `inner` and hashes everything written
+struct HashingWriter<W> {
+    inner: W,
+    digest: Sha256,
+}
+
+impl<W: Write> HashingWriter<W> {
+    fn put(&mut self, bytes: &[u8]) -> Result<()> {
+        self.digest.update(bytes);
+        self.inner.write_all(bytes).map_err(io_error)
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.digest.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reads from `inner` and hashes everything read
+struct HashingReader<R> {
+    inner: R,
+    digest: Sha256,
+}
+
+impl<R: Read> HashingReader<R> {
+    fn read_array<const L: usize>(&mut self) -> Result<[u8; L]> {
+        let mut buf = [0u8; L];
+        self.read_exact(&mut buf).map_err(io_error)?;
+        Ok(buf)
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.digest.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+impl<H, K, V, S, const N: usize> SparseMerkleTree<H, K, V, S, N>
+where
+    H: Hasher + Default,
+    K: Key<N> + MaybeSync,
+    V: Value + core::cmp::PartialEq + MaybeSync + BorshSerialize + BorshDeserialize,
+    S: Store<K, V, N>,
+{
+    /// Write the root and all leaves of the tree to `writer`
+    ///
+    /// The leaves are streamed from `Store::sorted_leaves`, see the module
+    /// docs for the format.
+    pub fn dump<W: Write>(&self, writer: W) -> Result<()> {
+        let mut writer = HashingWriter {
+            inner: writer,
+            digest: Sha256::new(),
+        };
+        writer.put(MAGIC)?;
+        writer.put(&DUMP_VERSION.to_le_bytes())?;
+        writer.put(hasher_fingerprint::<H>().as_slice())?;
+        writer.put(&(N as u32).to_le_bytes())?;
+        writer.put(&[BORSH_VALUES])?;
+        writer.put(self.root().as_slice())?;
+        writer.put(&(self.store().size() as u64).to_le_bytes())?;
+        let mut count = 0usize;
+        for (key, value) in self.store().sorted_leaves() {
+            let key = key.as_slice();
+            if key.len() > MAX_DUMP_KEY_LEN {
+                return Err(Error::KeyTooLarge);
+            }
+            writer.put(&(key.len() as u32).to_le_bytes())?;
+            writer.put(key)?;
+            value.serialize(&mut writer).map_err(io_error)?;
+            count += 1;
+        }
+        if count != self.store().size() {
+            return Err(Error::IncorrectNumberOfLeaves {
+                expected: self.store().size(),
+                actual: count,
+            });
+        }
+        let checksum = writer.digest.finalize();
+        writer.inner.write_all(&checksum).map_err(io_error)?;
+        writer.inner.flush().map_err(io_error)
+    }
+
+    /// Read a tree written by `dump`
+    ///
+    /// The header has to match `H`, `N` and the value encoding, and the
+    /// checksum the content. The leaves are added to the tree with
+    /// `update_all` in batches as they are read, and a recomputed root that
+    /// differs from the recorded one is rejected.
+    pub fn load<R: Read>(reader: R) -> Result<Self> {
+        let mut reader = HashingReader {
+            inner: reader,
+            digest: Sha256::new(),
+        };
+        if &reader.read_array::<8>()? != MAGIC {
+            return Err(invalid("not a tree dump"));
+        }
+        let version = u16::from_le_bytes(reader.read_array()?);
+        if version != DUMP_VERSION {
+            return Err(Error::InvalidDump(format!(
+                "unsupported version {}",
+                version
+            )));
+        }
+        if H256::from(reader.read_array::<32>()?) != hasher_fingerprint::<H>() {
+            return Err(invalid("dumped with a different hasher"));
+        }
+        let key_size = u32::from_le_bytes(reader.read_array()?);
+        if key_size as usize != N {
+            return Err(Error::InvalidDump(format!(
+                "dumped with {} byte keys, expected {}",
+                key_size, N
+            )));
+        }
+        if reader.read_array::<1>()? != [BORSH_VALUES] {
+            return Err(invalid("unknown value encoding"));
+        }
+        let root = H256::from(reader.read_array::<32>()?);
+        let count: usize = u64::from_le_bytes(reader.read_array()?)
+            .try_into()
+            .map_err(|_| invalid("too many leaves"))?;
+
+        let mut tree = Self::default();
+        let mut batch: Vec<(K, V)> = Vec::with_capacity(count.min(LOAD_BATCH_SIZE));
+        let mut last_key: Option<K> = None;
+        let mut key_bytes = Vec::new();
+        for _ in 0..count {
+            let key_len = u32::from_le_bytes(reader.read_array()?) as usize;
+            if key_len > MAX_DUMP_KEY_LEN {
+                return Err(invalid("key too long"));
+            }
+            key_bytes.resize(key_len, 0);
+            reader.read_exact(&mut key_bytes).map_err(io_error)?;
+            let key = K::try_from_bytes(&key_bytes).map_err(|_| invalid("invalid key"))?;
+            let value = V::deserialize_reader(&mut reader).map_err(io_error)?;
+            if value.is_zero() {
+                return Err(invalid("zero value leaf"));
+            }
+            if last_key.is_some_and(|last| *last >= *key) {
+                return Err(invalid("leaves out of order"));
+            }
+            last_key = Some(key);
+            batch.push((key, value));
+            if batch.len() == LOAD_BATCH_SIZE {
+                tree.update_all(core::mem::take(&mut batch))?;
+            }
+        }
+        tree.update_all(batch)?;
+        let checksum = reader.digest.finalize();
+        let mut recorded = [0u8; 32];
+        reader.inner.read_exact(&mut recorded).map_err(io_error)?;
+        if checksum.as_slice() != recorded {
+            return Err(Error::ChecksumMismatch);
+        }
+
+        if *tree.root() != root {
+            return Err(Error::RootMismatch {
+                expected: root,
+                actual: *tree.root(),
+            });
+        }
+        Ok(tree)
+    }
+}
@@ -17,6 +17,27 @@ pub enum Error {
     ExistenceProof,
     NonExistenceProof,
     KeyTooLarge,
+    Io(string::String),
+    InvalidDump(string::String),
+    ChecksumMismatch,
+    RootMismatch { expected: H256, actual: H256 },
+    MissingBranch(H256),
+    MissingLeaf(H256),
+    BranchHashMismatch(H256),
+    LeafHashMismatch(H256),
+    InvalidBranch(H256),
+    NonContiguousLeaves,
+    InvalidChunk(usize),
+    IncompleteSnapshot { missing: usize },
+    ZeroChunkSize,
+    UnexpectedRemoval(H256),
+    UnreachableNode(H256),
+    ConflictingLeaf(H256),
+    InvalidEncoding(string::String),
+    DuplicateKeys,
+    InvalidRange,
+    ZeroLeafHash,
+    IncorrectNumberOfBranches { expected: usize, actual: usize },
 }
 
 impl core::fmt::Display for Error {
@@ -65,6 +86,77 @@ impl core::fmt::Display for Error {
             Error::KeyTooLarge => {
                 write!(f, "Provided key has too many bytes")?;
             }
+            Error::Io(err_msg) => {
+                write!(f, "I/O error: {}", err_msg)?;
+            }
+            Error::InvalidDump(reason) => {
+                write!(f, "Invalid tree dump: {}", reason)?;
+            }
+            Error::ChecksumMismatch => {
+                write!(f, "Checksum mismatch")?;
+            }
+            Error::RootMismatch { expected, actual } => {
+                write!(
+                    f,
+                    "Root mismatch, expected {:?} actual {:?}",
+                    expected, actual
+                )?;
+            }
+            Error::MissingBranch(node) => {
+                write!(f, "Missing branch {:?}", node)?;
+            }
+            Error::MissingLeaf(node) => {
+                write!(f, "Missing leaf {:?}", node)?;
+            }
+            Error::BranchHashMismatch(node) => {
+                write!(f, "Children of branch {:?} hash to a different node", node)?;
+            }
+            Error::LeafHashMismatch(node) => {
+                write!(f, "Leaf {:?} hashes to a different node", node)?;
+            }
+            Error::InvalidBranch(node) => {
+                write!(f, "Branch {:?} is malformed or out of place", node)?;
+            }
+            Error::NonContiguousLeaves => {
+                write!(f, "Proof hides leaves between the proven ones")?;
+            }
+            Error::InvalidChunk(index) => {
+                write!(f, "Invalid snapshot chunk {}", index)?;
+            }
+            Error::IncompleteSnapshot { missing } => {
+                write!(f, "Incomplete snapshot, {} chunks missing", missing)?;
+            }
+            Error::ZeroChunkSize => {
+                write!(f, "Snapshot chunks must hold at least one leaf")?;
+            }
+            Error::UnexpectedRemoval(node) => {
+                write!(f, "Change set removes node {:?} the tree keeps", node)?;
+            }
+            Error::UnreachableNode(node) => {
+                write!(f, "Change set writes node {:?} outside the tree", node)?;
+            }
+            Error::ConflictingLeaf(node) => {
+                write!(f, "Key of leaf {:?} has another value in the merged tree", node)?;
+            }
+            Error::InvalidEncoding(reason) => {
+                write!(f, "Invalid proof encoding: {}", reason)?;
+            }
+            Error::DuplicateKeys => {
+                write!(f, "Duplicate keys")?;
+            }
+            Error::InvalidRange => {
+                write!(f, "Range start exceeds its end")?;
+            }
+            Error::ZeroLeafHash => {
+                write!(f, "Zero leaf hash proves nothing")?;
+            }
+            Error::IncorrectNumberOfBranches { expected, actual } => {
+                write!(
+                    f,
+                    "Incorrect number of branches, expected {} actual {}",
+                    expected, actual
+                )?;
+            }
         }
         Ok(())
     }
@@ -1,11 +1,12 @@
 #[cfg(feature = "borsh")]
+use crate::vec::Vec;
+#[cfg(feature = "borsh")]
+use borsh::io::{self, Read, Write};
+#[cfg(feature = "borsh")]
 use borsh::{BorshDeserialize, BorshSerialize};
 #[cfg(feature = "borsh")]
 use core::convert::TryInto;
-use std::fmt::Debug;
-use std::io::Read;
-#[cfg(feature = "borsh")]
-use std::io::Write;
+use core::fmt::Debug;
 
 /// The actual key value used in the tree
 #[derive(Eq, PartialEq, Debug, Hash, Clone, Copy, PartialOrd, Ord)]
@@ -13,7 +14,7 @@ pub struct InternalKey<const N: usize>([u8; N]);
 
 #[cfg(feature = "borsh")]
 impl<const N: usize> BorshSerialize for InternalKey<N> {
-    fn serialize<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+    fn serialize<W: Write>(&self, writer: &mut W) -> io::Result<()> {
         let bytes = self.0.to_vec();
         BorshSerialize::serialize(&bytes, writer)
     }
@@ -21,11 +22,10 @@ impl<const N: usize> BorshSerialize for InternalKey<N> {
 
 #[cfg(feature = "borsh")]
 impl<const N: usize> BorshDeserialize for InternalKey<N> {
-    fn deserialize_reader<R: Read>(reader: &mut R) -> std::io::Result<Self> {
-        use std::io::ErrorKind;
+    fn deserialize_reader<R: Read>(reader: &mut R) -> io::Result<Self> {
         let bytes: Vec<u8> = BorshDeserialize::deserialize_reader(reader)?;
         let bytes: [u8; N] = bytes.try_into().map_err(|_| {
-            std::io::Error::new(ErrorKind::InvalidData, "Input byte vector is too large")
+            io::Error::new(io::ErrorKind::InvalidData, "Input byte vector is too large")
         })?;
         Ok(InternalKey(bytes))
     }
@@ -52,9 +52,6 @@ impl<const N: usize> InternalKey<N> {
 
     #[inline]
     pub fn get_bit(&self, i: usize) -> bool {
-        if i / BYTE_SIZE > Self::max_index() {
-            println!("Hey");
-        }
         let byte_pos = Self::max_index() - i / BYTE_SIZE;
         let bit_pos = i % BYTE_SIZE;
         let bit = self.0[byte_pos] >> bit_pos & 1;
@@ -65,21 +62,21 @@ impl<const N: usize> InternalKey<N> {
     pub fn set_bit(&mut self, i: usize) {
         let byte_pos = Self::max_index() - i / BYTE_SIZE;
         let bit_pos = i % BYTE_SIZE;
-        self.0[byte_pos as usize] |= 1 << bit_pos as u8;
+        self.0[byte_pos] |= 1 << bit_pos as u8;
     }
 
     #[inline]
     pub fn clear_bit(&mut self, i: usize) {
         let byte_pos = Self::max_index() - i / BYTE_SIZE;
         let bit_pos = i % BYTE_SIZE;
-        self.0[byte_pos as usize] &= !((1 << bit_pos) as u8);
+        self.0[byte_pos] &= !((1 << bit_pos) as u8);
     }
 
     /// Treat InternalKey as a path in a tree
     /// fork height is the number of common bits(from higher to lower)
     /// of two InternalKeys
     pub fn fork_height(&self, key: &InternalKey<N>) -> usize {
-        let max = (BYTE_SIZE * N) as usize;
+        let max = BYTE_SIZE * N;
         for h in (0..max).rev() {
             if self.get_bit(h) != key.get_bit(h) {
                 return h;
@@ -105,14 +102,14 @@ impl<const N: usize> InternalKey<N> {
 
         let mut target = InternalKey::zero();
         let start = match range.start_bound() {
-            Bound::Included(&i) => i as usize,
+            Bound::Included(&i) => i,
             Bound::Excluded(&i) => panic!("do not allows excluded start: {}", i),
             Bound::Unbounded => 0,
         };
 
         let mut end = match range.end_bound() {
-            Bound::Included(&i) => i.saturating_add(1) as usize,
-            Bound::Excluded(&i) => i as usize,
+            Bound::Included(&i) => i.saturating_add(1),
+            Bound::Excluded(&i) => i,
             Bound::Unbounded => max,
         };
 
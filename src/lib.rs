@@ -63,16 +63,29 @@
 
 #![cfg_attr(not(feature = "std"), no_std)]
 
+pub mod async_tree;
+mod batch;
 #[cfg(feature = "blake2b")]
 pub mod blake2b;
+pub mod changeset;
+pub mod compact_store;
 pub mod default_store;
+#[cfg(all(feature = "std", feature = "borsh"))]
+pub mod dump;
 pub mod error;
 pub mod h256;
 pub mod internal_key;
 pub mod merge;
 pub mod merkle_proof;
+pub mod ordered_store;
+pub mod proof_encoding;
 pub mod proof_ics23;
 pub mod sha256;
+#[cfg(feature = "std")]
+pub mod shared;
+pub mod state_sync;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 #[cfg(test)]
 mod tests;
 pub mod traits;
@@ -80,24 +93,31 @@ pub mod tree;
 
 pub use h256::{Hash, H256};
 pub use internal_key::InternalKey;
-pub use merkle_proof::{CompiledMerkleProof, MerkleProof};
+pub use merge::hash_leaf;
+pub use merkle_proof::{
+    CompiledMerkleProof, CompiledMerkleProofBuilder, EmptySubtreeProof, MerkleProof, RangeProof,
+    SubtreeProof,
+};
 pub use traits::Key;
 pub use tree::SparseMerkleTree;
 
 /// Expected path size: log2(256) * 2, used for hint vector capacity
 pub const EXPECTED_PATH_SIZE: usize = 16;
-/// Height of sparse merkle tree
+/// Height of a sparse merkle tree with 32-byte keys, `8 * N` for `N`-byte
+/// keys
 pub const TREE_HEIGHT: usize = 256;
 /// Key limit size
 pub const KEY_LIMIT: usize = 4_294_967_295u32 as usize;
 
 cfg_if::cfg_if! {
     if #[cfg(feature = "std")] {
+        use std::borrow;
         use std::collections;
         use std::vec;
         use std::string;
     } else {
         extern crate alloc;
+        use alloc::borrow;
         use alloc::collections;
         use alloc::vec;
         use alloc::string;
@@ -19,6 +19,11 @@ pub fn merge<H: Hasher + Default>(lhs: &H256, rhs: &H256) -> H256 {
 
 /// hash_leaf = hash(prefix | key | value)
 /// zero value indicates a key is to be deleted, this function returns zero for zero value
+///
+/// The tree stores no leaf for a zero value. A zero leaf hash merges away,
+/// so `MerkleProof::compute_root_from_leaf_hashes` rejects it, and a leaf
+/// hash only proves a value once the verifier has computed it here from the
+/// key and the value.
 pub fn hash_leaf<H: Hasher + Default, K, V, const N: usize>(key: &K, value: &V) -> H256
 where
     K: Key<N>,
@@ -4,9 +4,10 @@ use crate::{
     merge::{hash_leaf, merge},
     traits::{Hasher, Value},
     vec::Vec,
-    Key, H256, TREE_HEIGHT,
+    InternalKey, Key, H256,
 };
-use core::convert::TryInto;
+use core::convert::{TryFrom, TryInto};
+use core::fmt;
 
 type Range = core::ops::Range<usize>;
 
@@ -46,19 +47,25 @@ impl MerkleProof {
     }
 
     /// convert merkle proof into CompiledMerkleProof
-    pub fn compile<K, const N: usize>(
-        self,
-        mut leaves: Vec<(K, H256)>,
-    ) -> Result<CompiledMerkleProof>
+    #[deprecated(note = "the values are never used, use `compile_keys` instead")]
+    pub fn compile<K, const N: usize>(self, leaves: Vec<(K, H256)>) -> Result<CompiledMerkleProof>
     where
         K: Key<N>,
     {
-        if leaves.is_empty() {
+        self.compile_keys(leaves.into_iter().map(|(k, _v)| k).collect())
+    }
+
+    /// convert merkle proof of `keys` into CompiledMerkleProof
+    pub fn compile_keys<K, const N: usize>(self, mut keys: Vec<K>) -> Result<CompiledMerkleProof>
+    where
+        K: Key<N>,
+    {
+        if keys.is_empty() {
             return Err(Error::EmptyKeys);
-        } else if leaves.len() != self.leaves_count() {
+        } else if keys.len() != self.leaves_count() {
             return Err(Error::IncorrectNumberOfLeaves {
                 expected: self.leaves_count(),
-                actual: leaves.len(),
+                actual: keys.len(),
             });
         }
 
@@ -66,27 +73,28 @@ impl MerkleProof {
         let mut leaves_path: Vec<VecDeque<_>> = leaves_path.into_iter().map(Into::into).collect();
         let mut proof: VecDeque<_> = proof.into();
 
-        // sort leaves
-        leaves.sort_unstable_by_key(|(k, _v)| **k);
+        // sort keys
+        keys.sort_unstable_by_key(|k| **k);
+        check_unique_keys(keys.iter().map(|k| &**k))?;
         // tree_buf: (height, key) -> (key_index, node)
-        let mut tree_buf: BTreeMap<_, _> = leaves
+        let mut tree_buf: BTreeMap<_, _> = keys
             .into_iter()
             .enumerate()
-            .map(|(i, (k, _v))| ((0, *k), (i, leaf_program(i))))
+            .map(|(i, k)| ((0, *k), (i, leaf_program(i))))
             .collect();
         // rebuild the tree from bottom to top
         while !tree_buf.is_empty() {
             // pop_front from tree_buf, the API is unstable
-            let &(mut height, key) = tree_buf.keys().next().unwrap();
+            let &(height, key) = tree_buf.keys().next().unwrap();
             let (leaf_index, program) = tree_buf.remove(&(height, key)).unwrap();
 
             if proof.is_empty() && tree_buf.is_empty() {
-                return Ok(CompiledMerkleProof(program.0));
-            } else if height == TREE_HEIGHT {
+                return Ok(CompiledMerkleProof::versioned(program.0));
+            } else if height == 8 * N {
                 if !proof.is_empty() {
                     return Err(Error::CorruptedProof);
                 }
-                return Ok(CompiledMerkleProof(program.0));
+                return Ok(CompiledMerkleProof::versioned(program.0));
             }
 
             let mut sibling_key = key.parent_path(height);
@@ -105,21 +113,18 @@ impl MerkleProof {
                 } else {
                     let merge_height = leaves_path[leaf_index]
                         .front()
-                        .map(|h| *h as usize)
+                        .copied()
                         .unwrap_or(height);
+                    check_merge_height::<N>(height, merge_height)?;
                     if height != merge_height {
-                        debug_assert!(height < merge_height);
                         let parent_key = key.copy_bits(merge_height..);
                         // skip zeros
                         tree_buf.insert((merge_height, parent_key), (leaf_index, program));
                         continue;
                     }
-                    let (proof, proof_height) = proof.pop_front().expect("pop proof");
-                    debug_assert_eq!(proof_height, leaves_path[leaf_index][0]);
-                    let proof_height = proof_height as usize;
-                    debug_assert!(height <= proof_height);
-                    if height < proof_height {
-                        height = proof_height;
+                    let (proof, proof_height) = proof.pop_front().ok_or(Error::CorruptedProof)?;
+                    if proof_height != height {
+                        return Err(Error::CorruptedProof);
                     }
 
                     let parent_key = key.parent_path(height);
@@ -140,8 +145,79 @@ impl MerkleProof {
     /// return EmptyProof error when proof is empty
     /// return CorruptedProof error when proof is invalid
     pub fn compute_root<H: Hasher + Default, K, V, const N: usize>(
+        self,
+        leaves: Vec<(K, V)>,
+    ) -> Result<H256>
+    where
+        K: Key<N>,
+        V: Value,
+    {
+        let leaves = leaves
+            .into_iter()
+            .map(|(k, v)| (k, hash_leaf::<H, K, V, N>(&k, &v)))
+            .collect();
+        self.root_from_hashes::<H, K, N>(leaves)
+    }
+
+    /// Compute root from proof
+    /// leaves: a vector of (key, leaf hash), see `hash_leaf`
+    ///
+    /// This computes a root, it does not verify the leaves. A leaf hash is
+    /// opaque, so the hash of an internal node, or any hash not taken from
+    /// the key, gives the root of the tree as well. The root only proves
+    /// that a key holds a value when the caller computed the leaf hash from
+    /// both with `hash_leaf` itself. Returns ZeroLeafHash error for a zero
+    /// leaf hash, which merges away and proves nothing, so absent keys can
+    /// not be proven from leaf hashes.
+    pub fn compute_root_from_leaf_hashes<H: Hasher + Default, K, const N: usize>(
+        self,
+        leaves: Vec<(K, H256)>,
+    ) -> Result<H256>
+    where
+        K: Key<N>,
+    {
+        if leaves.iter().any(|(_k, leaf_hash)| leaf_hash.is_zero()) {
+            return Err(Error::ZeroLeafHash);
+        }
+        self.root_from_hashes::<H, K, N>(leaves)
+    }
+
+    /// Compute root from the leaf hashes, zero for absent keys
+    fn root_from_hashes<H: Hasher + Default, K, const N: usize>(
+        self,
+        mut leaves: Vec<(K, H256)>,
+    ) -> Result<H256>
+    where
+        K: Key<N>,
+    {
+        if leaves.is_empty() {
+            return Err(Error::EmptyKeys);
+        } else if leaves.len() != self.leaves_count() {
+            return Err(Error::IncorrectNumberOfLeaves {
+                expected: self.leaves_count(),
+                actual: leaves.len(),
+            });
+        }
+
+        // sort leaves
+        leaves.sort_unstable_by_key(|(k, _v)| **k);
+        self.fold::<H, K, N>(leaves, None)
+            .map(|(root, _subtree)| root)
+    }
+
+    /// Compute root from proof, and check that no leaf missing from `leaves`
+    /// lies between two of them
+    ///
+    /// With `open_start` the tree may hold leaves before the first of
+    /// `leaves`, with `open_end` after the last one. Returns
+    /// NonContiguousLeaves error when the proof hides a leaf in between,
+    /// and CorruptedProof error for a leaf with a zero value, whose zero
+    /// hash merges away and would let the proof hide leaves next to it.
+    pub fn compute_contiguous_root<H: Hasher + Default, K, V, const N: usize>(
         self,
         mut leaves: Vec<(K, V)>,
+        open_start: bool,
+        open_end: bool,
     ) -> Result<H256>
     where
         K: Key<N>,
@@ -156,63 +232,132 @@ impl MerkleProof {
             });
         }
 
+        leaves.sort_unstable_by_key(|(k, _v)| **k);
+        let leaves = leaves
+            .into_iter()
+            .map(|(k, v)| (k, hash_leaf::<H, K, V, N>(&k, &v)))
+            .collect();
+        let bounds = Bounds {
+            open_start,
+            open_end,
+            subtree: 0..0,
+        };
+        self.fold::<H, K, N>(leaves, Some(bounds))
+            .map(|(root, _subtree)| root)
+    }
+
+    /// Rebuild the root from the sorted leaf hashes
+    ///
+    /// Every node in the rebuilt tree covers a range of the leaves. With
+    /// `bounds` set, only ranges next to each other may merge, and a sibling
+    /// from the proof may only sit before the first or after the last leaf,
+    /// and only if the matching bound is open, or between two leaves of
+    /// `bounds.subtree`, and no leaf hash may be zero. Also returns the node
+    /// covering exactly that range, if any.
+    fn fold<H: Hasher + Default, K, const N: usize>(
+        self,
+        leaves: Vec<(K, H256)>,
+        bounds: Option<Bounds>,
+    ) -> Result<(H256, Option<H256>)>
+    where
+        K: Key<N>,
+    {
+        let leaves_len = leaves.len();
         let (leaves_path, proof) = self.take();
         let mut leaves_path: Vec<VecDeque<_>> = leaves_path.into_iter().map(Into::into).collect();
         let mut proof: VecDeque<_> = proof.into();
 
-        // sort leaves
-        leaves.sort_unstable_by_key(|(k, _v)| **k);
-        // tree_buf: (height, key) -> (key_index, node)
+        // tree_buf: (height, key) -> (key_index, node, leaves range)
         let mut tree_buf: BTreeMap<_, _> = leaves
             .into_iter()
             .enumerate()
-            .map(|(i, (k, v))| ((0, *k), (i, hash_leaf::<H, K, V, N>(&k, &v))))
+            .map(|(i, (k, node))| ((0, *k), (i, node, i..i + 1)))
             .collect();
+        if tree_buf.len() != leaves_len {
+            return Err(Error::DuplicateKeys);
+        }
+        let contiguous = bounds.is_some();
+        if contiguous && tree_buf.values().any(|(_i, node, _range)| node.is_zero()) {
+            return Err(Error::CorruptedProof);
+        }
+        let covers_all = |range: &Range| !contiguous || *range == (0..leaves_len);
+        let is_subtree = |range: &Range| bounds.as_ref().is_some_and(|b| b.subtree == *range);
+        let mut subtree = None;
         // rebuild the tree from bottom to top
         while !tree_buf.is_empty() {
             // pop_front from tree_buf, the API is unstable
-            let (&(mut height, key), &(leaf_index, node)) = tree_buf.iter().next().unwrap();
+            let (&(height, key), (leaf_index, node, range)) = tree_buf.iter().next().unwrap();
+            let (leaf_index, node, mut range) = (*leaf_index, *node, range.clone());
             tree_buf.remove(&(height, key));
+            if is_subtree(&range) {
+                subtree = Some(node);
+            }
 
             if proof.is_empty() && tree_buf.is_empty() {
-                return Ok(node);
+                if !covers_all(&range) {
+                    return Err(Error::NonContiguousLeaves);
+                }
+                return Ok((node, subtree));
             } else if height == 8 * N {
                 if !proof.is_empty() {
                     return Err(Error::CorruptedProof);
                 }
-                return Ok(node);
+                if !covers_all(&range) {
+                    return Err(Error::NonContiguousLeaves);
+                }
+                return Ok((node, subtree));
             }
 
             let mut sibling_key = key.parent_path(height);
             if !key.get_bit(height) {
                 sibling_key.set_bit(height)
             }
-            let (sibling, sibling_height) =
-                if Some(&(height, sibling_key)) == tree_buf.keys().next() {
-                    let (_leaf_index, sibling) = tree_buf
-                        .remove(&(height, sibling_key))
-                        .expect("pop sibling");
-                    (sibling, height)
-                } else {
-                    let merge_height = leaves_path[leaf_index]
-                        .front()
-                        .map(|h| *h as usize)
-                        .unwrap_or(height);
-                    if height != merge_height {
-                        debug_assert!(height < merge_height);
-                        let parent_key = key.copy_bits(merge_height..);
-                        // skip zeros
-                        tree_buf.insert((merge_height, parent_key), (leaf_index, node));
-                        continue;
+            let sibling = if Some(&(height, sibling_key)) == tree_buf.keys().next() {
+                let (_leaf_index, sibling, sibling_range) = tree_buf
+                    .remove(&(height, sibling_key))
+                    .expect("pop sibling");
+                if contiguous && range.end != sibling_range.start {
+                    return Err(Error::NonContiguousLeaves);
+                }
+                if is_subtree(&sibling_range) {
+                    subtree = Some(sibling);
+                }
+                range.end = sibling_range.end;
+                sibling
+            } else {
+                let merge_height = leaves_path[leaf_index]
+                    .front()
+                    .copied()
+                    .unwrap_or(height);
+                check_merge_height::<N>(height, merge_height)?;
+                if height != merge_height {
+                    let parent_key = key.copy_bits(merge_height..);
+                    // skip zeros
+                    tree_buf.insert((merge_height, parent_key), (leaf_index, node, range));
+                    continue;
+                }
+                let (node, proof_height) = proof.pop_front().ok_or(Error::CorruptedProof)?;
+                if proof_height != height {
+                    return Err(Error::CorruptedProof);
+                }
+                if let Some(bounds) = &bounds {
+                    // the sibling holds at least one leaf, which must
+                    // lie outside of the proven range, or inside the
+                    // subtree
+                    let inside = |edge: usize| {
+                        bounds.subtree.start < edge && edge < bounds.subtree.end
+                    };
+                    let allowed = if key.get_bit(height) {
+                        (bounds.open_start && range.start == 0) || inside(range.start)
+                    } else {
+                        (bounds.open_end && range.end == leaves_len) || inside(range.end)
+                    };
+                    if !allowed {
+                        return Err(Error::NonContiguousLeaves);
                     }
-                    let (node, height) = proof.pop_front().expect("pop proof");
-                    debug_assert_eq!(height, leaves_path[leaf_index][0]);
-                    (node, height as usize)
-                };
-            debug_assert!(height <= sibling_height);
-            if height < sibling_height {
-                height = sibling_height;
-            }
+                }
+                node
+            };
             // skip zero merkle path
             let parent_key = key.parent_path(height);
 
@@ -222,7 +367,7 @@ impl MerkleProof {
                 merge::<H>(&node, &sibling)
             };
             leaves_path[leaf_index].pop_front();
-            tree_buf.insert((height + 1, parent_key), (leaf_index, parent));
+            tree_buf.insert((height + 1, parent_key), (leaf_index, parent, range));
         }
 
         Err(Error::CorruptedProof)
@@ -244,8 +389,41 @@ impl MerkleProof {
     }
 }
 
+/// Check that sorted keys hold every key at most once
+pub(crate) fn check_unique_keys<'a, const N: usize>(
+    sorted_keys: impl IntoIterator<Item = &'a InternalKey<N>>,
+) -> Result<()> {
+    let mut last = None;
+    for key in sorted_keys {
+        if last == Some(key) {
+            return Err(Error::DuplicateKeys);
+        }
+        last = Some(key);
+    }
+    Ok(())
+}
+
+/// Check that a leaf path of an untrusted proof climbs the tree
+/// Where `MerkleProof::fold` lets the proof hide leaves
+struct Bounds {
+    /// Before the first leaf
+    open_start: bool,
+    /// After the last leaf
+    open_end: bool,
+    /// Between the leaves of this range
+    subtree: Range,
+}
+
+fn check_merge_height<const N: usize>(height: usize, merge_height: usize) -> Result<()> {
+    if merge_height < height || merge_height >= 8 * N {
+        return Err(Error::CorruptedProof);
+    }
+    Ok(())
+}
+
 fn leaf_program(leaf_index: usize) -> (Vec<u8>, Option<Range>) {
-    let program = vec![0x4C];
+    let mut program = Vec::new();
+    Instruction::Leaf.write(&mut program, false);
     (
         program,
         Some(Range {
@@ -261,13 +439,13 @@ fn proof_program(
     height: usize,
 ) -> (Vec<u8>, Option<Range>) {
     let (child_program, child_range) = child;
-    let mut program = Vec::new();
-    let height = height as u64;
-    program.resize(41 + child_program.len(), 0x50);
-    program[..child_program.len()].copy_from_slice(child_program);
-    program[child_program.len() + 1..child_program.len() + 9]
-        .copy_from_slice(&height.to_be_bytes());
-    program[child_program.len() + 9..].copy_from_slice(proof.as_slice());
+    let mut program = Vec::with_capacity(child_program.len() + 36);
+    program.extend_from_slice(child_program);
+    Instruction::Proof {
+        height,
+        sibling: proof,
+    }
+    .write(&mut program, false);
     (program, child_range.clone())
 }
 
@@ -298,69 +476,297 @@ fn merge_program(
             return Err(Error::NonMergableRange);
         }
     };
-    let mut program = Vec::new();
-    program.resize(9 + a_program.len() + b_program.len(), 0x48);
+    let mut program = Vec::with_capacity(a_program.len() + b_program.len() + 4);
     if a_comes_first {
-        program[..a_program.len()].copy_from_slice(a_program);
-        program[a_program.len()..a_program.len() + b_program.len()].copy_from_slice(b_program);
+        program.extend_from_slice(a_program);
+        program.extend_from_slice(b_program);
     } else {
-        program[..b_program.len()].copy_from_slice(b_program);
-        program[b_program.len()..a_program.len() + b_program.len()].copy_from_slice(a_program);
+        program.extend_from_slice(b_program);
+        program.extend_from_slice(a_program);
     }
-    let height = height as u64;
-    let height_pos = a_program.len() + b_program.len() + 1;
-    program[height_pos..height_pos + 8].copy_from_slice(&height.to_be_bytes());
+    Instruction::Merge { height }.write(&mut program, false);
     Ok((program, Some(range)))
 }
 
+/// Push a leaf onto the stack
+const LEAF: u8 = 0x4C;
+/// Merge the top of the stack with a sibling from the proof
+const PROOF: u8 = 0x50;
+/// Merge the two nodes on top of the stack
+const MERGE: u8 = 0x48;
+
+/// Version byte that starts a compiled proof in the current format
+///
+/// Legacy proofs start with an instruction instead and carry every height
+/// in eight bytes, the current format carries heights as LEB128 varints.
+pub const COMPILED_PROOF_VERSION: u8 = 2;
+
+/// Append `value` as a LEB128 varint
+pub(crate) fn put_varint(out: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// Read a LEB128 varint from the front of `bytes`, none when it is
+/// truncated, overlong or does not fit in usize
+pub(crate) fn take_varint(bytes: &mut &[u8]) -> Option<usize> {
+    let mut value = 0usize;
+    for shift in (0..usize::BITS).step_by(7) {
+        let (&byte, rest) = bytes.split_first()?;
+        *bytes = rest;
+        let bits = (byte & 0x7F) as usize;
+        // reject overlong encodings and bits beyond usize
+        if (shift > 0 && byte == 0) || (bits << shift) >> shift != bits {
+            return None;
+        }
+        value |= bits << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+fn fmt_hash(hash: &H256, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "0x")?;
+    for byte in hash.as_slice() {
+        write!(f, "{:02x}", byte)?;
+    }
+    Ok(())
+}
+
+/// An instruction of a compiled proof
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum Instruction {
+    /// Push the next of the sorted leaves onto the stack
+    Leaf,
+    /// Merge the top of the stack with a sibling at `height`
+    Proof { height: usize, sibling: H256 },
+    /// Merge the two nodes on top of the stack at `height`
+    Merge { height: usize },
+}
+
+impl Instruction {
+    /// The height the instruction merges at, none for a leaf
+    pub fn height(&self) -> Option<usize> {
+        match self {
+            Instruction::Leaf => None,
+            Instruction::Proof { height, .. } | Instruction::Merge { height } => Some(*height),
+        }
+    }
+
+    /// Append the instruction to a program in the current or legacy format
+    pub(crate) fn write(&self, program: &mut Vec<u8>, legacy: bool) {
+        program.push(match self {
+            Instruction::Leaf => LEAF,
+            Instruction::Proof { .. } => PROOF,
+            Instruction::Merge { .. } => MERGE,
+        });
+        if let Some(height) = self.height() {
+            if legacy {
+                program.extend_from_slice(&(height as u64).to_be_bytes());
+            } else {
+                put_varint(program, height);
+            }
+        }
+        if let Instruction::Proof { sibling, .. } = self {
+            program.extend_from_slice(sibling.as_slice());
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Instruction::Leaf => write!(f, "LEAF"),
+            Instruction::Proof { height, sibling } => {
+                write!(f, "PROOF height={} sibling=", height)?;
+                fmt_hash(sibling, f)
+            }
+            Instruction::Merge { height } => write!(f, "MERGE height={}", height),
+        }
+    }
+}
+
+/// Iterator over the instructions of a compiled proof, see
+/// `CompiledMerkleProof::instructions`
+///
+/// Stops after the first error.
+pub struct Instructions<'a> {
+    program: &'a [u8],
+    legacy: bool,
+}
+
+impl<'a> Instructions<'a> {
+    fn new(program: &'a [u8]) -> Self {
+        match program.split_first() {
+            Some((&COMPILED_PROOF_VERSION, program)) => Instructions {
+                program,
+                legacy: false,
+            },
+            _ => Instructions {
+                program,
+                legacy: true,
+            },
+        }
+    }
+
+    fn read(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.program.len() < len {
+            return Err(Error::CorruptedProof);
+        }
+        let (read, rest) = self.program.split_at(len);
+        self.program = rest;
+        Ok(read)
+    }
+
+    fn height(&mut self) -> Result<usize> {
+        if self.legacy {
+            let height: [u8; 8] = self.read(8)?.try_into().expect("eight bytes");
+            return usize::try_from(u64::from_be_bytes(height)).map_err(|_| Error::CorruptedProof);
+        }
+        take_varint(&mut self.program).ok_or(Error::CorruptedProof)
+    }
+
+    fn next_instruction(&mut self, code: u8) -> Result<Instruction> {
+        match code {
+            LEAF => Ok(Instruction::Leaf),
+            PROOF => {
+                let height = self.height()?;
+                let sibling: [u8; 32] = self.read(32)?.try_into().expect("32 bytes");
+                Ok(Instruction::Proof {
+                    height,
+                    sibling: sibling.into(),
+                })
+            }
+            MERGE => Ok(Instruction::Merge {
+                height: self.height()?,
+            }),
+            _ => Err(Error::InvalidCode(code)),
+        }
+    }
+}
+
+impl<'a> Iterator for Instructions<'a> {
+    type Item = Result<Instruction>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (&code, rest) = self.program.split_first()?;
+        self.program = rest;
+        let instruction = self.next_instruction(code);
+        if instruction.is_err() {
+            // stop after an error
+            self.program = &[];
+        }
+        Some(instruction)
+    }
+}
+
 /// An structure optimized for verify merkle proof
 #[derive(Debug, Clone)]
 pub struct CompiledMerkleProof(pub Vec<u8>);
 
 impl CompiledMerkleProof {
+    /// Prefix a program with the version byte of the current format
+    pub(crate) fn versioned(program: Vec<u8>) -> Self {
+        let mut bytes = Vec::with_capacity(program.len() + 1);
+        bytes.push(COMPILED_PROOF_VERSION);
+        bytes.extend(program);
+        CompiledMerkleProof(bytes)
+    }
+
+    /// Decode the program into its instructions
+    pub fn instructions(&self) -> Instructions<'_> {
+        Instructions::new(&self.0)
+    }
+
+    /// Version of the format of the proof, 1 for the legacy format
+    pub fn version(&self) -> u8 {
+        match self.0.first() {
+            Some(&COMPILED_PROOF_VERSION) => COMPILED_PROOF_VERSION,
+            _ => 1,
+        }
+    }
+
+    /// Convert the proof into the legacy format, for verifiers that do not
+    /// know the current one
+    pub fn to_legacy(&self) -> Result<CompiledMerkleProof> {
+        let mut program = Vec::with_capacity(self.0.len() * 2);
+        for instruction in self.instructions() {
+            instruction?.write(&mut program, true);
+        }
+        Ok(CompiledMerkleProof(program))
+    }
+
+    /// Compute root from proof, in the current or the legacy format
     pub fn compute_root<H: Hasher + Default, K, V, const N: usize>(
         &self,
-        mut leaves: Vec<(K, V)>,
+        leaves: Vec<(K, V)>,
     ) -> Result<H256>
     where
         K: Key<N>,
         V: Value,
+    {
+        let leaves = leaves
+            .into_iter()
+            .map(|(k, v)| (k, hash_leaf::<H, K, V, N>(&k, &v)))
+            .collect();
+        self.root_from_hashes::<H, K, N>(leaves)
+    }
+
+    /// Compute root from proof, from (key, leaf hash) pairs, see `hash_leaf`
+    ///
+    /// Like `MerkleProof::compute_root_from_leaf_hashes`, this computes a
+    /// root and does not verify the leaves: the root only proves that a key
+    /// holds a value when the caller computed the leaf hash from both.
+    /// Returns ZeroLeafHash error for a zero leaf hash.
+    pub fn compute_root_from_leaf_hashes<H: Hasher + Default, K, const N: usize>(
+        &self,
+        leaves: Vec<(K, H256)>,
+    ) -> Result<H256>
+    where
+        K: Key<N>,
+    {
+        if leaves.iter().any(|(_k, leaf_hash)| leaf_hash.is_zero()) {
+            return Err(Error::ZeroLeafHash);
+        }
+        self.root_from_hashes::<H, K, N>(leaves)
+    }
+
+    /// Compute root from the leaf hashes, zero for absent keys
+    fn root_from_hashes<H: Hasher + Default, K, const N: usize>(
+        &self,
+        mut leaves: Vec<(K, H256)>,
+    ) -> Result<H256>
+    where
+        K: Key<N>,
     {
         leaves.sort_unstable_by_key(|(k, _v)| **k);
-        let mut program_index = 0;
+        check_unique_keys(leaves.iter().map(|(k, _v)| &**k))?;
         let mut leave_index = 0;
         let mut stack = Vec::new();
-        while program_index < self.0.len() {
-            let code = self.0[program_index];
-            program_index += 1;
-            match code {
-                // L
-                0x4C => {
+        for instruction in self.instructions() {
+            let instruction = instruction?;
+            if instruction.height().is_some_and(|height| height >= 8 * N) {
+                return Err(Error::CorruptedProof);
+            }
+            match instruction {
+                Instruction::Leaf => {
                     if leave_index >= leaves.len() {
                         return Err(Error::CorruptedStack);
                     }
-                    let (k, v) = leaves[leave_index].clone();
-                    stack.push((*k, hash_leaf::<H, K, V, N>(&k, &v)));
+                    let (k, leaf_hash) = leaves[leave_index];
+                    stack.push((*k, leaf_hash));
                     leave_index += 1;
                 }
-                // P
-                0x50 => {
-                    if stack.is_empty() {
-                        return Err(Error::CorruptedStack);
-                    }
-                    if program_index + 40 > self.0.len() {
-                        return Err(Error::CorruptedProof);
-                    }
-                    let height: [u8; 8] = self.0[program_index..program_index + 8]
-                        .try_into()
-                        .expect("8 bytes should fit in an 8 byte array");
-                    let height = u64::from_be_bytes(height) as usize;
-                    program_index += 8;
-                    let mut data = [0u8; 32];
-                    data.copy_from_slice(&self.0[program_index..program_index + 32]);
-                    program_index += 32;
-                    let proof = H256::from(data);
-                    let (key, value) = stack.pop().unwrap();
+                Instruction::Proof {
+                    height,
+                    sibling: proof,
+                } => {
+                    let (key, value) = stack.pop().ok_or(Error::CorruptedStack)?;
                     let parent_key = key.parent_path(height);
                     let parent = if key.get_bit(height) {
                         merge::<H>(&proof, &value)
@@ -369,21 +775,9 @@ impl CompiledMerkleProof {
                     };
                     stack.push((parent_key, parent));
                 }
-                // H
-                0x48 => {
-                    if stack.len() < 2 {
-                        return Err(Error::CorruptedStack);
-                    }
-                    if program_index >= self.0.len() {
-                        return Err(Error::CorruptedProof);
-                    }
-                    let height: [u8; 8] = self.0[program_index..program_index + 8]
-                        .try_into()
-                        .expect("8 bytes should fit in an 8 byte array");
-                    let height = u64::from_be_bytes(height) as usize;
-                    program_index += 8;
-                    let (key_b, value_b) = stack.pop().unwrap();
-                    let (key_a, value_a) = stack.pop().unwrap();
+                Instruction::Merge { height } => {
+                    let (key_b, value_b) = stack.pop().ok_or(Error::CorruptedStack)?;
+                    let (key_a, value_a) = stack.pop().ok_or(Error::CorruptedStack)?;
                     let parent_key_a = key_a.copy_bits(height..);
                     let parent_key_b = key_b.copy_bits(height..);
                     let a_set = key_a.get_bit(height);
@@ -403,7 +797,6 @@ impl CompiledMerkleProof {
                     };
                     stack.push((parent_key_a, parent));
                 }
-                _ => return Err(Error::InvalidCode(code)),
             }
         }
         if stack.len() != 1 {
@@ -425,3 +818,277 @@ impl CompiledMerkleProof {
         Ok(&calculated_root == root)
     }
 }
+
+/// One instruction per line after the version, and the decoding error last
+/// if the program is malformed
+impl fmt::Display for CompiledMerkleProof {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "compiled proof v{}", self.version())?;
+        for instruction in self.instructions() {
+            match instruction {
+                Ok(instruction) => write!(f, "\n{}", instruction)?,
+                Err(err) => write!(f, "\nerror: {}", err)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Assembles a compiled proof from instructions, in the current format
+/// unless `legacy` is set
+///
+/// The program is not checked, so that malformed proofs can be crafted.
+#[derive(Debug, Default, Clone)]
+pub struct CompiledMerkleProofBuilder {
+    instructions: Vec<Instruction>,
+    legacy: bool,
+}
+
+impl CompiledMerkleProofBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Emit the legacy format, with eight byte heights and no version
+    pub fn legacy(&mut self) -> &mut Self {
+        self.legacy = true;
+        self
+    }
+
+    pub fn push(&mut self, instruction: Instruction) -> &mut Self {
+        self.instructions.push(instruction);
+        self
+    }
+
+    pub fn leaf(&mut self) -> &mut Self {
+        self.push(Instruction::Leaf)
+    }
+
+    pub fn proof(&mut self, height: usize, sibling: H256) -> &mut Self {
+        self.push(Instruction::Proof { height, sibling })
+    }
+
+    pub fn merge(&mut self, height: usize) -> &mut Self {
+        self.push(Instruction::Merge { height })
+    }
+
+    pub fn build(&self) -> CompiledMerkleProof {
+        let mut program = Vec::new();
+        for instruction in self.instructions.iter() {
+            instruction.write(&mut program, self.legacy);
+        }
+        if self.legacy {
+            CompiledMerkleProof(program)
+        } else {
+            CompiledMerkleProof::versioned(program)
+        }
+    }
+}
+
+/// Proof of the root of a subtree, see `SparseMerkleTree::subtree_proof`
+#[derive(Debug, Clone)]
+pub struct SubtreeProof<K, V> {
+    /// Leaf of the subtree with the lowest key
+    pub first: (K, V),
+    /// Leaf of the subtree with the highest key, the same as `first` for a
+    /// subtree of one leaf
+    pub last: (K, V),
+    /// Last leaf before the subtree, none if there is none
+    pub left: Option<(K, V)>,
+    /// First leaf after the subtree, none if there is none
+    pub right: Option<(K, V)>,
+    /// Proof of `left`, `first`, `last` and `right`
+    pub proof: MerkleProof,
+}
+
+impl<K, V> SubtreeProof<K, V>
+where
+    V: Value,
+{
+    /// Verify that `subtree_root` is the root of the subtree covering
+    /// `prefix_key.parent_path(height)` under `root`
+    ///
+    /// The hashes do not commit to the height of a node, so the siblings
+    /// above a node alone can not place it. Checks instead that `first` and
+    /// `last` lie in the subtree and the neighbours outside of it, that no
+    /// leaf of the tree lies between the neighbours and the subtree, and
+    /// that `subtree_root` covers exactly the leaves from `first` to `last`.
+    /// Returns CorruptedProof error for a leaf with a zero value.
+    pub fn verify<H: Hasher + Default, const N: usize>(
+        &self,
+        root: &H256,
+        prefix_key: &K,
+        height: usize,
+        subtree_root: &H256,
+    ) -> Result<bool>
+    where
+        K: Key<N>,
+    {
+        let prefix = prefix_key.parent_path(height);
+        let (first, last) = (&self.first, &self.last);
+        if first.0.parent_path(height) != prefix
+            || last.0.parent_path(height) != prefix
+            || *first.0 > *last.0
+            || (first.0 == last.0 && first.1 != last.1)
+            || self.left.iter().any(|(k, _v)| k.parent_path(height) >= prefix)
+            || self.right.iter().any(|(k, _v)| k.parent_path(height) <= prefix)
+        {
+            return Ok(false);
+        }
+        let mut leaves: Vec<(K, V)> = self.left.iter().cloned().collect();
+        let start = leaves.len();
+        leaves.push(first.clone());
+        if first.0 != last.0 {
+            leaves.push(last.clone());
+        }
+        let subtree = start..leaves.len();
+        leaves.extend(self.right.iter().cloned());
+        if leaves.len() != self.proof.leaves_count() {
+            return Err(Error::IncorrectNumberOfLeaves {
+                expected: self.proof.leaves_count(),
+                actual: leaves.len(),
+            });
+        }
+        let leaves = leaves
+            .into_iter()
+            .map(|(k, v)| (k, hash_leaf::<H, K, V, N>(&k, &v)))
+            .collect();
+        let bounds = Bounds {
+            open_start: self.left.is_some(),
+            open_end: self.right.is_some(),
+            subtree,
+        };
+        match self.proof.clone().fold::<H, K, N>(leaves, Some(bounds)) {
+            Ok((computed, subtree)) => {
+                Ok(&computed == root && subtree.as_ref() == Some(subtree_root))
+            }
+            Err(Error::NonContiguousLeaves) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// Proof that no leaf lies in a subtree, see
+/// `SparseMerkleTree::empty_subtree_proof`
+#[derive(Debug, Clone)]
+pub struct EmptySubtreeProof<K, V> {
+    /// Last leaf before the subtree, none if there is none
+    pub left: Option<(K, V)>,
+    /// First leaf after the subtree, none if there is none
+    pub right: Option<(K, V)>,
+    /// Proof of `left` and `right`, none for an empty tree
+    pub proof: Option<MerkleProof>,
+}
+
+impl<K, V> EmptySubtreeProof<K, V>
+where
+    V: Value,
+{
+    /// Verify that the subtree covering `prefix_key.parent_path(height)` is
+    /// empty under `root`
+    ///
+    /// Checks that `left` and `right` lie on either side of the subtree and
+    /// that no leaf of the tree lies between them. Returns CorruptedProof
+    /// error for a neighbour with a zero value.
+    pub fn verify<H: Hasher + Default, const N: usize>(
+        &self,
+        root: &H256,
+        prefix_key: &K,
+        height: usize,
+    ) -> Result<bool>
+    where
+        K: Key<N>,
+    {
+        let prefix = prefix_key.parent_path(height);
+        if self.left.iter().any(|(k, _v)| k.parent_path(height) >= prefix)
+            || self.right.iter().any(|(k, _v)| k.parent_path(height) <= prefix)
+        {
+            return Ok(false);
+        }
+        let leaves: Vec<(K, V)> = self.left.iter().chain(self.right.iter()).cloned().collect();
+        let proof = match &self.proof {
+            Some(proof) => proof.clone(),
+            None => return Ok(leaves.is_empty() && root.is_zero()),
+        };
+        if leaves.is_empty() {
+            return Ok(false);
+        }
+        match proof.compute_contiguous_root::<H, K, V, N>(
+            leaves,
+            self.left.is_some(),
+            self.right.is_some(),
+        ) {
+            Ok(computed) => Ok(&computed == root),
+            Err(Error::NonContiguousLeaves) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// Proof of every leaf in a range of keys, see
+/// `SparseMerkleTree::range_proof`
+#[derive(Debug, Clone)]
+pub struct RangeProof<K, V> {
+    /// Leaves in the range in key order
+    pub leaves: Vec<(K, V)>,
+    /// Last leaf before the range, none if there is none
+    pub left: Option<(K, V)>,
+    /// First leaf after the range, none if there is none
+    pub right: Option<(K, V)>,
+    /// Proof of `left`, `leaves` and `right`, none for an empty tree
+    pub proof: Option<MerkleProof>,
+}
+
+impl<K, V> RangeProof<K, V>
+where
+    V: Value,
+{
+    /// Verify that `leaves` are all the leaves with keys in `start..end`
+    /// under `root`
+    ///
+    /// Checks that the leaves lie in the range and the neighbours outside of
+    /// it, and that no leaf of the tree lies between consecutive ones.
+    pub fn verify<H: Hasher + Default, const N: usize>(
+        &self,
+        root: &H256,
+        start: &K,
+        end: &K,
+    ) -> Result<bool>
+    where
+        K: Key<N>,
+    {
+        if **start > **end
+            || self.leaves.iter().any(|(k, _v)| **k < **start || **k >= **end)
+            || self.left.iter().any(|(k, _v)| **k >= **start)
+            || self.right.iter().any(|(k, _v)| **k < **end)
+        {
+            return Ok(false);
+        }
+        let leaves: Vec<(K, V)> = self
+            .left
+            .iter()
+            .chain(self.leaves.iter())
+            .chain(self.right.iter())
+            .cloned()
+            .collect();
+        if leaves.windows(2).any(|pair| *pair[0].0 >= *pair[1].0) {
+            return Ok(false);
+        }
+        let proof = match &self.proof {
+            Some(proof) => proof.clone(),
+            None => return Ok(leaves.is_empty() && root.is_zero()),
+        };
+        if leaves.is_empty() {
+            return Ok(false);
+        }
+        match proof.compute_contiguous_root::<H, K, V, N>(
+            leaves,
+            self.left.is_some(),
+            self.right.is_some(),
+        ) {
+            Ok(computed) => Ok(&computed == root),
+            Err(Error::NonContiguousLeaves) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+}
@@ -0,0 +1,149 @@
+use crate::{
+    borrow::Cow,
+    collections::BTreeMap,
+    default_store::Map,
+    error::Error,
+    traits::Store,
+    tree::{BranchNode, LeafNode},
+    InternalKey, Key, H256,
+};
+#[cfg(feature = "borsh")]
+use borsh::{BorshDeserialize, BorshSerialize};
+use core::ops::{Bound, RangeBounds};
+
+/// An in-memory store that keeps leaves ordered by their `InternalKey`
+///
+/// Compared to `DefaultStore`, `sorted_leaves` is a linear walk instead of
+/// a sort, and leaves can be scanned by key range or key prefix.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "borsh", derive(BorshSerialize, BorshDeserialize))]
+pub struct OrderedStore<K, V, const N: usize>
+where
+    K: Key<N>,
+{
+    branches_map: Map<H256, BranchNode<K, N>>,
+    // leaf key -> (leaf hash, leaf)
+    leaves: BTreeMap<InternalKey<N>, (H256, LeafNode<K, V, N>)>,
+    // leaf hash -> leaf key
+    leaf_keys: Map<H256, InternalKey<N>>,
+}
+
+impl<K, V, const N: usize> Default for OrderedStore<K, V, N>
+where
+    K: Key<N>,
+{
+    fn default() -> Self {
+        Self {
+            branches_map: Map::default(),
+            leaves: BTreeMap::new(),
+            leaf_keys: Map::default(),
+        }
+    }
+}
+
+impl<K, V, const N: usize> OrderedStore<K, V, N>
+where
+    K: Key<N>,
+{
+    pub fn branches_map(&self) -> &Map<H256, BranchNode<K, N>> {
+        &self.branches_map
+    }
+    pub fn clear(&mut self) {
+        self.branches_map.clear();
+        self.leaves.clear();
+        self.leaf_keys.clear();
+    }
+
+    /// Leaves whose internal key falls in `range`, in key order
+    pub fn range<R>(&self, range: R) -> impl Iterator<Item = (K, &V)>
+    where
+        R: RangeBounds<InternalKey<N>>,
+    {
+        self.leaves
+            .range(range)
+            .map(|(_, (_, leaf))| (leaf.key, &leaf.value))
+    }
+
+    /// Leaves whose internal key starts with `prefix`, in key order
+    pub fn prefix<'a>(&'a self, prefix: &'a [u8]) -> impl Iterator<Item = (K, &'a V)> {
+        let start = if prefix.len() > N {
+            // no key is long enough to match, the range below will be empty
+            Bound::Excluded(InternalKey::new([u8::MAX; N]))
+        } else {
+            let mut start = [0u8; N];
+            start[..prefix.len()].copy_from_slice(prefix);
+            Bound::Included(InternalKey::new(start))
+        };
+        self.leaves
+            .range((start, Bound::Unbounded))
+            .take_while(move |(key, _)| key.as_slice().starts_with(prefix))
+            .map(|(_, (_, leaf))| (leaf.key, &leaf.value))
+    }
+}
+
+impl<K, V: Clone, const N: usize> Store<K, V, N> for OrderedStore<K, V, N>
+where
+    K: Key<N>,
+{
+    fn get_branch(&self, node: &H256) -> Result<Option<BranchNode<K, N>>, Error> {
+        Ok(self.branches_map.get(node).cloned())
+    }
+    fn get_leaf(&self, leaf_hash: &H256) -> Result<Option<LeafNode<K, V, N>>, Error> {
+        Ok(self
+            .leaf_keys
+            .get(leaf_hash)
+            .and_then(|key| self.leaves.get(key))
+            .map(|(_, leaf)| leaf.clone()))
+    }
+    fn get_branch_ref(&self, node: &H256) -> Result<Option<Cow<'_, BranchNode<K, N>>>, Error> {
+        Ok(self.branches_map.get(node).map(Cow::Borrowed))
+    }
+    fn get_leaf_ref(&self, leaf_hash: &H256) -> Result<Option<Cow<'_, LeafNode<K, V, N>>>, Error> {
+        Ok(self
+            .leaf_keys
+            .get(leaf_hash)
+            .and_then(|key| self.leaves.get(key))
+            .map(|(_, leaf)| Cow::Borrowed(leaf)))
+    }
+    fn insert_branch(&mut self, node: H256, branch: BranchNode<K, N>) -> Result<(), Error> {
+        self.branches_map.insert(node, branch);
+        Ok(())
+    }
+    fn insert_leaf(&mut self, leaf_hash: H256, leaf: LeafNode<K, V, N>) -> Result<(), Error> {
+        let key = *leaf.key;
+        if let Some((old_hash, _)) = self.leaves.insert(key, (leaf_hash, leaf)) {
+            if old_hash != leaf_hash {
+                self.leaf_keys.remove(&old_hash);
+            }
+        }
+        self.leaf_keys.insert(leaf_hash, key);
+        Ok(())
+    }
+    fn remove_branch(&mut self, node: &H256) -> Result<(), Error> {
+        self.branches_map.remove(node);
+        Ok(())
+    }
+    fn remove_leaf(&mut self, leaf_hash: &H256) -> Result<(), Error> {
+        if let Some(key) = self.leaf_keys.remove(leaf_hash) {
+            self.leaves.remove(&key);
+        }
+        Ok(())
+    }
+
+    fn sorted_leaves<'a>(&'a self) -> impl Iterator<Item = (K, &'a V)>
+    where
+        V: 'a,
+    {
+        self.leaves
+            .values()
+            .map(|(_, leaf)| (leaf.key, &leaf.value))
+    }
+
+    fn size(&self) -> usize {
+        self.leaves.len()
+    }
+
+    fn branch_count(&self) -> usize {
+        self.branches_map.len()
+    }
+}
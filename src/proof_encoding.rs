@@ -0,0 +1,303 @@
+//! Versioned binary encoding of `MerkleProof` and `CompiledMerkleProof`.
+//!
+//! Both start with the version byte `COMPILED_PROOF_VERSION` and carry
+//! counts and heights as LEB128 varints, like compiled proofs in the current
+//! format. A merkle proof is laid out as
+//!
+//! ```txt
+//! version: u8
+//! | leaf count | leaf count * (path length | path length * height)
+//! | sibling count | sibling count * (height | sibling: H256)
+//! ```
+//!
+//! and a compiled proof is its program in the current format
+//!
+//! ```txt
+//! version: u8 | instructions: (LEAF | PROOF height sibling: H256 | MERGE height)*
+//! ```
+//!
+//! The borsh and serde encodings hold these bytes as a byte string.
+//! Decoding a merkle proof takes at most `MAX_PROOF_LEAVES` leaves and
+//! heights up to `MAX_PROOF_HEIGHT`, the height of a tree of 8191 byte keys.
+
+use crate::{
+    error::{Error, Result},
+    merkle_proof::{
+        put_varint, take_varint, CompiledMerkleProof, MerkleProof, COMPILED_PROOF_VERSION,
+    },
+    string::String,
+    vec,
+    vec::Vec,
+    H256,
+};
+use core::convert::TryInto;
+
+/// Most leaves a decoded merkle proof may prove
+pub const MAX_PROOF_LEAVES: usize = 1 << 20;
+/// Highest height a decoded merkle proof may hold
+pub const MAX_PROOF_HEIGHT: usize = u16::MAX as usize;
+
+fn invalid(reason: &str) -> Error {
+    Error::InvalidEncoding(String::from(reason))
+}
+
+/// Reads the encoded bytes front to back
+struct Decoder<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    /// Check the version byte
+    fn new(bytes: &'a [u8]) -> Result<Self> {
+        let mut decoder = Decoder { bytes };
+        if decoder.read(1)?[0] != COMPILED_PROOF_VERSION {
+            return Err(invalid("unsupported version"));
+        }
+        Ok(decoder)
+    }
+
+    fn read(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.bytes.len() < len {
+            return Err(invalid("unexpected end of input"));
+        }
+        let (read, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(read)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    fn varint(&mut self) -> Result<usize> {
+        take_varint(&mut self.bytes).ok_or_else(|| invalid("malformed varint"))
+    }
+
+    /// Read a length, and check that the input holds that many items of at
+    /// least `item_size` bytes, so that a corrupted length can not trigger a
+    /// huge allocation
+    fn len(&mut self, item_size: usize) -> Result<usize> {
+        let len = self.varint()?;
+        if len.saturating_mul(item_size) > self.bytes.len() {
+            return Err(invalid("length exceeds the input"));
+        }
+        Ok(len)
+    }
+
+    fn height(&mut self) -> Result<usize> {
+        let height = self.varint()?;
+        if height > MAX_PROOF_HEIGHT {
+            return Err(invalid("height too large"));
+        }
+        Ok(height)
+    }
+
+    fn hash(&mut self) -> Result<H256> {
+        let bytes: [u8; 32] = self.read(32)?.try_into().expect("32 bytes");
+        Ok(bytes.into())
+    }
+}
+
+impl MerkleProof {
+    /// Encode the proof, see the `proof_encoding` module for the layout
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = vec![COMPILED_PROOF_VERSION];
+        put_varint(&mut out, self.leaves_path().len());
+        for path in self.leaves_path() {
+            put_varint(&mut out, path.len());
+            for height in path {
+                put_varint(&mut out, *height);
+            }
+        }
+        put_varint(&mut out, self.proof().len());
+        for (node, height) in self.proof() {
+            put_varint(&mut out, *height);
+            out.extend_from_slice(node.as_slice());
+        }
+        out
+    }
+
+    /// Decode a proof encoded by `to_bytes`
+    ///
+    /// Returns InvalidEncoding error on an unknown version, truncated or
+    /// trailing bytes, malformed varints, lengths that exceed the input,
+    /// heights out of order in a path or above `MAX_PROOF_HEIGHT`, more
+    /// than `MAX_PROOF_LEAVES` leaves or more siblings than the paths
+    /// account for.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let mut decoder = Decoder::new(bytes)?;
+        let leaves_count = decoder.len(1)?;
+        if leaves_count > MAX_PROOF_LEAVES {
+            return Err(invalid("too many leaves"));
+        }
+        let mut leaves_path = Vec::with_capacity(leaves_count);
+        let mut path_heights = 0usize;
+        for _ in 0..leaves_count {
+            let path_len = decoder.len(1)?;
+            let mut path = Vec::with_capacity(path_len);
+            for _ in 0..path_len {
+                let height = decoder.height()?;
+                if path.last().is_some_and(|last| *last >= height) {
+                    return Err(invalid("path heights out of order"));
+                }
+                path.push(height);
+            }
+            path_heights += path.len();
+            leaves_path.push(path);
+        }
+        let proof_len = decoder.len(1 + 32)?;
+        if proof_len > path_heights {
+            return Err(invalid("more siblings than path heights"));
+        }
+        let mut proof = Vec::with_capacity(proof_len);
+        for _ in 0..proof_len {
+            let height = decoder.height()?;
+            proof.push((decoder.hash()?, height));
+        }
+        if !decoder.is_empty() {
+            return Err(invalid("trailing bytes"));
+        }
+        Ok(MerkleProof::new(leaves_path, proof))
+    }
+}
+
+impl CompiledMerkleProof {
+    /// Encode the proof, see the `proof_encoding` module for the layout
+    ///
+    /// Accepts programs in the current and the legacy format. Returns
+    /// InvalidCode error on an unknown instruction, and CorruptedProof error
+    /// when the program is truncated.
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut program = Vec::with_capacity(self.0.len());
+        for instruction in self.instructions() {
+            instruction?.write(&mut program, false);
+        }
+        Ok(CompiledMerkleProof::versioned(program).0)
+    }
+
+    /// Decode a proof encoded by `to_bytes`
+    ///
+    /// Returns InvalidEncoding error on an unknown version, InvalidCode
+    /// error on an unknown instruction, and CorruptedProof error when the
+    /// program is truncated.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.first() != Some(&COMPILED_PROOF_VERSION) {
+            return Err(invalid("unsupported version"));
+        }
+        let proof = CompiledMerkleProof(bytes.to_vec());
+        for instruction in proof.instructions() {
+            instruction?;
+        }
+        Ok(proof)
+    }
+}
+
+#[cfg(feature = "borsh")]
+mod borsh_impls {
+    use super::*;
+    use borsh::io::{self, ErrorKind, Read, Write};
+    use borsh::{BorshDeserialize, BorshSerialize};
+
+    fn io_error(err: Error) -> io::Error {
+        io::Error::new(
+            ErrorKind::InvalidData,
+            crate::string::ToString::to_string(&err),
+        )
+    }
+
+    impl BorshSerialize for MerkleProof {
+        fn serialize<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+            BorshSerialize::serialize(&self.to_bytes(), writer)
+        }
+    }
+
+    impl BorshDeserialize for MerkleProof {
+        fn deserialize_reader<R: Read>(reader: &mut R) -> io::Result<Self> {
+            let bytes: Vec<u8> = BorshDeserialize::deserialize_reader(reader)?;
+            MerkleProof::from_bytes(&bytes).map_err(io_error)
+        }
+    }
+
+    impl BorshSerialize for CompiledMerkleProof {
+        fn serialize<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+            BorshSerialize::serialize(&self.to_bytes().map_err(io_error)?, writer)
+        }
+    }
+
+    impl BorshDeserialize for CompiledMerkleProof {
+        fn deserialize_reader<R: Read>(reader: &mut R) -> io::Result<Self> {
+            let bytes: Vec<u8> = BorshDeserialize::deserialize_reader(reader)?;
+            CompiledMerkleProof::from_bytes(&bytes).map_err(io_error)
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+mod serde_impls {
+    use super::*;
+    use core::fmt;
+    use serde::de::{self, Deserializer, SeqAccess, Visitor};
+    use serde::ser::{self, Serializer};
+    use serde::{Deserialize, Serialize};
+
+    /// Takes the encoded bytes as a byte string, or as a sequence of bytes
+    /// for formats without byte strings
+    struct BytesVisitor;
+
+    impl<'de> Visitor<'de> for BytesVisitor {
+        type Value = Vec<u8>;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("an encoded proof")
+        }
+
+        fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> core::result::Result<Vec<u8>, E> {
+            Ok(bytes.to_vec())
+        }
+
+        fn visit_byte_buf<E: de::Error>(self, bytes: Vec<u8>) -> core::result::Result<Vec<u8>, E> {
+            Ok(bytes)
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(
+            self,
+            mut seq: A,
+        ) -> core::result::Result<Vec<u8>, A::Error> {
+            let mut bytes = Vec::new();
+            while let Some(byte) = seq.next_element()? {
+                bytes.push(byte);
+            }
+            Ok(bytes)
+        }
+    }
+
+    impl Serialize for MerkleProof {
+        fn serialize<S: Serializer>(&self, serializer: S) -> core::result::Result<S::Ok, S::Error> {
+            serializer.serialize_bytes(&self.to_bytes())
+        }
+    }
+
+    impl<'de> Deserialize<'de> for MerkleProof {
+        fn deserialize<D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> core::result::Result<Self, D::Error> {
+            let bytes = deserializer.deserialize_bytes(BytesVisitor)?;
+            MerkleProof::from_bytes(&bytes).map_err(de::Error::custom)
+        }
+    }
+
+    impl Serialize for CompiledMerkleProof {
+        fn serialize<S: Serializer>(&self, serializer: S) -> core::result::Result<S::Ok, S::Error> {
+            serializer.serialize_bytes(&self.to_bytes().map_err(ser::Error::custom)?)
+        }
+    }
+
+    impl<'de> Deserialize<'de> for CompiledMerkleProof {
+        fn deserialize<D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> core::result::Result<Self, D::Error> {
+            let bytes = deserializer.deserialize_bytes(BytesVisitor)?;
+            CompiledMerkleProof::from_bytes(&bytes).map_err(de::Error::custom)
+        }
+    }
+}
@@ -1,8 +1,12 @@
-use ics23::{ExistenceProof, HashOp, InnerOp, InnerSpec, LeafOp, LengthOp, ProofSpec};
+use ics23::commitment_proof::Proof;
+use ics23::{
+    CommitmentProof, ExistenceProof, HashOp, HostFunctionsProvider, InnerOp, InnerSpec, LeafOp,
+    LengthOp, ProofSpec,
+};
 
 use crate::collections::VecDeque;
 use crate::error::{Error, Result};
-use crate::{Key, MerkleProof, H256, TREE_HEIGHT, traits::Value};
+use crate::{Key, MerkleProof, H256, traits::Value, vec, vec::Vec};
 
 pub fn convert<K, V, const N: usize>(
     merkle_proof: MerkleProof,
@@ -15,36 +19,33 @@ where
     V: Value,
 {
     let (leaves_path, proof) = merkle_proof.take();
-    let mut merge_heights: VecDeque<_> = leaves_path
-        .get(0)
-        .expect("The heights should exist")
-        .clone()
-        .into();
+    let mut merge_heights: VecDeque<_> = match leaves_path.as_slice() {
+        [heights] => heights.clone().into(),
+        _ => return Err(Error::CorruptedProof),
+    };
     let mut proof: VecDeque<_> = proof.into();
     let mut cur_key = **key;
     let mut height = 0;
     let mut path = Vec::new();
     while !proof.is_empty() {
-        if height == TREE_HEIGHT {
-            if !proof.is_empty() {
-                return Err(Error::CorruptedProof);
-            }
-            break;
+        if height == 8 * N {
+            return Err(Error::CorruptedProof);
         }
 
         // check the height is valid
-        let merge_height = merge_heights.front().map(|h| *h as usize).unwrap_or(height);
-        if height != merge_height {
+        let merge_height = merge_heights.front().copied().unwrap_or(height);
+        if merge_height < height || merge_height >= 8 * N {
+            return Err(Error::CorruptedProof);
+        } else if height != merge_height {
             // skip the heights
             height = merge_height;
             continue;
         }
 
         // get a proof
-        let (sibling, sibling_height) = proof.pop_front().expect("no proof");
-        if height < sibling_height as usize {
-            // skip heights
-            height = sibling_height as usize;
+        let (sibling, sibling_height) = proof.pop_front().ok_or(Error::CorruptedProof)?;
+        if sibling_height != height {
+            return Err(Error::CorruptedProof);
         }
         let inner_op = get_inner_op(hash_op, &sibling, cur_key.get_bit(height));
         path.push(inner_op);
@@ -62,11 +63,78 @@ where
     })
 }
 
+/// Verify an ICS 23 proof that no leaf lies in the subtree covering
+/// `prefix_key.parent_path(height)`, see
+/// `SparseMerkleTree::empty_subtree_commitment_proof`
+///
+/// The proof is a non-existence proof whose neighbours lie on either side
+/// of the subtree. ICS 23 checks that they are neighbours in the tree, and
+/// the keys they hold are mapped back with `Key::try_from_bytes` to check
+/// that they lie outside of the subtree.
+pub fn verify_empty_subtree<HF, K, const N: usize>(
+    proof: &CommitmentProof,
+    spec: &ProofSpec,
+    root: &[u8],
+    prefix_key: &K,
+    height: usize,
+) -> bool
+where
+    HF: HostFunctionsProvider,
+    K: Key<N>,
+{
+    let non_existence = match &proof.proof {
+        Some(Proof::Nonexist(non_existence)) => non_existence,
+        _ => return false,
+    };
+    let prefix = prefix_key.parent_path(height);
+    let outside = |proof: &Option<ExistenceProof>, before: bool| match proof {
+        Some(proof) => match K::try_from_bytes(&proof.key) {
+            Ok(key) if before => key.parent_path(height) < prefix,
+            Ok(key) => key.parent_path(height) > prefix,
+            Err(_) => false,
+        },
+        None => true,
+    };
+    if !outside(&non_existence.left, true) || !outside(&non_existence.right, false) {
+        return false;
+    }
+    // the tree holds no leaf with a zero value
+    let zero = |proof: &Option<ExistenceProof>| {
+        proof
+            .iter()
+            .any(|proof| proof.value.iter().all(|byte| *byte == 0))
+    };
+    if zero(&non_existence.left) || zero(&non_existence.right) {
+        return false;
+    }
+    // ICS 23 only checks neighbours through a key between them
+    let probe = match &non_existence.left {
+        Some(left) => {
+            let mut probe = left.key.clone();
+            probe.push(0);
+            probe
+        }
+        None => Vec::new(),
+    };
+    if let Some(right) = &non_existence.right {
+        if probe >= right.key {
+            return false;
+        }
+    }
+    ics23::verify_non_membership::<HF>(proof, spec, &root.to_vec(), &probe)
+}
+
+/// Proof spec of a tree with 32-byte keys, see `get_spec_for`
 pub fn get_spec(hash_op: HashOp) -> ProofSpec {
+    get_spec_for::<32>(hash_op)
+}
+
+/// Proof spec of a tree with `N`-byte keys
+pub fn get_spec_for<const N: usize>(hash_op: HashOp) -> ProofSpec {
     ProofSpec {
         leaf_spec: Some(get_leaf_op(hash_op)),
         inner_spec: Some(get_inner_spec(hash_op)),
-        max_depth: TREE_HEIGHT as i32,
+        max_depth: (8 * N) as i32,
         min_depth: 0,
         prehash_key_before_comparison: false,
     }
@@ -0,0 +1,468 @@
+//! A tree shared between one writer and many concurrent readers.
+//!
+//! Readers take a `Snapshot`, which keeps answering queries for the root it
+//! was taken at while the writer moves on. To make that possible, nodes the
+//! writer removes from the store are kept aside until no snapshot can reach
+//! them anymore.
+
+use crate::{
+    borrow::Cow,
+    collections::BTreeMap,
+    default_store::Map,
+    error::{Error, Result},
+    merkle_proof::{
+        CompiledMerkleProof, EmptySubtreeProof, MerkleProof, RangeProof, SubtreeProof,
+    },
+    string::ToString,
+    traits::{Hasher, MaybeSync, Store, Value},
+    tree::{resolve, BranchNode, Diff, LeafNode, SparseMerkleTree},
+    vec::Vec,
+    Key, H256,
+};
+use ics23::CommitmentProof;
+use std::sync::{Arc, Mutex, RwLock};
+
+/// Wraps the store of a `SharedSmt`, moving removed nodes to a graveyard
+/// tagged with the epoch they were last reachable in
+struct Retaining<K, V, S, const N: usize>
+where
+    K: Key<N>,
+{
+    inner: S,
+    epoch: u64,
+    branches: Map<H256, (u64, BranchNode<K, N>)>,
+    leaves: Map<H256, (u64, LeafNode<K, V, N>)>,
+}
+
+impl<K, V, S, const N: usize> Retaining<K, V, S, N>
+where
+    K: Key<N>,
+{
+    fn new(inner: S) -> Self {
+        Retaining {
+            inner,
+            epoch: 0,
+            branches: Map::default(),
+            leaves: Map::default(),
+        }
+    }
+
+    /// Drop the removed nodes last reachable before `epoch`
+    fn prune(&mut self, epoch: u64) {
+        self.branches
+            .retain(|_, (removed_at, _)| *removed_at >= epoch);
+        self.leaves
+            .retain(|_, (removed_at, _)| *removed_at >= epoch);
+    }
+}
+
+impl<K, V, S, const N: usize> Default for Retaining<K, V, S, N>
+where
+    K: Key<N>,
+    S: Default,
+{
+    fn default() -> Self {
+        Self::new(S::default())
+    }
+}
+
+impl<K, V, S, const N: usize> Store<K, V, N> for Retaining<K, V, S, N>
+where
+    K: Key<N>,
+    V: Clone,
+    S: Store<K, V, N>,
+{
+    fn get_branch(&self, node: &H256) -> Result<Option<BranchNode<K, N>>> {
+        match self.inner.get_branch(node)? {
+            Some(branch) => Ok(Some(branch)),
+            None => Ok(self.branches.get(node).map(|(_, branch)| branch.clone())),
+        }
+    }
+    fn get_leaf(&self, leaf_key: &H256) -> Result<Option<LeafNode<K, V, N>>> {
+        match self.inner.get_leaf(leaf_key)? {
+            Some(leaf) => Ok(Some(leaf)),
+            None => Ok(self.leaves.get(leaf_key).map(|(_, leaf)| leaf.clone())),
+        }
+    }
+    fn get_branch_ref(&self, node: &H256) -> Result<Option<Cow<'_, BranchNode<K, N>>>> {
+        match self.inner.get_branch_ref(node)? {
+            Some(branch) => Ok(Some(branch)),
+            None => Ok(self
+                .branches
+                .get(node)
+                .map(|(_, branch)| Cow::Borrowed(branch))),
+        }
+    }
+    fn get_leaf_ref(&self, leaf_key: &H256) -> Result<Option<Cow<'_, LeafNode<K, V, N>>>> {
+        match self.inner.get_leaf_ref(leaf_key)? {
+            Some(leaf) => Ok(Some(leaf)),
+            None => Ok(self
+                .leaves
+                .get(leaf_key)
+                .map(|(_, leaf)| Cow::Borrowed(leaf))),
+        }
+    }
+    fn insert_branch(&mut self, node: H256, branch: BranchNode<K, N>) -> Result<()> {
+        self.branches.remove(&node);
+        self.inner.insert_branch(node, branch)
+    }
+    fn insert_leaf(&mut self, leaf_key: H256, leaf: LeafNode<K, V, N>) -> Result<()> {
+        self.leaves.remove(&leaf_key);
+        self.inner.insert_leaf(leaf_key, leaf)
+    }
+    fn remove_branch(&mut self, node: &H256) -> Result<()> {
+        if let Some(branch) = self.inner.get_branch(node)? {
+            self.inner.remove_branch(node)?;
+            self.branches.insert(*node, (self.epoch, branch));
+        }
+        Ok(())
+    }
+    fn remove_leaf(&mut self, leaf_key: &H256) -> Result<()> {
+        if let Some(leaf) = self.inner.get_leaf(leaf_key)? {
+            self.inner.remove_leaf(leaf_key)?;
+            self.leaves.insert(*leaf_key, (self.epoch, leaf));
+        }
+        Ok(())
+    }
+
+    fn sorted_leaves<'a>(&'a self) -> impl Iterator<Item = (K, &'a V)>
+    where
+        V: 'a,
+    {
+        self.inner.sorted_leaves()
+    }
+
+    fn size(&self) -> usize {
+        self.inner.size()
+    }
+
+    fn branch_count(&self) -> usize {
+        self.inner.branch_count()
+    }
+}
+
+type Tree<H, K, V, S, const N: usize> = SparseMerkleTree<H, K, V, Retaining<K, V, S, N>, N>;
+
+struct Latest<H, K, V, S, const N: usize>
+where
+    H: Hasher + Default,
+    K: Key<N>,
+    V: Value,
+    S: Store<K, V, N>,
+{
+    tree: Tree<H, K, V, S, N>,
+    /// Number of updates published so far
+    epoch: u64,
+}
+
+struct Shared<H, K, V, S, const N: usize>
+where
+    H: Hasher + Default,
+    K: Key<N>,
+    V: Value,
+    S: Store<K, V, N>,
+{
+    latest: RwLock<Latest<H, K, V, S, N>>,
+    /// epoch -> number of snapshots pinned to it
+    pins: Mutex<BTreeMap<u64, usize>>,
+}
+
+fn poisoned<T>(_: T) -> Error {
+    Error::Store("shared tree lock poisoned".to_string())
+}
+
+/// A `SparseMerkleTree` with a single writer and concurrent readers
+///
+/// Clones are handles to the same tree. Updates take the write lock only
+/// for their own duration and publish the new root when they succeed.
+/// Readers query a `Snapshot`, which is pinned to the root it was taken at.
+pub struct SharedSmt<H, K, V, S, const N: usize>
+where
+    H: Hasher + Default,
+    K: Key<N>,
+    V: Value,
+    S: Store<K, V, N>,
+{
+    shared: Arc<Shared<H, K, V, S, N>>,
+}
+
+impl<H, K, V, S, const N: usize> Clone for SharedSmt<H, K, V, S, N>
+where
+    H: Hasher + Default,
+    K: Key<N>,
+    V: Value,
+    S: Store<K, V, N>,
+{
+    fn clone(&self) -> Self {
+        SharedSmt {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+}
+
+impl<H, K, V, S, const N: usize> Default for SharedSmt<H, K, V, S, N>
+where
+    H: Hasher + Default,
+    K: Key<N> + MaybeSync,
+    V: Value + MaybeSync,
+    S: Store<K, V, N>,
+{
+    fn default() -> Self {
+        Self::new(SparseMerkleTree::default())
+    }
+}
+
+impl<H, K, V, S, const N: usize> SharedSmt<H, K, V, S, N>
+where
+    H: Hasher + Default,
+    K: Key<N> + MaybeSync,
+    V: Value + MaybeSync,
+    S: Store<K, V, N>,
+{
+    /// Share `tree` from now on
+    pub fn new(tree: SparseMerkleTree<H, K, V, S, N>) -> Self {
+        let root = *tree.root();
+        let tree = SparseMerkleTree::new(root, Retaining::new(tree.take_store()));
+        SharedSmt {
+            shared: Arc::new(Shared {
+                latest: RwLock::new(Latest { tree, epoch: 0 }),
+                pins: Mutex::new(BTreeMap::new()),
+            }),
+        }
+    }
+
+    /// The latest published root
+    pub fn root(&self) -> Result<H256> {
+        let latest = self.shared.latest.read().map_err(poisoned)?;
+        Ok(*latest.tree.root())
+    }
+
+    /// Pin the latest published root for reading
+    pub fn snapshot(&self) -> Result<Snapshot<H, K, V, S, N>> {
+        // holding the read lock keeps the writer from pruning before the pin
+        let latest = self.shared.latest.read().map_err(poisoned)?;
+        let epoch = latest.epoch;
+        *self
+            .shared
+            .pins
+            .lock()
+            .map_err(poisoned)?
+            .entry(epoch)
+            .or_insert(0) += 1;
+        Ok(Snapshot {
+            shared: Arc::clone(&self.shared),
+            root: *latest.tree.root(),
+            epoch,
+        })
+    }
+
+    /// Update a leaf and publish the new root, see `SparseMerkleTree::update`
+    pub fn update(&self, key: K, value: V) -> Result<H256> {
+        self.write(|tree| tree.update(key, value).copied())
+    }
+
+    /// Update several leaves and publish the new root once, see
+    /// `SparseMerkleTree::update_all`
+    pub fn update_all(&self, leaves: Vec<(K, V)>) -> Result<H256> {
+        self.write(|tree| tree.update_all(leaves).copied())
+    }
+
+    /// Number of removed nodes kept for snapshots
+    #[cfg(test)]
+    pub(crate) fn retained(&self) -> usize {
+        let latest = self.shared.latest.read().unwrap();
+        let store = latest.tree.store();
+        store.branches.len() + store.leaves.len()
+    }
+
+    /// Run `update` under the write lock, then drop the removed nodes no
+    /// snapshot can reach
+    fn write<F>(&self, update: F) -> Result<H256>
+    where
+        F: FnOnce(&mut Tree<H, K, V, S, N>) -> Result<H256>,
+    {
+        let mut latest = self.shared.latest.write().map_err(poisoned)?;
+        let epoch = latest.epoch;
+        latest.tree.store_mut().epoch = epoch;
+        // a failed update keeps the old root, which is tagged `epoch` too
+        let root = update(&mut latest.tree)?;
+        latest.epoch += 1;
+        let oldest_pin = self
+            .shared
+            .pins
+            .lock()
+            .map_err(poisoned)?
+            .keys()
+            .next()
+            .copied()
+            .unwrap_or(latest.epoch);
+        latest.tree.store_mut().prune(oldest_pin);
+        Ok(root)
+    }
+}
+
+/// A read-only view of a `SharedSmt` at the root it was taken at
+///
+/// Queries only take the read lock for their own duration. The nodes of the
+/// root stay available until the snapshot and its clones are dropped.
+pub struct Snapshot<H, K, V, S, const N: usize>
+where
+    H: Hasher + Default,
+    K: Key<N>,
+    V: Value,
+    S: Store<K, V, N>,
+{
+    shared: Arc<Shared<H, K, V, S, N>>,
+    root: H256,
+    epoch: u64,
+}
+
+impl<H, K, V, S, const N: usize> Snapshot<H, K, V, S, N>
+where
+    H: Hasher + Default,
+    K: Key<N>,
+    V: Value,
+    S: Store<K, V, N>,
+{
+    /// Merkle root the snapshot is pinned to
+    pub fn root(&self) -> &H256 {
+        &self.root
+    }
+
+    /// Get value of a leaf
+    /// return zero value if leaf not exists
+    pub fn get(&self, key: &K) -> Result<V> {
+        let latest = self.shared.latest.read().map_err(poisoned)?;
+        resolve(latest.tree.view_at(self.root).get(key))
+    }
+
+    /// Get the values of several leaves at once, in the order of `keys`
+    pub fn get_many(&self, keys: &[K]) -> Result<Vec<V>> {
+        let latest = self.shared.latest.read().map_err(poisoned)?;
+        resolve(latest.tree.view_at(self.root).get_many(keys))
+    }
+
+    /// Generate merkle proof
+    pub fn merkle_proof(&self, keys: Vec<K>) -> Result<MerkleProof> {
+        let latest = self.shared.latest.read().map_err(poisoned)?;
+        resolve(latest.tree.view_at(self.root).merkle_proof(keys))
+    }
+
+    /// Generate compiled merkle proof, without building the merkle proof
+    /// first
+    pub fn compiled_merkle_proof(&self, keys: Vec<K>) -> Result<CompiledMerkleProof> {
+        let latest = self.shared.latest.read().map_err(poisoned)?;
+        resolve(latest.tree.view_at(self.root).compiled_merkle_proof(keys))
+    }
+
+    /// Generate ICS 23 commitment proof for the existing key
+    pub fn membership_proof(&self, key: &K) -> Result<CommitmentProof> {
+        let latest = self.shared.latest.read().map_err(poisoned)?;
+        resolve(latest.tree.view_at(self.root).membership_proof(key))
+    }
+
+    /// Generate ICS 23 commitment proof for the non-existing key
+    pub fn non_membership_proof(&self, key: &K) -> Result<CommitmentProof> {
+        let latest = self.shared.latest.read().map_err(poisoned)?;
+        resolve(latest.tree.view_at(self.root).non_membership_proof(key))
+    }
+
+    /// Get the root of the subtree covering `prefix_key.parent_path(height)`
+    /// return zero if the subtree holds no leaves
+    pub fn subtree_root(&self, prefix_key: &K, height: usize) -> Result<H256> {
+        let latest = self.shared.latest.read().map_err(poisoned)?;
+        resolve(latest.tree.view_at(self.root).subtree(prefix_key, height))
+    }
+
+    /// Generate proof of the root of the subtree covering
+    /// `prefix_key.parent_path(height)`
+    pub fn subtree_proof(&self, prefix_key: &K, height: usize) -> Result<SubtreeProof<K, V>> {
+        let latest = self.shared.latest.read().map_err(poisoned)?;
+        resolve(latest.tree.view_at(self.root).subtree_proof(prefix_key, height))
+    }
+
+    /// Generate proof that no leaf lies in the subtree covering
+    /// `prefix_key.parent_path(height)`
+    pub fn empty_subtree_proof(
+        &self,
+        prefix_key: &K,
+        height: usize,
+    ) -> Result<EmptySubtreeProof<K, V>> {
+        let latest = self.shared.latest.read().map_err(poisoned)?;
+        resolve(latest.tree.view_at(self.root).empty_subtree_proof(prefix_key, height))
+    }
+
+    /// Generate ICS 23 commitment proof that no leaf lies in the subtree
+    /// covering `prefix_key.parent_path(height)`
+    pub fn empty_subtree_commitment_proof(
+        &self,
+        prefix_key: &K,
+        height: usize,
+    ) -> Result<CommitmentProof> {
+        let latest = self.shared.latest.read().map_err(poisoned)?;
+        let view = latest.tree.view_at(self.root);
+        resolve(view.empty_subtree_commitment_proof(prefix_key, height))
+    }
+
+    /// Generate proof of the leaves with keys in `start..end`
+    ///
+    /// Returns InvalidRange error if `start` is greater than `end`.
+    pub fn range_proof(&self, start: &K, end: &K) -> Result<RangeProof<K, V>> {
+        let latest = self.shared.latest.read().map_err(poisoned)?;
+        resolve(latest.tree.view_at(self.root).range_proof(start, end))
+    }
+
+    /// List the leaves that differ between this snapshot and `other` in
+    /// key order, both taken from the same `SharedSmt`
+    pub fn diff(&self, other: &Self) -> Result<Vec<Diff<K, V>>> {
+        if !Arc::ptr_eq(&self.shared, &other.shared) {
+            return Err(Error::Store("snapshots of different trees".to_string()));
+        }
+        let latest = self.shared.latest.read().map_err(poisoned)?;
+        resolve(latest.tree.view_at(self.root).diff(other.root))
+    }
+}
+
+impl<H, K, V, S, const N: usize> Clone for Snapshot<H, K, V, S, N>
+where
+    H: Hasher + Default,
+    K: Key<N>,
+    V: Value,
+    S: Store<K, V, N>,
+{
+    fn clone(&self) -> Self {
+        let mut pins = self
+            .shared
+            .pins
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        *pins.entry(self.epoch).or_insert(0) += 1;
+        Snapshot {
+            shared: Arc::clone(&self.shared),
+            root: self.root,
+            epoch: self.epoch,
+        }
+    }
+}
+
+impl<H, K, V, S, const N: usize> Drop for Snapshot<H, K, V, S, N>
+where
+    H: Hasher + Default,
+    K: Key<N>,
+    V: Value,
+    S: Store<K, V, N>,
+{
+    fn drop(&mut self) {
+        let mut pins = self
+            .shared
+            .pins
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(count) = pins.get_mut(&self.epoch) {
+            *count -= 1;
+            if *count == 0 {
+                pins.remove(&self.epoch);
+            }
+        }
+    }
+}
@@ -0,0 +1,315 @@
+//! Chunked snapshots for state sync.
+//!
+//! `SparseMerkleTree::snapshot_chunks` splits the sorted leaves into runs of
+//! consecutive leaves. Each chunk carries a proof for its leaves and the
+//! leaves just outside of it, which shows against a trusted root that no
+//! leaf of the tree was left out of the chunk. `SnapshotImporter` takes the
+//! chunks in any order, places each next to the chunks around it and
+//! assembles the store.
+
+use crate::{
+    collections::BTreeMap,
+    error::{Error, Result},
+    merkle_proof::MerkleProof,
+    traits::{Hasher, MaybeSync, Store, Value},
+    vec,
+    vec::Vec,
+    InternalKey, Key, SparseMerkleTree, H256,
+};
+
+/// A run of consecutive leaves and a proof of them against the root
+#[derive(Debug, Clone)]
+pub struct SnapshotChunk<K, V> {
+    /// Position of the chunk in the snapshot
+    pub index: usize,
+    /// Leaves of the chunk in key order
+    pub leaves: Vec<(K, V)>,
+    /// Last leaf of the previous chunk, none for the first chunk
+    pub left: Option<(K, V)>,
+    /// First leaf of the next chunk, none for the last chunk
+    pub right: Option<(K, V)>,
+    /// Proof of `left`, `leaves` and `right`
+    pub proof: MerkleProof,
+}
+
+impl<K, V> SnapshotChunk<K, V>
+where
+    V: Value,
+{
+    /// Verify the chunk against `root`
+    ///
+    /// Checks that the leaves are in the tree and that no leaf of the tree
+    /// lies between them and the neighbours, or before the first chunk and
+    /// after the last one. Returns CorruptedProof error for a leaf with a
+    /// zero value, which the tree never holds.
+    pub fn verify<H: Hasher + Default, const N: usize>(&self, root: &H256) -> Result<bool>
+    where
+        K: Key<N>,
+    {
+        if self.leaves.is_empty() {
+            return Ok(false);
+        }
+        let leaves: Vec<(K, V)> = self
+            .left
+            .iter()
+            .chain(self.leaves.iter())
+            .chain(self.right.iter())
+            .cloned()
+            .collect();
+        if leaves.windows(2).any(|pair| *pair[0].0 >= *pair[1].0) {
+            return Ok(false);
+        }
+        match self.proof.clone().compute_contiguous_root::<H, K, V, N>(
+            leaves,
+            self.left.is_some(),
+            self.right.is_some(),
+        ) {
+            Ok(computed) => Ok(&computed == root),
+            Err(Error::NonContiguousLeaves) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+impl<H, K, V, S, const N: usize> SparseMerkleTree<H, K, V, S, N>
+where
+    H: Hasher + Default,
+    K: Key<N>,
+    V: Value + core::cmp::PartialEq,
+    S: Store<K, V, N>,
+{
+    /// Split the leaves into chunks of `chunk_size` leaves in key order
+    ///
+    /// The chunks are built one at a time as the iterator advances, so a
+    /// server can stream them without holding the snapshot in memory. An
+    /// empty tree has no chunks. Returns ZeroChunkSize error if
+    /// `chunk_size` is 0.
+    pub fn snapshot_chunks(
+        &self,
+        chunk_size: usize,
+    ) -> Result<impl Iterator<Item = Result<SnapshotChunk<K, V>>> + '_> {
+        if chunk_size == 0 {
+            return Err(Error::ZeroChunkSize);
+        }
+        let mut leaves = self
+            .store()
+            .sorted_leaves()
+            .map(|(k, v)| (k, v.clone()))
+            .peekable();
+        let mut index = 0;
+        let mut left = None;
+        Ok(core::iter::from_fn(move || {
+            leaves.peek()?;
+            let chunk: Vec<(K, V)> = leaves.by_ref().take(chunk_size).collect();
+            let right = leaves.peek().cloned();
+            let keys = left
+                .iter()
+                .chain(chunk.iter())
+                .chain(right.iter())
+                .map(|(k, _v)| *k)
+                .collect();
+            let proof = match self.merkle_proof(keys) {
+                Ok(proof) => proof,
+                Err(err) => return Some(Err(err)),
+            };
+            let chunk = SnapshotChunk {
+                index,
+                leaves: chunk,
+                left: left.take(),
+                right,
+                proof,
+            };
+            left = chunk.leaves.last().cloned();
+            index += 1;
+            Some(Ok(chunk))
+        }))
+    }
+
+    /// Number of chunks `snapshot_chunks` splits the leaves into
+    ///
+    /// Returns ZeroChunkSize error if `chunk_size` is 0.
+    pub fn snapshot_chunk_count(&self, chunk_size: usize) -> Result<usize> {
+        if chunk_size == 0 {
+            return Err(Error::ZeroChunkSize);
+        }
+        Ok(self.store().size().div_ceil(chunk_size))
+    }
+}
+
+/// Keys at the edges of an imported chunk
+#[derive(PartialEq)]
+struct ChunkBounds<const N: usize> {
+    first: InternalKey<N>,
+    last: InternalKey<N>,
+    left: Option<InternalKey<N>>,
+    right: Option<InternalKey<N>>,
+}
+
+impl<const N: usize> ChunkBounds<N> {
+    fn of<K: Key<N>, V>(chunk: &SnapshotChunk<K, V>) -> Self {
+        ChunkBounds {
+            first: *chunk.leaves[0].0,
+            last: *chunk.leaves[chunk.leaves.len() - 1].0,
+            left: chunk.left.as_ref().map(|(k, _v)| **k),
+            right: chunk.right.as_ref().map(|(k, _v)| **k),
+        }
+    }
+
+    /// Check if the chunk comes right after `prev`
+    fn follows(&self, prev: &Self) -> bool {
+        prev.right == Some(self.first) && self.left == Some(prev.last)
+    }
+}
+
+/// Assembles a tree from verified snapshot chunks
+///
+/// The proof of a chunk does not cover its index. A chunk only takes its
+/// place once it is the first chunk, or lines up with a chunk next to it
+/// that took its place before; since every chunk but the last holds exactly
+/// `chunk_size` leaves, that fixes its leaves. Verified chunks that can not
+/// take their place yet wait in memory until a neighbour arrives.
+pub struct SnapshotImporter<H, K, V, S, const N: usize>
+where
+    H: Hasher + Default,
+    K: Key<N>,
+    V: Value,
+    S: Store<K, V, N>,
+{
+    root: H256,
+    chunk_count: usize,
+    chunk_size: usize,
+    received: BTreeMap<usize, ChunkBounds<N>>,
+    pending: BTreeMap<usize, Vec<SnapshotChunk<K, V>>>,
+    tree: SparseMerkleTree<H, K, V, S, N>,
+}
+
+impl<H, K, V, S, const N: usize> SnapshotImporter<H, K, V, S, N>
+where
+    H: Hasher + Default,
+    K: Key<N> + MaybeSync,
+    V: Value + core::cmp::PartialEq + MaybeSync,
+    S: Store<K, V, N>,
+{
+    /// Import a snapshot of `chunk_count` chunks of `chunk_size` leaves of
+    /// the tree under the trusted `root`
+    pub fn new(root: H256, chunk_count: usize, chunk_size: usize) -> Self {
+        SnapshotImporter {
+            root,
+            chunk_count,
+            chunk_size,
+            received: BTreeMap::new(),
+            pending: BTreeMap::new(),
+            tree: SparseMerkleTree::default(),
+        }
+    }
+
+    /// Verify a chunk and add its leaves to the store once it lines up with
+    /// the chunks next to it
+    ///
+    /// Returns InvalidChunk error when the chunk does not verify against the
+    /// root, holds the wrong number of leaves, does not line up with the
+    /// chunks next to it, or differs from the chunk imported at its index
+    /// before. The same chunk imported twice is ignored.
+    pub fn add_chunk(&mut self, chunk: SnapshotChunk<K, V>) -> Result<()> {
+        let index = chunk.index;
+        let size = chunk.leaves.len();
+        if index >= self.chunk_count
+            || chunk.left.is_none() != (index == 0)
+            || chunk.right.is_none() != (index + 1 == self.chunk_count)
+            || size > self.chunk_size
+            || (chunk.right.is_some() && size != self.chunk_size)
+            || !chunk.verify::<H, N>(&self.root)?
+        {
+            return Err(Error::InvalidChunk(index));
+        }
+        let bounds = ChunkBounds::of(&chunk);
+        if let Some(placed) = self.received.get(&index) {
+            if *placed != bounds {
+                return Err(Error::InvalidChunk(index));
+            }
+            return Ok(());
+        }
+        match self.lines_up(index, &bounds) {
+            Some(true) => {}
+            Some(false) => return Err(Error::InvalidChunk(index)),
+            None => {
+                let staged = self.pending.entry(index).or_default();
+                if !staged.iter().any(|other| ChunkBounds::of(other) == bounds) {
+                    staged.push(chunk);
+                }
+                return Ok(());
+            }
+        }
+        self.place(chunk, bounds)?;
+
+        // the chunks waiting next to the placed ones
+        let mut placed = vec![index];
+        while let Some(index) = placed.pop() {
+            let neighbours = [index.checked_sub(1), Some(index + 1)];
+            for neighbour in neighbours.iter().flatten().copied() {
+                let candidates = match self.pending.remove(&neighbour) {
+                    Some(candidates) => candidates,
+                    None => continue,
+                };
+                for candidate in candidates {
+                    let bounds = ChunkBounds::of(&candidate);
+                    if self.lines_up(neighbour, &bounds) == Some(true) {
+                        self.place(candidate, bounds)?;
+                        placed.push(neighbour);
+                        break;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Check if a chunk takes its place at `index`, none if no chunk next
+    /// to it took its place yet
+    fn lines_up(&self, index: usize, bounds: &ChunkBounds<N>) -> Option<bool> {
+        let prev = index.checked_sub(1).and_then(|i| self.received.get(&i));
+        let next = self.received.get(&(index + 1));
+        let after_prev = prev.map(|prev| bounds.follows(prev));
+        let before_next = next.map(|next| next.follows(bounds));
+        if after_prev == Some(false) || before_next == Some(false) {
+            Some(false)
+        } else if index == 0 || after_prev.is_some() || before_next.is_some() {
+            Some(true)
+        } else {
+            None
+        }
+    }
+
+    fn place(&mut self, chunk: SnapshotChunk<K, V>, bounds: ChunkBounds<N>) -> Result<()> {
+        self.tree.update_all(chunk.leaves)?;
+        self.received.insert(chunk.index, bounds);
+        Ok(())
+    }
+
+    /// Indexes of the chunks that did not take their place yet
+    pub fn missing_chunks(&self) -> Vec<usize> {
+        (0..self.chunk_count)
+            .filter(|index| !self.received.contains_key(index))
+            .collect()
+    }
+
+    /// Check if every chunk was imported
+    pub fn is_complete(&self) -> bool {
+        self.received.len() == self.chunk_count
+    }
+
+    /// Return the assembled tree once every chunk was imported
+    pub fn finish(self) -> Result<SparseMerkleTree<H, K, V, S, N>> {
+        let missing = self.chunk_count - self.received.len();
+        if missing > 0 {
+            return Err(Error::IncompleteSnapshot { missing });
+        }
+        if self.tree.root() != &self.root {
+            return Err(Error::RootMismatch {
+                expected: self.root,
+                actual: *self.tree.root(),
+            });
+        }
+        Ok(self.tree)
+    }
+}
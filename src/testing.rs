@@ -0,0 +1,229 @@
+//! Utilities for exercising the error paths of `SparseMerkleTree`.
+//!
+//! Enabled by the `testing` feature.
+
+use crate::{
+    borrow::Cow,
+    error::Error,
+    merkle_proof::{CompiledMerkleProof, MerkleProof},
+    sha256::Sha256Hasher,
+    string::String,
+    traits::Store,
+    tree::{BranchNode, LeafNode},
+    vec::Vec,
+    Hash, Key, H256,
+};
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+cfg_if::cfg_if! {
+    if #[cfg(feature = "std")] {
+        use std::boxed::Box;
+    } else {
+        use alloc::boxed::Box;
+    }
+}
+
+/// A fallible call made on a `Store`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StoreCall {
+    GetBranch(H256),
+    GetLeaf(H256),
+    InsertBranch(H256),
+    InsertLeaf(H256),
+    RemoveBranch(H256),
+    RemoveLeaf(H256),
+}
+
+impl StoreCall {
+    /// Whether the call only reads from the store
+    pub fn is_read(&self) -> bool {
+        matches!(self, StoreCall::GetBranch(_) | StoreCall::GetLeaf(_))
+    }
+}
+
+type Predicate = Box<dyn Fn(&StoreCall) -> bool + Send + Sync>;
+
+enum Fault {
+    Never,
+    Nth(usize),
+    When(Predicate),
+}
+
+/// A store wrapper that fails chosen calls with `Error::Store`
+///
+/// Calls are numbered from zero in the order they reach the store.
+/// `sorted_leaves` and `size` cannot fail and are not counted.
+pub struct FaultyStore<S> {
+    inner: S,
+    calls: AtomicUsize,
+    fault: Fault,
+}
+
+impl<S> FaultyStore<S> {
+    /// Wrap a store without injecting any fault
+    pub fn new(inner: S) -> Self {
+        FaultyStore {
+            inner,
+            calls: AtomicUsize::new(0),
+            fault: Fault::Never,
+        }
+    }
+
+    /// Fail the call numbered `n`, counting from the current number of calls
+    pub fn fail_nth(&mut self, n: usize) {
+        self.fault = Fault::Nth(self.calls() + n);
+    }
+
+    /// Fail every call matching `predicate`
+    pub fn fail_when<F>(&mut self, predicate: F)
+    where
+        F: Fn(&StoreCall) -> bool + Send + Sync + 'static,
+    {
+        self.fault = Fault::When(Box::new(predicate));
+    }
+
+    /// Stop injecting faults
+    pub fn clear_faults(&mut self) {
+        self.fault = Fault::Never;
+    }
+
+    /// Number of calls made so far
+    pub fn calls(&self) -> usize {
+        self.calls.load(Ordering::Relaxed)
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn inner_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    fn check(&self, call: StoreCall) -> Result<(), Error> {
+        let n = self.calls.fetch_add(1, Ordering::Relaxed);
+        let fail = match &self.fault {
+            Fault::Never => false,
+            Fault::Nth(target) => n == *target,
+            Fault::When(predicate) => predicate(&call),
+        };
+        if fail {
+            let mut msg = String::new();
+            let _ = write!(msg, "injected fault on call {}: {:?}", n, call);
+            return Err(Error::Store(msg));
+        }
+        Ok(())
+    }
+}
+
+impl<S: Default> Default for FaultyStore<S> {
+    fn default() -> Self {
+        Self::new(S::default())
+    }
+}
+
+impl<S: fmt::Debug> fmt::Debug for FaultyStore<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FaultyStore")
+            .field("inner", &self.inner)
+            .field("calls", &self.calls())
+            .finish()
+    }
+}
+
+impl<K, V, S, const N: usize> Store<K, V, N> for FaultyStore<S>
+where
+    K: Key<N>,
+    S: Store<K, V, N>,
+{
+    fn get_branch(&self, node: &H256) -> Result<Option<BranchNode<K, N>>, Error> {
+        self.check(StoreCall::GetBranch(*node))?;
+        self.inner.get_branch(node)
+    }
+    fn get_leaf(&self, leaf_key: &H256) -> Result<Option<LeafNode<K, V, N>>, Error> {
+        self.check(StoreCall::GetLeaf(*leaf_key))?;
+        self.inner.get_leaf(leaf_key)
+    }
+    fn get_branch_ref(&self, node: &H256) -> Result<Option<Cow<'_, BranchNode<K, N>>>, Error> {
+        self.check(StoreCall::GetBranch(*node))?;
+        self.inner.get_branch_ref(node)
+    }
+    fn get_leaf_ref(&self, leaf_key: &H256) -> Result<Option<Cow<'_, LeafNode<K, V, N>>>, Error>
+    where
+        V: Clone,
+    {
+        self.check(StoreCall::GetLeaf(*leaf_key))?;
+        self.inner.get_leaf_ref(leaf_key)
+    }
+    fn insert_branch(&mut self, node: H256, branch: BranchNode<K, N>) -> Result<(), Error> {
+        self.check(StoreCall::InsertBranch(node))?;
+        self.inner.insert_branch(node, branch)
+    }
+    fn insert_leaf(&mut self, leaf_key: H256, leaf: LeafNode<K, V, N>) -> Result<(), Error> {
+        self.check(StoreCall::InsertLeaf(leaf_key))?;
+        self.inner.insert_leaf(leaf_key, leaf)
+    }
+    fn remove_branch(&mut self, node: &H256) -> Result<(), Error> {
+        self.check(StoreCall::RemoveBranch(*node))?;
+        self.inner.remove_branch(node)
+    }
+    fn remove_leaf(&mut self, leaf_key: &H256) -> Result<(), Error> {
+        self.check(StoreCall::RemoveLeaf(*leaf_key))?;
+        self.inner.remove_leaf(leaf_key)
+    }
+    fn sorted_leaves<'a>(&'a self) -> impl Iterator<Item = (K, &'a V)>
+    where
+        V: 'a,
+    {
+        self.inner.sorted_leaves()
+    }
+    fn size(&self) -> usize {
+        self.inner.size()
+    }
+    fn branch_count(&self) -> usize {
+        self.inner.branch_count()
+    }
+}
+
+/// Run the proof verifications on untrusted bytes, as the fuzz targets do
+///
+/// The first byte gives how many of the following bytes to take as leaves,
+/// each byte `b` standing for the key and value `[b; 32]`. The remaining
+/// bytes are decoded as a `MerkleProof`, as an encoded `CompiledMerkleProof`
+/// and taken as a raw compiled program. Verification may fail on any of
+/// them, but must never panic.
+pub fn verify_untrusted_proof(data: &[u8]) {
+    let (count, data) = match data.split_first() {
+        Some((count, data)) => (usize::from(*count).min(data.len()), data),
+        None => return,
+    };
+    let (seeds, program) = data.split_at(count);
+    let leaves: Vec<(Hash, H256)> = seeds
+        .iter()
+        .map(|b| ([*b; 32].into(), [*b; 32].into()))
+        .collect();
+
+    if let Ok(proof) = MerkleProof::from_bytes(program) {
+        let _ = proof
+            .clone()
+            .compute_root::<Sha256Hasher, Hash, H256, 32>(leaves.clone());
+        let _ = proof
+            .clone()
+            .compute_contiguous_root::<Sha256Hasher, Hash, H256, 32>(leaves.clone(), true, false);
+        let _ = proof.compile_keys(leaves.iter().map(|(k, _v)| *k).collect());
+    }
+    if let Ok(compiled) = CompiledMerkleProof::from_bytes(program) {
+        let _ = compiled.compute_root::<Sha256Hasher, Hash, H256, 32>(leaves.clone());
+    }
+    let compiled = CompiledMerkleProof(program.to_vec());
+    let _ = compiled.to_bytes();
+    if let Ok(legacy) = compiled.to_legacy() {
+        let _ = legacy.compute_root::<Sha256Hasher, Hash, H256, 32>(leaves.clone());
+    }
+    let _ = compiled.compute_root::<Sha256Hasher, Hash, H256, 32>(leaves);
+}
@@ -0,0 +1,140 @@
+use super::padded_key::PaddedKey;
+use super::{leaves, new_smt};
+use crate::{
+    async_tree::AsyncSparseMerkleTree,
+    blake2b::Blake2bHasher,
+    default_store::DefaultStore,
+    error::Error,
+    traits::{AsyncStore, Store},
+    tree::{BranchNode, LeafNode},
+    H256,
+};
+use core::future::Future;
+use core::pin::{pin, Pin};
+use core::task::{Context, Poll, Waker};
+use proptest::prelude::*;
+
+type AsyncSmt<const N: usize> = AsyncSparseMerkleTree<
+    Blake2bHasher,
+    PaddedKey<N>,
+    H256,
+    YieldingStore<DefaultStore<PaddedKey<N>, H256, N>>,
+    N,
+>;
+
+/// Returns `Pending` once before completing, like a store waiting on I/O
+struct YieldNow(bool);
+
+impl Future for YieldNow {
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.0 {
+            Poll::Ready(())
+        } else {
+            self.0 = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+}
+
+/// Poll `future` until it completes
+fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let mut cx = Context::from_waker(Waker::noop());
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+    }
+}
+
+/// An `AsyncStore` that yields before every operation
+#[derive(Default)]
+struct YieldingStore<S>(S);
+
+impl<K, V, S, const N: usize> AsyncStore<K, V, N> for YieldingStore<S>
+where
+    K: crate::Key<N> + Send,
+    V: Send,
+    S: Store<K, V, N> + Send + Sync,
+{
+    async fn get_branch(&self, node: &H256) -> Result<Option<BranchNode<K, N>>, Error> {
+        YieldNow(false).await;
+        self.0.get_branch(node)
+    }
+    async fn get_leaf(&self, leaf_key: &H256) -> Result<Option<LeafNode<K, V, N>>, Error> {
+        YieldNow(false).await;
+        self.0.get_leaf(leaf_key)
+    }
+    async fn insert_branch(&mut self, node: H256, branch: BranchNode<K, N>) -> Result<(), Error> {
+        YieldNow(false).await;
+        self.0.insert_branch(node, branch)
+    }
+    async fn insert_leaf(&mut self, leaf_key: H256, leaf: LeafNode<K, V, N>) -> Result<(), Error> {
+        YieldNow(false).await;
+        self.0.insert_leaf(leaf_key, leaf)
+    }
+    async fn remove_branch(&mut self, node: &H256) -> Result<(), Error> {
+        YieldNow(false).await;
+        self.0.remove_branch(node)
+    }
+    async fn remove_leaf(&mut self, leaf_key: &H256) -> Result<(), Error> {
+        YieldNow(false).await;
+        self.0.remove_leaf(leaf_key)
+    }
+}
+
+fn assert_send<T: Send>(value: T) -> T {
+    value
+}
+
+#[test]
+fn test_async_futures_are_send() {
+    let mut smt = AsyncSmt::<32>::new(H256::zero(), YieldingStore::default());
+    let key: PaddedKey<32> = [1u8; 32].into();
+    block_on(assert_send(smt.update(key, [2u8; 32].into()))).expect("update");
+    assert_eq!(block_on(assert_send(smt.get(&key))), Ok([2u8; 32].into()));
+    block_on(assert_send(smt.merkle_proof(vec![key]))).expect("gen proof");
+    block_on(assert_send(smt.membership_proof(&key))).expect("gen proof");
+    block_on(assert_send(smt.non_membership_proof(&[3u8; 32].into()))).expect("gen proof");
+}
+
+proptest! {
+    #[test]
+    fn test_async_tree_matches_sync_tree((pairs, n) in leaves(1, 30), (absent, _n2) in leaves(1, 5)) {
+        let mut smt = AsyncSmt::<29>::new(H256::zero(), YieldingStore::default());
+        for (k, v) in pairs.iter() {
+            block_on(smt.update(*k, *v)).expect("update");
+        }
+        let mut expected = new_smt::<29>(pairs.clone());
+        assert_eq!(smt.root(), expected.root());
+
+        for (k, _v) in pairs.iter().take(n) {
+            block_on(smt.update(*k, H256::zero())).expect("update");
+            expected.update(*k, H256::zero()).expect("update");
+        }
+        assert_eq!(smt.root(), expected.root());
+        assert_eq!(smt.store().0.branches_map(), expected.store().branches_map());
+        assert_eq!(smt.store().0.leaves_map(), expected.store().leaves_map());
+
+        let keys: Vec<_> = pairs.iter().map(|(k, _v)| *k).collect();
+        assert_eq!(block_on(smt.get_many(&keys)), expected.get_many(&keys));
+        for k in keys.iter() {
+            assert_eq!(block_on(smt.get(k)), expected.get(k));
+        }
+        let proof = block_on(smt.merkle_proof(keys.clone())).expect("gen proof");
+        let sync_proof = expected.merkle_proof(keys.clone()).expect("gen proof");
+        assert_eq!(proof.leaves_path(), sync_proof.leaves_path());
+        assert_eq!(proof.proof(), sync_proof.proof());
+        let compiled = block_on(smt.compiled_merkle_proof(keys.clone())).expect("compile");
+        assert_eq!(compiled.0, expected.compiled_merkle_proof(keys).expect("compile").0);
+
+        for (k, _v) in pairs.iter().skip(n) {
+            assert_eq!(block_on(smt.membership_proof(k)), expected.membership_proof(k));
+        }
+        for (k, _v) in absent.iter().filter(|(k, _v)| !pairs.iter().any(|(key, _)| key == k)) {
+            assert_eq!(block_on(smt.non_membership_proof(k)), expected.non_membership_proof(k));
+        }
+    }
+}
@@ -0,0 +1,149 @@
+use super::padded_key::PaddedKey;
+use super::{leaves, new_smt, Smt};
+use crate::{
+    blake2b::Blake2bHasher, compact_store::CompactStore, traits::Store, SparseMerkleTree, H256,
+};
+use proptest::prelude::*;
+
+type CompactSmt<const N: usize> =
+    SparseMerkleTree<Blake2bHasher, PaddedKey<N>, H256, CompactStore<PaddedKey<N>, H256, N>, N>;
+
+/// Keys packed into the low bits so that leaves share long prefixes, a zero
+/// value deletes the key
+fn dense_pairs(max_leaves: usize) -> impl Strategy<Value = Vec<(PaddedKey<4>, H256)>> {
+    prop::collection::vec((0u16..512, 0u8..4), 0..=max_leaves).prop_map(|pairs| {
+        pairs
+            .into_iter()
+            .map(|(k, v)| {
+                let [hi, lo] = k.to_be_bytes();
+                let value = if v == 0 { H256::zero() } else { [v; 32].into() };
+                ([0, 0, hi, lo].into(), value)
+            })
+            .collect()
+    })
+}
+
+fn assert_same_tree<const N: usize>(smt: &Smt<N>, expected: &Smt<N>, keys: &[PaddedKey<N>]) {
+    assert_eq!(smt.root(), expected.root());
+    assert!(smt.validate());
+    for k in keys {
+        assert_eq!(smt.get(k), expected.get(k));
+    }
+    let mut present: Vec<_> = keys
+        .iter()
+        .map(|k| (*k, expected.get(k).unwrap()))
+        .filter(|(_k, v)| !v.is_zero())
+        .collect();
+    present.sort_by_key(|(k, _v)| **k);
+    present.dedup_by_key(|(k, _v)| *k);
+    if !present.is_empty() {
+        let proof = smt
+            .merkle_proof(present.iter().map(|(k, _v)| *k).collect())
+            .expect("gen proof");
+        assert!(proof
+            .verify::<Blake2bHasher, PaddedKey<N>, H256, N>(expected.root(), present)
+            .expect("verify"));
+    }
+}
+
+#[test]
+fn test_update_all_spans_threads() {
+    // enough leaves for the subtrees to be hashed in parallel
+    let pairs: Vec<(PaddedKey<4>, H256)> = (0u32..5000)
+        .map(|i| {
+            (
+                (i * 7919).to_be_bytes().into(),
+                [(i % 250) as u8 + 1; 32].into(),
+            )
+        })
+        .collect();
+    let mut smt = Smt::<4>::default();
+    smt.update_all(pairs.clone()).expect("update_all");
+    let expected = new_smt::<4>(pairs.clone());
+    assert_eq!(smt.root(), expected.root());
+    assert!(smt.validate());
+
+    // rewrite every third leaf and delete every sixth
+    let changes: Vec<(PaddedKey<4>, H256)> = pairs
+        .iter()
+        .step_by(3)
+        .enumerate()
+        .map(|(i, (k, _v))| {
+            (
+                *k,
+                if i % 2 == 0 {
+                    H256::zero()
+                } else {
+                    [0xAA; 32].into()
+                },
+            )
+        })
+        .collect();
+    let mut expected = expected;
+    for (k, v) in changes.clone() {
+        expected.update(k, v).unwrap();
+    }
+    smt.update_all(changes).expect("update_all");
+    assert_eq!(smt.root(), expected.root());
+    assert!(smt.validate());
+
+    let root = *expected.root();
+    let broken = Smt::<4>::new([1u8; 32].into(), smt.take_store());
+    assert!(!broken.validate());
+    let fixed = Smt::<4>::new(root, broken.take_store());
+    assert!(fixed.validate());
+}
+
+proptest! {
+    #[test]
+    fn test_update_all_matches_update(initial in dense_pairs(200), changes in dense_pairs(200)) {
+        let mut smt = Smt::<4>::default();
+        let mut expected = Smt::<4>::default();
+        for (k, v) in initial.iter() {
+            smt.update(*k, *v).unwrap();
+            expected.update(*k, *v).unwrap();
+        }
+        smt.update_all(changes.clone()).expect("update_all");
+        for (k, v) in changes.iter() {
+            expected.update(*k, *v).unwrap();
+        }
+        let keys: Vec<_> = initial.iter().chain(changes.iter()).map(|(k, _v)| *k).collect();
+        assert_same_tree(&smt, &expected, &keys);
+
+        // the store can be updated as usual afterwards
+        for (k, v) in initial.iter().rev() {
+            smt.update(*k, *v).unwrap();
+            expected.update(*k, *v).unwrap();
+        }
+        assert_same_tree(&smt, &expected, &keys);
+        assert_eq!(smt.store().size(), expected.store().size());
+    }
+
+    #[test]
+    fn test_update_all_bulk_construction((pairs, n) in leaves(1, 100)) {
+        let mut smt = Smt::<29>::default();
+        smt.update_all(pairs.clone()).expect("update_all");
+        let mut expected = new_smt::<29>(pairs.clone());
+        let keys: Vec<_> = pairs.iter().map(|(k, _v)| *k).collect();
+        assert_same_tree(&smt, &expected, &keys);
+
+        let deletions: Vec<_> = keys.iter().take(n).map(|k| (*k, H256::zero())).collect();
+        smt.update_all(deletions.clone()).expect("update_all");
+        for (k, v) in deletions {
+            expected.update(k, v).unwrap();
+        }
+        assert_same_tree(&smt, &expected, &keys);
+    }
+
+    #[test]
+    fn test_update_all_compact_store(initial in dense_pairs(100), changes in dense_pairs(100)) {
+        let mut smt = CompactSmt::<4>::default();
+        let mut expected = Smt::<4>::default();
+        smt.update_all(initial.clone()).expect("update_all");
+        expected.update_all(initial).expect("update_all");
+        smt.update_all(changes.clone()).expect("update_all");
+        expected.update_all(changes).expect("update_all");
+        assert_eq!(smt.root(), expected.root());
+        assert!(smt.validate());
+    }
+}
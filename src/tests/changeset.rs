@@ -0,0 +1,195 @@
+use super::padded_key::PaddedKey;
+use super::{leaves, new_smt, Smt};
+use crate::{
+    changeset::ChangeSet,
+    error::Error,
+    tree::{LeafNode, StoreOp},
+    H256,
+};
+use proptest::prelude::*;
+
+fn leaf_pairs() -> Vec<(PaddedKey<29>, H256)> {
+    (1u8..=10)
+        .map(|i| ([i; 29].into(), [i; 32].into()))
+        .collect()
+}
+
+/// A tree over `leaf_pairs`, a replica of it and the change set of a few
+/// updates to the tree
+fn primary_and_replica() -> (Smt<29>, Smt<29>, ChangeSet<PaddedKey<29>, H256, 29>) {
+    let mut primary = new_smt::<29>(leaf_pairs());
+    let replica = Smt::<29>::new(*primary.root(), primary.store().clone());
+    primary.start_recording();
+    primary
+        .update([3u8; 29].into(), [0xAA; 32].into())
+        .expect("update");
+    primary
+        .update([5u8; 29].into(), H256::zero())
+        .expect("update");
+    primary
+        .update([0xEE; 29].into(), [0xEE; 32].into())
+        .expect("update");
+    let changeset = primary.take_changeset().expect("recording");
+    (primary, replica, changeset)
+}
+
+fn assert_replicated(primary: &Smt<29>, replica: &Smt<29>) {
+    assert_eq!(replica.root(), primary.root());
+    assert_eq!(
+        replica.store().branches_map(),
+        primary.store().branches_map()
+    );
+    assert_eq!(replica.store().leaves_map(), primary.store().leaves_map());
+}
+
+#[test]
+fn test_recording() {
+    let mut smt = new_smt::<29>(leaf_pairs());
+    assert!(smt.take_changeset().is_none());
+    let root = *smt.root();
+    smt.start_recording();
+    let changeset = smt.take_changeset().expect("recording");
+    assert!(changeset.is_empty());
+    assert_eq!(changeset.old_root, root);
+    assert_eq!(changeset.new_root, root);
+
+    smt.update([1u8; 29].into(), [0xAA; 32].into())
+        .expect("update");
+    let changeset = smt.stop_recording().expect("recording");
+    assert!(!changeset.is_empty());
+    assert_eq!(changeset.old_root, root);
+    assert_eq!(&changeset.new_root, smt.root());
+    smt.update([2u8; 29].into(), [0xAA; 32].into())
+        .expect("update");
+    assert!(smt.take_changeset().is_none());
+}
+
+#[test]
+fn test_apply_changeset() {
+    let (primary, mut replica, changeset) = primary_and_replica();
+    replica.apply_changeset(changeset).expect("apply");
+    assert_replicated(&primary, &replica);
+}
+
+#[test]
+fn test_apply_changeset_wrong_root() {
+    let (_primary, mut replica, changeset) = primary_and_replica();
+    replica
+        .update([1u8; 29].into(), H256::zero())
+        .expect("update");
+    let root = *replica.root();
+    assert_eq!(
+        replica.apply_changeset(changeset.clone()).err(),
+        Some(Error::RootMismatch {
+            expected: changeset.old_root,
+            actual: root,
+        })
+    );
+
+    let (_primary, mut replica, mut changeset) = primary_and_replica();
+    let root = *replica.root();
+    changeset.new_root = [0xAA; 32].into();
+    assert_eq!(
+        replica.apply_changeset(changeset).err(),
+        Some(Error::MissingBranch([0xAA; 32].into()))
+    );
+    // nothing was written
+    assert_eq!(replica.root(), &root);
+    assert!(replica.validate());
+}
+
+#[test]
+fn test_apply_tampered_changeset() {
+    // a write left out
+    let (_primary, mut replica, mut changeset) = primary_and_replica();
+    let index = changeset
+        .ops
+        .iter()
+        .rposition(|op| matches!(op, StoreOp::InsertBranch(..)))
+        .unwrap();
+    changeset.ops.remove(index);
+    assert!(matches!(
+        replica.apply_changeset(changeset),
+        Err(Error::MissingBranch(_))
+    ));
+
+    // a leaf with another value
+    let (_primary, mut replica, mut changeset) = primary_and_replica();
+    let leaf_hash = changeset
+        .ops
+        .iter_mut()
+        .find_map(|op| match op {
+            StoreOp::InsertLeaf(leaf_hash, leaf) => {
+                leaf.value = [0xBB; 32].into();
+                Some(*leaf_hash)
+            }
+            _ => None,
+        })
+        .unwrap();
+    assert_eq!(
+        replica.apply_changeset(changeset).err(),
+        Some(Error::LeafHashMismatch(leaf_hash))
+    );
+
+    // a node of a subtree the new tree keeps
+    let (_primary, mut replica, mut changeset) = primary_and_replica();
+    let kept = [9u8; 29].into();
+    let leaf_hash = *replica
+        .store()
+        .leaves_map()
+        .iter()
+        .find(|(_, leaf)| leaf.key == kept)
+        .unwrap()
+        .0;
+    changeset.ops.push(StoreOp::RemoveBranch(leaf_hash));
+    assert_eq!(
+        replica.apply_changeset(changeset).err(),
+        Some(Error::UnexpectedRemoval(leaf_hash))
+    );
+
+    // a leaf outside the tree
+    let (_primary, mut replica, mut changeset) = primary_and_replica();
+    let stray: H256 = [0xCC; 32].into();
+    changeset.ops.push(StoreOp::InsertLeaf(
+        stray,
+        LeafNode {
+            key: [0xCC; 29].into(),
+            value: [0xCC; 32].into(),
+        },
+    ));
+    assert_eq!(
+        replica.apply_changeset(changeset).err(),
+        Some(Error::UnreachableNode(stray))
+    );
+}
+
+proptest! {
+    #[test]
+    fn test_replicate_updates(
+        (pairs, n) in leaves(2, 50),
+        (changes, _m) in leaves(1, 20),
+    ) {
+        let mut primary = new_smt::<29>(pairs[..n].to_vec());
+        let mut replica = Smt::<29>::new(*primary.root(), primary.store().clone());
+        primary.start_recording();
+
+        // single updates, deletions and a batch in one change set
+        for (k, v) in pairs[n..].iter() {
+            primary.update(*k, *v).expect("update");
+        }
+        for (k, _v) in pairs[..n].iter().step_by(2) {
+            primary.update(*k, H256::zero()).expect("update");
+        }
+        primary.update_all(changes.clone()).expect("update_all");
+        let changeset = primary.take_changeset().expect("recording");
+        replica.apply_changeset(changeset).expect("apply");
+        assert_replicated(&primary, &replica);
+
+        // the next change set starts where the last one ended
+        let deletions = changes.iter().map(|(k, _v)| (*k, H256::zero())).collect();
+        primary.update_all(deletions).expect("update_all");
+        let changeset = primary.stop_recording().expect("recording");
+        replica.apply_changeset(changeset).expect("apply");
+        assert_replicated(&primary, &replica);
+    }
+}
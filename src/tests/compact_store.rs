@@ -0,0 +1,86 @@
+use super::padded_key::PaddedKey;
+use super::{leaves, new_smt};
+use crate::{
+    blake2b::Blake2bHasher,
+    compact_store::{CompactBranchNode, CompactStore},
+    traits::Store,
+    SparseMerkleTree, H256,
+};
+use proptest::prelude::*;
+
+type CompactSmt<const N: usize> =
+    SparseMerkleTree<Blake2bHasher, PaddedKey<N>, H256, CompactStore<PaddedKey<N>, H256, N>, N>;
+
+fn new_compact_smt<const N: usize>(pairs: Vec<(PaddedKey<N>, H256)>) -> CompactSmt<N> {
+    let mut smt = CompactSmt::<N>::default();
+    for (key, value) in pairs {
+        smt.update(key, value).unwrap();
+    }
+    smt
+}
+
+#[test]
+fn test_compact_branch_conversion() {
+    let mut smt = new_smt::<32>(vec![
+        ([1u8; 32].into(), [1u8; 32].into()),
+        ([2u8; 32].into(), [2u8; 32].into()),
+    ]);
+    smt.update([3u8; 32].into(), [3u8; 32].into()).unwrap();
+    for (hash, branch) in smt.store().branches_map() {
+        let compact = CompactBranchNode::from_branch(branch, *hash).expect("fits in u16");
+        assert_eq!(compact.fork_height as usize, branch.fork_height);
+        assert_eq!(&compact.into_branch(branch.key), branch);
+    }
+}
+
+proptest! {
+    #[test]
+    fn test_compact_store_matches_default_store((pairs, n) in leaves(1, 50)) {
+        let mut smt = new_compact_smt::<29>(pairs.clone());
+        let mut expected = new_smt::<29>(pairs.clone());
+        assert_eq!(smt.root(), expected.root());
+        // leaf branches are not stored
+        assert!(smt.store().branches_map().len() < expected.store().branches_map().len());
+
+        for (k, _v) in pairs.iter().take(n) {
+            smt.update(*k, H256::zero()).unwrap();
+            expected.update(*k, H256::zero()).unwrap();
+            assert_eq!(smt.root(), expected.root());
+        }
+        assert!(smt.validate());
+
+        // every reachable branch carries the same prefix as in DefaultStore
+        let mut reachable = vec![*expected.root()];
+        while let Some(hash) = reachable.pop() {
+            let branch = match expected.store().get_branch(&hash).unwrap() {
+                Some(branch) => branch,
+                None => continue,
+            };
+            if branch.node != hash {
+                reachable.push(branch.node);
+                reachable.push(branch.sibling);
+            }
+            let compact = smt.store().get_branch(&hash).unwrap().expect("branch");
+            assert_eq!(compact.fork_height, branch.fork_height);
+            assert_eq!(compact.node, branch.node);
+            assert_eq!(compact.sibling, branch.sibling);
+            assert_eq!(
+                compact.key.parent_path(branch.fork_height),
+                branch.key.parent_path(branch.fork_height)
+            );
+            assert_eq!(compact.key.get_bit(branch.fork_height), branch.key.get_bit(branch.fork_height));
+        }
+
+        let keys: Vec<_> = pairs.iter().skip(n).map(|(k, _v)| *k).collect();
+        let data: Vec<_> = pairs.iter().skip(n).cloned().collect();
+        for (k, v) in data.iter() {
+            assert_eq!(smt.get(k), Ok(*v));
+        }
+        if !keys.is_empty() {
+            let proof = smt.merkle_proof(keys).expect("gen proof");
+            assert!(proof
+                .verify::<Blake2bHasher, PaddedKey<29>, H256, 29>(smt.root(), data)
+                .expect("verify"));
+        }
+    }
+}
@@ -0,0 +1,236 @@
+use super::padded_key::PaddedKey;
+use super::{leaves, new_smt, Smt};
+use crate::{
+    blake2b::Blake2bHasher,
+    error::Error,
+    merkle_proof::{
+        CompiledMerkleProof, CompiledMerkleProofBuilder, Instruction, COMPILED_PROOF_VERSION,
+    },
+    H256,
+};
+use proptest::prelude::*;
+
+fn leaf_pairs() -> Vec<(PaddedKey<29>, H256)> {
+    (1u8..=10)
+        .map(|i| ([i; 29].into(), [i; 32].into()))
+        .collect()
+}
+
+fn compute_root(
+    proof: &CompiledMerkleProof,
+    leaves: Vec<(PaddedKey<29>, H256)>,
+) -> Result<H256, Error> {
+    proof.compute_root::<Blake2bHasher, PaddedKey<29>, H256, 29>(leaves)
+}
+
+#[test]
+fn test_compact_heights() {
+    let pairs = leaf_pairs();
+    let smt = new_smt::<29>(pairs.clone());
+    let proven = vec![pairs[2], pairs[7]];
+    let proof = smt
+        .merkle_proof(proven.iter().map(|(k, _v)| *k).collect())
+        .expect("proof");
+    let siblings = proof.proof().len();
+    let compiled = proof
+        .compile_keys(proven.iter().map(|(k, _v)| *k).collect())
+        .expect("compile");
+    assert_eq!(compiled.version(), COMPILED_PROOF_VERSION);
+    assert_eq!(compiled.0[0], COMPILED_PROOF_VERSION);
+
+    let legacy = compiled.to_legacy().expect("legacy");
+    assert_eq!(legacy.version(), 1);
+    assert_eq!(legacy.0[0], 0x4C);
+    assert!(compiled.0.len() < legacy.0.len());
+    // a height takes at most two bytes instead of eight
+    assert!(legacy.0.len() - compiled.0.len() + 1 >= 6 * siblings);
+
+    assert_eq!(compute_root(&compiled, proven.clone()), Ok(*smt.root()));
+    assert_eq!(compute_root(&legacy, proven), Ok(*smt.root()));
+    assert_eq!(legacy.to_legacy().expect("legacy").0, legacy.0);
+}
+
+#[test]
+fn test_reject_malformed_programs() {
+    let pairs = leaf_pairs();
+    let smt = new_smt::<29>(pairs.clone());
+    let proof = smt.merkle_proof(vec![pairs[4].0]).expect("proof");
+    let compiled = proof.compile_keys(vec![pairs[4].0]).expect("compile");
+    let legacy = compiled.to_legacy().expect("legacy");
+
+    // truncated programs
+    for program in [&compiled.0, &legacy.0] {
+        for len in 0..program.len() {
+            let truncated = CompiledMerkleProof(program[..len].to_vec());
+            assert_ne!(compute_root(&truncated, vec![pairs[4]]), Ok(*smt.root()));
+        }
+    }
+    let truncated = CompiledMerkleProof(vec![0x4C, 0x48, 0, 0]);
+    assert_eq!(
+        compute_root(&truncated, vec![pairs[4]]),
+        Err(Error::CorruptedProof)
+    );
+
+    // overlong and overflowing heights
+    for height in [&[0x81, 0x00][..], &[0xFF; 10][..]] {
+        let mut program = vec![COMPILED_PROOF_VERSION, 0x4C, 0x48];
+        program.extend_from_slice(height);
+        assert_eq!(
+            compute_root(&CompiledMerkleProof(program), vec![pairs[4]]),
+            Err(Error::CorruptedProof)
+        );
+    }
+    let program = vec![COMPILED_PROOF_VERSION, 0x4C, 0x42];
+    assert_eq!(
+        compute_root(&CompiledMerkleProof(program), vec![pairs[4]]),
+        Err(Error::InvalidCode(0x42))
+    );
+}
+
+#[test]
+fn test_instructions() {
+    let pairs = leaf_pairs();
+    let smt = new_smt::<29>(pairs[..2].to_vec());
+    let proof = smt.merkle_proof(vec![pairs[0].0]).expect("proof");
+    let (sibling, height) = proof.proof()[0];
+    // the keys 0x01.. and 0x02.. fork at the second highest bit
+    assert_eq!(height, 8 * 29 - 7);
+    let compiled = proof.compile_keys(vec![pairs[0].0]).expect("compile");
+    let expected = vec![Instruction::Leaf, Instruction::Proof { height, sibling }];
+    for proof in [&compiled, &compiled.to_legacy().expect("legacy")] {
+        let instructions: Result<Vec<_>, Error> = proof.instructions().collect();
+        assert_eq!(instructions, Ok(expected.clone()));
+    }
+    assert_eq!(
+        compiled.to_string(),
+        format!(
+            "compiled proof v2\nLEAF\nPROOF height=225 sibling=0x{}",
+            hex::encode(sibling.as_slice())
+        )
+    );
+
+    let mut truncated = compiled.clone();
+    truncated.0.pop();
+    assert_eq!(
+        truncated.instructions().collect::<Vec<_>>(),
+        vec![Ok(Instruction::Leaf), Err(Error::CorruptedProof)]
+    );
+    assert_eq!(
+        truncated.to_string(),
+        "compiled proof v2\nLEAF\nerror: Corrupted proof"
+    );
+}
+
+#[test]
+fn test_builder() {
+    let pairs = leaf_pairs();
+    let smt = new_smt::<29>(pairs[..3].to_vec());
+    let proof = smt
+        .merkle_proof(vec![pairs[0].0, pairs[1].0])
+        .expect("proof");
+    let compiled = proof
+        .compile_keys(vec![pairs[0].0, pairs[1].0])
+        .expect("compile");
+    let mut builder = CompiledMerkleProofBuilder::new();
+    for instruction in compiled.instructions() {
+        builder.push(instruction.expect("instruction"));
+    }
+    assert_eq!(builder.build().0, compiled.0);
+    assert_eq!(
+        builder.legacy().build().0,
+        compiled.to_legacy().expect("legacy").0
+    );
+
+    // merging a single node
+    let crafted = CompiledMerkleProofBuilder::new().leaf().merge(3).build();
+    assert_eq!(
+        compute_root(&crafted, vec![pairs[0]]),
+        Err(Error::CorruptedStack)
+    );
+    // siblings on the same side
+    let crafted = CompiledMerkleProofBuilder::new()
+        .leaf()
+        .leaf()
+        .merge(0)
+        .build();
+    assert_eq!(
+        compute_root(&crafted, vec![pairs[0], pairs[2]]),
+        Err(Error::NonSiblings)
+    );
+    let crafted = CompiledMerkleProofBuilder::new()
+        .leaf()
+        .proof(8 * 29 - 1, [1u8; 32].into())
+        .build();
+    assert!(compute_root(&crafted, vec![pairs[0]]).is_ok());
+}
+
+#[test]
+fn test_compiled_merkle_proof() {
+    let pairs = leaf_pairs();
+    let smt = new_smt::<29>(pairs.clone());
+    // present and absent keys
+    let keys = vec![pairs[2].0, [0x30; 29].into(), pairs[7].0, [0u8; 29].into()];
+    let compiled = smt.compiled_merkle_proof(keys.clone()).expect("compile");
+    let proof = smt.merkle_proof(keys.clone()).expect("proof");
+    assert_eq!(
+        compiled.0,
+        proof.clone().compile_keys(keys.clone()).expect("compile").0
+    );
+    #[allow(deprecated)]
+    let legacy_call = proof
+        .compile(keys.iter().map(|k| (*k, H256::zero())).collect())
+        .expect("compile");
+    assert_eq!(legacy_call.0, compiled.0);
+    let leaves = keys
+        .iter()
+        .map(|k| (*k, smt.get(k).expect("get")))
+        .collect();
+    assert_eq!(compute_root(&compiled, leaves), Ok(*smt.root()));
+
+    let empty = Smt::<29>::default();
+    let compiled = empty.compiled_merkle_proof(keys.clone()).expect("compile");
+    let leaves = keys.iter().map(|k| (*k, H256::zero())).collect();
+    assert_eq!(compute_root(&compiled, leaves), Ok(H256::zero()));
+
+    assert_eq!(
+        smt.compiled_merkle_proof(vec![]).err(),
+        Some(Error::EmptyKeys)
+    );
+    assert_eq!(
+        smt.compiled_merkle_proof(vec![pairs[2].0, pairs[2].0])
+            .err(),
+        Some(Error::DuplicateKeys)
+    );
+}
+
+proptest! {
+    #[test]
+    fn test_compiled_formats_agree((pairs, n) in leaves(1, 50)) {
+        let smt = new_smt::<29>(pairs.clone());
+        let proven = pairs[..n].to_vec();
+        let proof = smt
+            .merkle_proof(proven.iter().map(|(k, _v)| *k).collect())
+            .expect("proof");
+        let compiled = proof.compile_keys(proven.iter().map(|(k, _v)| *k).collect()).expect("compile");
+        let legacy = compiled.to_legacy().expect("legacy");
+        prop_assert!(compiled.0.len() <= legacy.0.len() + 1);
+        prop_assert_eq!(compute_root(&compiled, proven.clone()), Ok(*smt.root()));
+        prop_assert_eq!(compute_root(&legacy, proven), Ok(*smt.root()));
+    }
+
+    #[test]
+    fn test_compiled_merkle_proof_matches_compile(
+        (pairs, n) in leaves(1, 50),
+        (absent, _m) in leaves(1, 10),
+    ) {
+        let smt = new_smt::<29>(pairs[..n].to_vec());
+        let mut keys: Vec<_> = pairs.iter().chain(absent.iter()).map(|(k, _v)| *k).collect();
+        keys.sort_unstable_by_key(|k| **k);
+        keys.dedup();
+        let compiled = smt.compiled_merkle_proof(keys.clone()).expect("compile");
+        let proof = smt.merkle_proof(keys.clone()).expect("proof");
+        prop_assert_eq!(&compiled.0, &proof.compile_keys(keys.clone()).expect("compile").0);
+        let leaves = keys.iter().map(|k| (*k, smt.get(k).expect("get"))).collect();
+        prop_assert_eq!(compute_root(&compiled, leaves), Ok(*smt.root()));
+    }
+}
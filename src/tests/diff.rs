@@ -0,0 +1,140 @@
+use super::padded_key::PaddedKey;
+use super::{leaves, new_smt};
+use crate::{
+    blake2b::Blake2bHasher,
+    default_store::DefaultStore,
+    error::Error,
+    traits::Store,
+    tree::{BranchNode, Diff, LeafNode},
+    InternalKey, SparseMerkleTree, H256,
+};
+use proptest::prelude::*;
+use std::collections::BTreeMap;
+
+/// Never removes nodes, so that it holds every root the tree went through
+#[derive(Default)]
+struct KeepingStore(DefaultStore<PaddedKey<29>, H256, 29>);
+
+impl Store<PaddedKey<29>, H256, 29> for KeepingStore {
+    fn get_branch(&self, node: &H256) -> Result<Option<BranchNode<PaddedKey<29>, 29>>, Error> {
+        self.0.get_branch(node)
+    }
+    fn get_leaf(
+        &self,
+        leaf_key: &H256,
+    ) -> Result<Option<LeafNode<PaddedKey<29>, H256, 29>>, Error> {
+        self.0.get_leaf(leaf_key)
+    }
+    fn insert_branch(
+        &mut self,
+        node: H256,
+        branch: BranchNode<PaddedKey<29>, 29>,
+    ) -> Result<(), Error> {
+        self.0.insert_branch(node, branch)
+    }
+    fn insert_leaf(
+        &mut self,
+        leaf_key: H256,
+        leaf: LeafNode<PaddedKey<29>, H256, 29>,
+    ) -> Result<(), Error> {
+        self.0.insert_leaf(leaf_key, leaf)
+    }
+    fn remove_branch(&mut self, _node: &H256) -> Result<(), Error> {
+        Ok(())
+    }
+    fn remove_leaf(&mut self, _leaf_key: &H256) -> Result<(), Error> {
+        Ok(())
+    }
+    fn sorted_leaves<'a>(&'a self) -> impl Iterator<Item = (PaddedKey<29>, &'a H256)>
+    where
+        H256: 'a,
+    {
+        self.0.sorted_leaves()
+    }
+    fn size(&self) -> usize {
+        self.0.size()
+    }
+    fn branch_count(&self) -> usize {
+        self.0.branch_count()
+    }
+}
+
+type KeepingSmt = SparseMerkleTree<Blake2bHasher, PaddedKey<29>, H256, KeepingStore, 29>;
+
+type Leaves = BTreeMap<InternalKey<29>, (PaddedKey<29>, H256)>;
+
+fn expected_diff(a: &Leaves, b: &Leaves) -> Vec<Diff<PaddedKey<29>, H256>> {
+    let keys: std::collections::BTreeSet<_> = a.keys().chain(b.keys()).collect();
+    keys.into_iter()
+        .filter_map(|k| match (a.get(k), b.get(k)) {
+            (Some((key, old)), Some((_, new))) if old != new => {
+                Some(Diff::Modified(*key, *old, *new))
+            }
+            (Some((key, old)), None) => Some(Diff::Removed(*key, *old)),
+            (None, Some((key, new))) => Some(Diff::Added(*key, *new)),
+            _ => None,
+        })
+        .collect()
+}
+
+fn apply(leaves: &mut Leaves, pairs: &[(PaddedKey<29>, H256)]) {
+    for (k, v) in pairs {
+        if v.is_zero() {
+            leaves.remove(&**k);
+        } else {
+            leaves.insert(**k, (*k, *v));
+        }
+    }
+}
+
+#[test]
+fn test_diff_same_and_empty_roots() {
+    let pairs: Vec<(PaddedKey<29>, H256)> = (1u8..=5)
+        .map(|i| ([i; 29].into(), [i; 32].into()))
+        .collect();
+    let smt = new_smt::<29>(pairs.clone());
+    let root = *smt.root();
+    assert!(smt.diff(root, root).expect("diff").is_empty());
+    assert!(smt
+        .diff(H256::zero(), H256::zero())
+        .expect("diff")
+        .is_empty());
+    let added: Vec<_> = pairs.iter().map(|(k, v)| Diff::Added(*k, *v)).collect();
+    assert_eq!(smt.diff(H256::zero(), root).expect("diff"), added);
+    let removed: Vec<_> = pairs.iter().map(|(k, v)| Diff::Removed(*k, *v)).collect();
+    assert_eq!(smt.diff(root, H256::zero()).expect("diff"), removed);
+    // the store does not hold the nodes of the other root
+    let unknown: H256 = [0xAA; 32].into();
+    assert_eq!(
+        smt.diff(root, unknown).err(),
+        Some(Error::MissingBranch(unknown))
+    );
+}
+
+proptest! {
+    #[test]
+    fn test_diff_matches_leaves(
+        (pairs, n) in leaves(1, 50),
+        (changes, m) in leaves(1, 20),
+    ) {
+        let mut smt = KeepingSmt::default();
+        let mut leaves_a = Leaves::new();
+        smt.update_all(pairs.clone()).expect("update_all");
+        apply(&mut leaves_a, &pairs);
+        let root_a = *smt.root();
+
+        // delete some leaves, modify some and add new ones
+        let mut updates: Vec<_> = pairs[..n].iter().map(|(k, _v)| (*k, H256::zero())).collect();
+        updates.extend(pairs[n..].iter().map(|(k, v)| (*k, [!v.as_slice()[0]; 32].into())));
+        updates.extend(changes[..m].iter().copied());
+        for (k, v) in updates.iter() {
+            smt.update(*k, *v).expect("update");
+        }
+        let mut leaves_b = leaves_a.clone();
+        apply(&mut leaves_b, &updates);
+        let root_b = *smt.root();
+
+        prop_assert_eq!(smt.diff(root_a, root_b).expect("diff"), expected_diff(&leaves_a, &leaves_b));
+        prop_assert_eq!(smt.diff(root_b, root_a).expect("diff"), expected_diff(&leaves_b, &leaves_a));
+    }
+}
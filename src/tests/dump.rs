@@ -0,0 +1,198 @@
+use super::padded_key::PaddedKey;
+use super::{leaves, new_smt, ShaSmt, Smt};
+use crate::{
+    compact_store::CompactStore, default_store::DefaultStore, dump::MAX_DUMP_KEY_LEN,
+    error::Error, sha256::Sha256Hasher, traits::Store, InternalKey, Key, SparseMerkleTree, H256,
+};
+use core::ops::Deref;
+use proptest::prelude::*;
+use sha2::{Digest, Sha256};
+
+type CompactSmt =
+    SparseMerkleTree<Sha256Hasher, PaddedKey<29>, H256, CompactStore<PaddedKey<29>, H256, 29>, 29>;
+
+const ROOT_OFFSET: usize = 47;
+const FIRST_LEAF_OFFSET: usize = 87;
+
+/// A key whose user bytes are the hex of its internal key, twice as long
+#[derive(Eq, PartialEq, Debug, Hash, Clone, Copy)]
+struct HexKey {
+    internal: InternalKey<4>,
+    hex: [u8; 8],
+}
+
+impl Deref for HexKey {
+    type Target = InternalKey<4>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.internal
+    }
+}
+
+impl Key<4> for HexKey {
+    type Error = Error;
+
+    fn as_slice(&self) -> &[u8] {
+        &self.hex
+    }
+
+    fn try_from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let text = core::str::from_utf8(bytes).map_err(|_| Error::KeyTooLarge)?;
+        if text.len() != 8 {
+            return Err(Error::KeyTooLarge);
+        }
+        let value = u32::from_str_radix(text, 16).map_err(|_| Error::KeyTooLarge)?;
+        let mut hex = [0u8; 8];
+        hex.copy_from_slice(bytes);
+        Ok(HexKey {
+            internal: value.to_be_bytes().into(),
+            hex,
+        })
+    }
+}
+
+type HexSmt = SparseMerkleTree<Sha256Hasher, HexKey, H256, DefaultStore<HexKey, H256, 4>, 4>;
+
+fn dump<const N: usize>(smt: &Smt<N>) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    smt.dump(&mut bytes).expect("dump");
+    bytes
+}
+
+/// Recompute the checksum after the content was edited
+fn reseal(bytes: &mut [u8]) {
+    let (content, checksum) = bytes.split_at_mut(bytes.len() - 32);
+    checksum.copy_from_slice(&Sha256::digest(content));
+}
+
+#[test]
+fn test_load_empty_tree() {
+    let bytes = dump(&Smt::<29>::default());
+    let smt = Smt::<29>::load(&bytes[..]).expect("load");
+    assert!(smt.is_empty());
+    assert_eq!(smt.store().size(), 0);
+}
+
+#[test]
+fn test_load_rejects_other_trees() {
+    let smt = new_smt::<29>(vec![([1u8; 29].into(), [2u8; 32].into())]);
+    let bytes = dump(&smt);
+    assert!(matches!(
+        ShaSmt::<29>::load(&bytes[..]),
+        Err(Error::InvalidDump(_))
+    ));
+    assert!(matches!(
+        Smt::<32>::load(&bytes[..]),
+        Err(Error::InvalidDump(_))
+    ));
+    assert!(matches!(
+        Smt::<29>::load(&b"not a dump"[..]),
+        Err(Error::InvalidDump(_))
+    ));
+    assert!(matches!(
+        Smt::<29>::load(&bytes[..bytes.len() - 1]),
+        Err(Error::Io(_))
+    ));
+}
+
+#[test]
+fn test_load_rejects_wrong_root() {
+    let smt = new_smt::<29>(vec![([1u8; 29].into(), [2u8; 32].into())]);
+    let mut bytes = dump(&smt);
+    bytes[ROOT_OFFSET] ^= 1;
+    // the checksum catches the edit
+    assert_eq!(
+        Smt::<29>::load(&bytes[..]).err(),
+        Some(Error::ChecksumMismatch)
+    );
+    // and the recomputed root a dump with a matching checksum
+    reseal(&mut bytes);
+    let mut expected: [u8; 32] = (*smt.root()).into();
+    expected[0] ^= 1;
+    assert_eq!(
+        Smt::<29>::load(&bytes[..]).err(),
+        Some(Error::RootMismatch {
+            expected: expected.into(),
+            actual: *smt.root(),
+        })
+    );
+}
+
+#[test]
+fn test_load_in_batches() {
+    // several batches of leaves, the last one partly filled
+    let pairs: Vec<(PaddedKey<4>, H256)> = (0u32..10_000)
+        .map(|i| {
+            (
+                (i * 7919).to_be_bytes().into(),
+                [(i % 250) as u8 + 1; 32].into(),
+            )
+        })
+        .collect();
+    let mut smt = Smt::<4>::default();
+    smt.update_all(pairs).expect("update_all");
+    let loaded = Smt::<4>::load(&dump(&smt)[..]).expect("load");
+    assert_eq!(loaded.root(), smt.root());
+    assert!(loaded.validate());
+    assert_eq!(loaded.store().leaves_map(), smt.store().leaves_map());
+    // no branch of an earlier batch is left behind
+    assert_eq!(loaded.store().branches_map(), smt.store().branches_map());
+}
+
+#[test]
+fn test_load_keys_longer_than_n() {
+    let mut smt = HexSmt::default();
+    for i in [1u32, 0xabcd, 0xdead_beef] {
+        let key = HexKey::try_from_bytes(format!("{:08x}", i).as_bytes()).expect("key");
+        smt.update(key, [1u8; 32].into()).expect("update");
+    }
+    let mut bytes = Vec::new();
+    smt.dump(&mut bytes).expect("dump");
+    let loaded = HexSmt::load(&bytes[..]).expect("load");
+    assert_eq!(loaded.root(), smt.root());
+    assert_eq!(loaded.store().leaves_map(), smt.store().leaves_map());
+}
+
+#[test]
+fn test_load_rejects_long_key_length() {
+    let smt = new_smt::<29>(vec![([1u8; 29].into(), [2u8; 32].into())]);
+    let mut bytes = dump(&smt);
+    let too_long = (MAX_DUMP_KEY_LEN as u32 + 1).to_le_bytes();
+    bytes[FIRST_LEAF_OFFSET..FIRST_LEAF_OFFSET + 4].copy_from_slice(&too_long);
+    reseal(&mut bytes);
+    assert_eq!(
+        Smt::<29>::load(&bytes[..]).err(),
+        Some(Error::InvalidDump("key too long".into()))
+    );
+}
+
+proptest! {
+    #[test]
+    fn test_dump_load((pairs, _n) in leaves(1, 50)) {
+        let smt = new_smt::<29>(pairs.clone());
+        let bytes = dump(&smt);
+        let loaded = Smt::<29>::load(&bytes[..]).expect("load");
+        assert_eq!(loaded.root(), smt.root());
+        assert!(loaded.validate());
+        assert_eq!(loaded.store().leaves_map(), smt.store().leaves_map());
+        // the dump does not depend on the store
+        let compact = CompactSmt::load(&dump_sha(&pairs)[..]).expect("load");
+        assert!(compact.validate());
+    }
+
+    #[test]
+    fn test_load_rejects_corruption((pairs, _n) in leaves(1, 20), index: prop::sample::Index, bit in 0u8..8) {
+        let mut bytes = dump(&new_smt::<29>(pairs));
+        let index = index.index(bytes.len());
+        bytes[index] ^= 1 << bit;
+        prop_assert!(Smt::<29>::load(&bytes[..]).is_err());
+    }
+}
+
+fn dump_sha(pairs: &[(PaddedKey<29>, H256)]) -> Vec<u8> {
+    let mut smt = ShaSmt::<29>::default();
+    smt.update_all(pairs.to_vec()).expect("update_all");
+    let mut bytes = Vec::new();
+    smt.dump(&mut bytes).expect("dump");
+    bytes
+}
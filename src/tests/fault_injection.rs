@@ -0,0 +1,259 @@
+use super::padded_key::PaddedKey;
+use super::{leaves, new_smt, Smt};
+use crate::{
+    blake2b::Blake2bHasher,
+    default_store::DefaultStore,
+    error::Error,
+    testing::{FaultyStore, StoreCall},
+    SparseMerkleTree, H256,
+};
+use proptest::prelude::*;
+use std::sync::{
+    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Arc,
+};
+
+type FaultySmt<const N: usize> = SparseMerkleTree<
+    Blake2bHasher,
+    PaddedKey<N>,
+    H256,
+    FaultyStore<DefaultStore<PaddedKey<N>, H256, N>>,
+    N,
+>;
+
+fn new_faulty_smt<const N: usize>(pairs: &[(PaddedKey<N>, H256)]) -> FaultySmt<N> {
+    let mut smt = FaultySmt::<N>::default();
+    for (key, value) in pairs {
+        smt.update(*key, *value).expect("update without faults");
+    }
+    smt
+}
+
+/// Fail the `n`th read from now on, leaving writes alone
+fn fail_nth_read<S>(store: &mut FaultyStore<S>, n: usize) {
+    let reads = Arc::new(AtomicUsize::new(0));
+    store.fail_when(move |call: &StoreCall| {
+        call.is_read() && reads.fetch_add(1, Ordering::Relaxed) == n
+    });
+}
+
+/// Count the reads that reach the store after its first write from now on
+fn count_late_reads<S>(store: &mut FaultyStore<S>) -> Arc<AtomicUsize> {
+    let wrote = AtomicBool::new(false);
+    let late_reads = Arc::new(AtomicUsize::new(0));
+    let counter = late_reads.clone();
+    store.fail_when(move |call: &StoreCall| {
+        if !call.is_read() {
+            wrote.store(true, Ordering::Relaxed);
+        } else if wrote.load(Ordering::Relaxed) {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+        false
+    });
+    late_reads
+}
+
+fn assert_store_error<T: core::fmt::Debug>(result: Result<T, Error>) {
+    match result {
+        Err(Error::Store(_)) => {}
+        other => panic!("expected a store error, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_faulty_store_counts_and_fails_nth_call() {
+    let mut smt = FaultySmt::<32>::default();
+    smt.update([1u8; 32].into(), [2u8; 32].into())
+        .expect("update");
+    let calls = smt.store().calls();
+    assert!(calls > 0);
+
+    smt.store_mut().fail_nth(0);
+    let root = *smt.root();
+    assert_store_error(smt.update([3u8; 32].into(), [4u8; 32].into()));
+    assert_eq!(smt.root(), &root);
+    assert_eq!(smt.store().calls(), calls + 1);
+
+    // the fault is only injected once
+    smt.update([3u8; 32].into(), [4u8; 32].into())
+        .expect("update");
+    assert!(smt.validate());
+}
+
+#[test]
+fn test_failed_write_is_undone() {
+    let mut smt = FaultySmt::<32>::default();
+    smt.update([1u8; 32].into(), [2u8; 32].into())
+        .expect("update");
+    let root = *smt.root();
+    smt.start_recording();
+
+    // the old leaf is removed before the first branch is inserted
+    let inserts = Arc::new(AtomicUsize::new(0));
+    smt.store_mut().fail_when(move |call: &StoreCall| {
+        matches!(call, StoreCall::InsertBranch(_)) && inserts.fetch_add(1, Ordering::Relaxed) == 0
+    });
+    assert_store_error(smt.update([1u8; 32].into(), [3u8; 32].into()));
+    smt.store_mut().clear_faults();
+    assert_eq!(smt.root(), &root);
+    assert!(smt.validate());
+    assert_eq!(smt.get(&[1u8; 32].into()), Ok([2u8; 32].into()));
+    // nothing is recorded for the failed update
+    let changeset = smt.stop_recording().expect("recording");
+    assert!(changeset.ops.is_empty());
+}
+
+#[test]
+fn test_writes_make_no_reads() {
+    let pairs: Vec<(PaddedKey<29>, H256)> = (1u8..20)
+        .map(|i| ([i; 29].into(), [i; 32].into()))
+        .collect();
+    let mut smt = new_faulty_smt::<29>(&pairs);
+    let mut expected = new_smt::<29>(pairs.clone());
+
+    let late_reads = count_late_reads(smt.store_mut());
+    smt.update([3u8; 29].into(), [7u8; 32].into()).expect("update");
+    assert_eq!(late_reads.load(Ordering::Relaxed), 0);
+    let late_reads = count_late_reads(smt.store_mut());
+    smt.update([4u8; 29].into(), H256::zero()).expect("delete");
+    assert_eq!(late_reads.load(Ordering::Relaxed), 0);
+    expected.update([3u8; 29].into(), [7u8; 32].into()).expect("update");
+    expected.update([4u8; 29].into(), H256::zero()).expect("delete");
+
+    let batch: Vec<(PaddedKey<29>, H256)> = vec![
+        ([5u8; 29].into(), [9u8; 32].into()),
+        ([6u8; 29].into(), H256::zero()),
+        ([42u8; 29].into(), [42u8; 32].into()),
+    ];
+    let late_reads = count_late_reads(smt.store_mut());
+    smt.update_all(batch.clone()).expect("update_all");
+    assert_eq!(late_reads.load(Ordering::Relaxed), 0);
+    expected.update_all(batch).expect("update_all");
+
+    let other = new_smt::<29>(vec![([43u8; 29].into(), [1u8; 32].into())]);
+    let late_reads = count_late_reads(smt.store_mut());
+    smt.merge_from(&other).expect("merge_from");
+    assert_eq!(late_reads.load(Ordering::Relaxed), 0);
+    expected.merge_from(&other).expect("merge_from");
+
+    smt.store_mut().clear_faults();
+    assert_eq!(smt.root(), expected.root());
+    assert!(smt.validate());
+}
+
+proptest! {
+    #[test]
+    fn test_read_fault_keeps_tree_consistent(
+        (pairs, _n) in leaves(1, 30),
+        (extra, _n2) in leaves(1, 5),
+        fault in 0usize..64,
+    ) {
+        let mut smt = new_faulty_smt::<29>(&pairs);
+        let mut expected: Smt<29> = new_smt(pairs.clone());
+        for (k, v) in extra {
+            let root = *smt.root();
+            fail_nth_read(smt.store_mut(), fault);
+            let result = smt.update(k, v).copied();
+            smt.store_mut().clear_faults();
+            match result {
+                Ok(new_root) => {
+                    expected.update(k, v).expect("update");
+                    assert_eq!(&new_root, expected.root());
+                }
+                Err(err) => {
+                    assert!(matches!(err, Error::Store(_)));
+                    assert_eq!(smt.root(), &root);
+                    assert!(smt.validate());
+                    // retrying without the fault lands on the same root
+                    smt.update(k, v).expect("retry");
+                    expected.update(k, v).expect("update");
+                }
+            }
+            assert_eq!(smt.root(), expected.root());
+        }
+        assert!(smt.validate());
+        for (k, _v) in pairs {
+            assert_eq!(smt.get(&k), expected.get(&k));
+        }
+    }
+
+    #[test]
+    fn test_any_fault_never_reports_wrong_root(
+        (pairs, _n) in leaves(1, 30),
+        (extra, _n2) in leaves(1, 5),
+        fault in 0usize..128,
+    ) {
+        let mut smt = new_faulty_smt::<29>(&pairs);
+        let mut expected: Smt<29> = new_smt(pairs.clone());
+        for (k, v) in extra {
+            let root = *smt.root();
+            smt.store_mut().fail_nth(fault);
+            let result = smt.update(k, v).copied();
+            smt.store_mut().clear_faults();
+            match result {
+                Ok(new_root) => {
+                    expected.update(k, v).expect("update");
+                    assert_eq!(&new_root, expected.root());
+                }
+                Err(err) => {
+                    assert!(matches!(err, Error::Store(_)));
+                    // a failed update never publishes a new root
+                    assert_eq!(smt.root(), &root);
+                    // and the writes it made were undone
+                    assert!(smt.validate());
+                    for (key, _v) in &pairs {
+                        assert_eq!(smt.get(key), expected.get(key));
+                    }
+                    smt.update(k, v).expect("retry");
+                    expected.update(k, v).expect("update");
+                }
+            }
+            assert_eq!(smt.root(), expected.root());
+        }
+    }
+
+    #[test]
+    fn test_read_faults_in_queries_never_panic(
+        (pairs, n) in leaves(1, 30),
+        (absent, _n2) in leaves(1, 5),
+        fault in 0usize..64,
+    ) {
+        let mut smt = new_faulty_smt::<29>(&pairs);
+        let keys: Vec<_> = pairs.iter().take(n).map(|(k, _v)| *k).collect();
+        let absent: Vec<_> = absent
+            .into_iter()
+            .map(|(k, _v)| k)
+            .filter(|k| pairs.iter().all(|(key, _v)| key != k))
+            .collect();
+
+        smt.store_mut().fail_nth(fault);
+        for (k, v) in pairs.iter().take(n) {
+            match smt.get(k) {
+                Ok(value) => assert_eq!(&value, v),
+                Err(err) => assert!(matches!(err, Error::Store(_))),
+            }
+        }
+        smt.store_mut().fail_nth(fault);
+        match smt.merkle_proof(keys.clone()) {
+            Ok(proof) => {
+                let leaves = pairs.iter().take(n).cloned().collect();
+                assert!(proof
+                    .verify::<Blake2bHasher, PaddedKey<29>, H256, 29>(smt.root(), leaves)
+                    .expect("verify"));
+            }
+            Err(err) => assert!(matches!(err, Error::Store(_))),
+        }
+        for k in keys {
+            smt.store_mut().fail_nth(fault);
+            if let Err(err) = smt.membership_proof(&k) {
+                assert!(matches!(err, Error::Store(_)));
+            }
+        }
+        for k in absent {
+            smt.store_mut().fail_nth(fault);
+            if let Err(err) = smt.non_membership_proof(&k) {
+                assert!(matches!(err, Error::Store(_)));
+            }
+        }
+    }
+}
@@ -0,0 +1,90 @@
+use super::padded_key::PaddedKey;
+use super::{new_sha_smt, new_smt};
+use crate::{blake2b::Blake2bHasher, proof_ics23, Key, H256};
+use proptest::prelude::*;
+
+/// Leaves whose keys only differ in the bytes around height 256, and in the
+/// last byte
+fn leaves<const N: usize>() -> impl Strategy<Value = Vec<(PaddedKey<N>, H256)>> {
+    prop::collection::vec((any::<[u8; 3]>(), any::<[u8; 32]>()), 1..30).prop_map(|pairs| {
+        let mut pairs: Vec<(PaddedKey<N>, H256)> = pairs
+            .into_iter()
+            .map(|(bytes, value)| {
+                let mut key = [0x5A; N];
+                let index = N.saturating_sub(33);
+                key[index] = bytes[0];
+                key[index + 1] = bytes[1];
+                key[N - 1] = bytes[2];
+                (key.into(), value.into())
+            })
+            .collect();
+        pairs.sort_by_key(|(k, _v)| **k);
+        pairs.dedup_by_key(|(k, _v)| **k);
+        pairs
+    })
+}
+
+fn check_proofs<const N: usize>(pairs: Vec<(PaddedKey<N>, H256)>) -> Result<(), TestCaseError> {
+    let smt = new_smt::<N>(pairs.clone());
+    let keys: Vec<_> = pairs.iter().map(|(k, _v)| *k).collect();
+    let proof = smt.merkle_proof(keys.clone()).expect("proof");
+    prop_assert!(proof
+        .clone()
+        .verify::<Blake2bHasher, PaddedKey<N>, H256, N>(smt.root(), pairs.clone())
+        .expect("verify"));
+    let compiled = proof.compile_keys(keys.clone()).expect("compile");
+    prop_assert_eq!(
+        &compiled.0,
+        &smt.compiled_merkle_proof(keys).expect("compile").0
+    );
+    prop_assert!(compiled
+        .verify::<Blake2bHasher, PaddedKey<N>, H256, N>(smt.root(), pairs.clone())
+        .expect("verify"));
+
+    let sha_smt = new_sha_smt::<N>(pairs.clone());
+    let spec = proof_ics23::get_spec_for::<N>(ics23::HashOp::Sha256);
+    let root = sha_smt.root().as_slice().to_vec();
+    for (k, v) in pairs {
+        let proof = sha_smt.membership_proof(&k).expect("proof");
+        prop_assert!(ics23::verify_membership::<ics23::HostFunctionsManager>(
+            &proof,
+            &spec,
+            &root,
+            &k.to_vec(),
+            v.as_slice()
+        ));
+    }
+    Ok(())
+}
+
+#[test]
+fn test_spec_depth() {
+    let spec = proof_ics23::get_spec_for::<300>(ics23::HashOp::Sha256);
+    assert_eq!(spec.max_depth, 2400);
+    assert_eq!(
+        proof_ics23::get_spec(ics23::HashOp::Sha256),
+        proof_ics23::get_spec_for::<32>(ics23::HashOp::Sha256)
+    );
+}
+
+proptest! {
+    #[test]
+    fn test_narrow_keys(pairs in leaves::<8>()) {
+        check_proofs(pairs)?;
+    }
+
+    #[test]
+    fn test_hash_width_keys(pairs in leaves::<32>()) {
+        check_proofs(pairs)?;
+    }
+
+    #[test]
+    fn test_wide_keys(pairs in leaves::<33>()) {
+        check_proofs(pairs)?;
+    }
+
+    #[test]
+    fn test_string_width_keys(pairs in leaves::<300>()) {
+        check_proofs(pairs)?;
+    }
+}
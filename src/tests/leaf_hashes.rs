@@ -0,0 +1,118 @@
+use super::padded_key::PaddedKey;
+use super::{leaves, new_smt};
+use crate::{
+    blake2b::Blake2bHasher, error::Error, hash_leaf, merge::merge, merkle_proof::MerkleProof, H256,
+};
+use proptest::prelude::*;
+
+fn leaf_hash(key: &PaddedKey<29>, value: &H256) -> H256 {
+    hash_leaf::<Blake2bHasher, PaddedKey<29>, H256, 29>(key, value)
+}
+
+#[test]
+fn test_zero_leaf_hash() {
+    let pairs: Vec<(PaddedKey<29>, H256)> = (1u8..=10)
+        .map(|i| ([i; 29].into(), [i; 32].into()))
+        .collect();
+    let smt = new_smt::<29>(pairs.clone());
+    let absent: PaddedKey<29> = [0x30; 29].into();
+    assert_eq!(leaf_hash(&absent, &H256::zero()), H256::zero());
+
+    let keys = vec![pairs[2].0, absent];
+    let proof = smt.merkle_proof(keys.clone()).expect("proof");
+    let compiled = smt.compiled_merkle_proof(keys).expect("compile");
+    let leaves = vec![
+        (pairs[2].0, leaf_hash(&pairs[2].0, &pairs[2].1)),
+        (absent, H256::zero()),
+    ];
+    assert_eq!(
+        proof
+            .clone()
+            .compute_root_from_leaf_hashes::<Blake2bHasher, PaddedKey<29>, 29>(leaves.clone()),
+        Err(Error::ZeroLeafHash)
+    );
+    assert_eq!(
+        compiled.compute_root_from_leaf_hashes::<Blake2bHasher, PaddedKey<29>, 29>(leaves),
+        Err(Error::ZeroLeafHash)
+    );
+
+    // a zero leaf hash merges away, the root as its sibling passes for any
+    // key, present or not
+    let forged = MerkleProof::new(vec![vec![0]], vec![(*smt.root(), 0)]);
+    let compiled = forged
+        .clone()
+        .compile_keys(vec![pairs[2].0])
+        .expect("compile");
+    let leaves = vec![(pairs[2].0, H256::zero())];
+    assert_eq!(
+        forged.compute_root_from_leaf_hashes::<Blake2bHasher, PaddedKey<29>, 29>(leaves.clone()),
+        Err(Error::ZeroLeafHash)
+    );
+    assert_eq!(
+        compiled.compute_root_from_leaf_hashes::<Blake2bHasher, PaddedKey<29>, 29>(leaves),
+        Err(Error::ZeroLeafHash)
+    );
+}
+
+#[test]
+fn test_internal_node_hash() {
+    let pairs: Vec<(PaddedKey<29>, H256)> = (1u8..=10)
+        .map(|i| ([i; 29].into(), [i; 32].into()))
+        .collect();
+    let smt = new_smt::<29>(pairs.clone());
+    let key = pairs[0].0;
+    let (mut leaves_path, mut proof) = smt.merkle_proof(vec![key]).expect("proof").take();
+    // fold the first sibling into the leaf, the parent is an internal node
+    let (sibling, height) = proof.remove(0);
+    leaves_path[0].retain(|h| *h != height);
+    let node = leaf_hash(&key, &pairs[0].1);
+    let parent = if key.get_bit(height) {
+        merge::<Blake2bHasher>(&sibling, &node)
+    } else {
+        merge::<Blake2bHasher>(&node, &sibling)
+    };
+
+    // the root matches, although the hash is no leaf of the key
+    let proof = MerkleProof::new(leaves_path, proof);
+    let leaves = vec![(key, parent)];
+    let compiled = proof.clone().compile_keys(vec![key]).expect("compile");
+    assert_eq!(
+        proof.compute_root_from_leaf_hashes::<Blake2bHasher, PaddedKey<29>, 29>(leaves.clone()),
+        Ok(*smt.root())
+    );
+    assert_eq!(
+        compiled.compute_root_from_leaf_hashes::<Blake2bHasher, PaddedKey<29>, 29>(leaves),
+        Ok(*smt.root())
+    );
+}
+
+proptest! {
+    #[test]
+    fn test_leaf_hashes_match_values((pairs, n) in leaves(1, 50)) {
+        let smt = new_smt::<29>(pairs.clone());
+        let leaves = pairs[..n].to_vec();
+        let leaf_hashes: Vec<_> = leaves.iter().map(|(k, v)| (*k, leaf_hash(k, v))).collect();
+        let keys: Vec<_> = leaves.iter().map(|(k, _v)| *k).collect();
+
+        let proof = smt.merkle_proof(keys.clone()).expect("proof");
+        prop_assert_eq!(
+            proof
+                .clone()
+                .compute_root_from_leaf_hashes::<Blake2bHasher, PaddedKey<29>, 29>(leaf_hashes.clone()),
+            Ok(*smt.root())
+        );
+        prop_assert_eq!(
+            proof.compute_root::<Blake2bHasher, PaddedKey<29>, H256, 29>(leaves.clone()),
+            Ok(*smt.root())
+        );
+        let compiled = smt.compiled_merkle_proof(keys).expect("compile");
+        prop_assert_eq!(
+            compiled.compute_root_from_leaf_hashes::<Blake2bHasher, PaddedKey<29>, 29>(leaf_hashes),
+            Ok(*smt.root())
+        );
+        prop_assert_eq!(
+            compiled.compute_root::<Blake2bHasher, PaddedKey<29>, H256, 29>(leaves),
+            Ok(*smt.root())
+        );
+    }
+}
@@ -0,0 +1,178 @@
+use super::padded_key::PaddedKey;
+use super::{leaves, new_smt, Smt};
+use crate::{default_store::DefaultStore, error::Error, traits::Store, tree::LeafNode, H256};
+use proptest::prelude::*;
+
+type Store29 = DefaultStore<PaddedKey<29>, H256, 29>;
+
+fn leaf_pairs() -> Vec<(PaddedKey<29>, H256)> {
+    (1u8..=8)
+        .map(|i| ([i; 29].into(), [i; 32].into()))
+        .collect()
+}
+
+/// A tree over `leaf_pairs` and some branch above the leaves
+fn tree_and_branch() -> (H256, Store29, H256) {
+    let smt = new_smt::<29>(leaf_pairs());
+    let root = *smt.root();
+    let store = smt.take_store();
+    let (node, _branch) = store
+        .branches_map()
+        .iter()
+        .find(|(node, branch)| branch.fork_height > 0 && **node != root)
+        .expect("inner branch");
+    let node = *node;
+    (root, store, node)
+}
+
+fn load(root: H256, store: Store29) -> Result<Smt<29>, Error> {
+    Smt::<29>::load_verified(root, store)
+}
+
+#[test]
+fn test_load_verified_empty() {
+    assert!(load(H256::zero(), Store29::default()).is_ok());
+    let store = new_smt::<29>(leaf_pairs()).take_store();
+    assert_eq!(
+        load(H256::zero(), store).err(),
+        Some(Error::IncorrectNumberOfLeaves {
+            expected: 0,
+            actual: 8
+        })
+    );
+}
+
+#[test]
+fn test_load_verified_wrong_root() {
+    let store = new_smt::<29>(leaf_pairs()).take_store();
+    let root: H256 = [7u8; 32].into();
+    assert_eq!(load(root, store).err(), Some(Error::MissingBranch(root)));
+}
+
+#[test]
+fn test_load_verified_missing_nodes() {
+    let (root, mut store, node) = tree_and_branch();
+    store.remove_branch(&node).unwrap();
+    assert_eq!(load(root, store).err(), Some(Error::MissingBranch(node)));
+
+    let smt = new_smt::<29>(leaf_pairs());
+    let root = *smt.root();
+    let mut store = smt.take_store();
+    let leaf_hash = *store.leaves_map().keys().next().unwrap();
+    store.remove_leaf(&leaf_hash).unwrap();
+    assert_eq!(load(root, store).err(), Some(Error::MissingLeaf(leaf_hash)));
+}
+
+#[test]
+fn test_load_verified_tampered_hashes() {
+    let (root, mut store, node) = tree_and_branch();
+    let mut branch = store.get_branch(&node).unwrap().unwrap();
+    branch.sibling = [0xAA; 32].into();
+    store.insert_branch(node, branch).unwrap();
+    assert_eq!(
+        load(root, store).err(),
+        Some(Error::BranchHashMismatch(node))
+    );
+
+    let smt = new_smt::<29>(leaf_pairs());
+    let root = *smt.root();
+    let mut store = smt.take_store();
+    let (leaf_hash, leaf) = store
+        .leaves_map()
+        .iter()
+        .map(|(hash, leaf)| (*hash, leaf.clone()))
+        .next()
+        .unwrap();
+    let tampered = LeafNode {
+        key: leaf.key,
+        value: [0xAA; 32].into(),
+    };
+    store.insert_leaf(leaf_hash, tampered).unwrap();
+    assert_eq!(
+        load(root, store).err(),
+        Some(Error::LeafHashMismatch(leaf_hash))
+    );
+}
+
+#[test]
+fn test_load_verified_misplaced_nodes() {
+    // a leaf whose branch claims another key
+    let smt = new_smt::<29>(leaf_pairs());
+    let root = *smt.root();
+    let mut store = smt.take_store();
+    let leaf_hash = *store.leaves_map().keys().next().unwrap();
+    let mut branch = store.get_branch(&leaf_hash).unwrap().unwrap();
+    branch.key = [0xEE; 29].into();
+    store.insert_branch(leaf_hash, branch).unwrap();
+    assert_eq!(
+        load(root, store).err(),
+        Some(Error::InvalidBranch(leaf_hash))
+    );
+
+    // a branch whose key lies outside its parent
+    let (root, mut store, node) = tree_and_branch();
+    let mut branch = store.get_branch(&node).unwrap().unwrap();
+    branch.key.set_bit(29 * 8 - 1);
+    store.insert_branch(node, branch).unwrap();
+    assert_eq!(load(root, store).err(), Some(Error::InvalidBranch(node)));
+}
+
+#[test]
+fn test_load_verified_extra_leaf() {
+    let smt = new_smt::<29>(leaf_pairs());
+    let root = *smt.root();
+    let mut store = smt.take_store();
+    store
+        .insert_leaf(
+            [0xAA; 32].into(),
+            LeafNode {
+                key: [0xAA; 29].into(),
+                value: [0xAA; 32].into(),
+            },
+        )
+        .unwrap();
+    assert_eq!(
+        load(root, store).err(),
+        Some(Error::IncorrectNumberOfLeaves {
+            expected: 8,
+            actual: 9
+        })
+    );
+}
+
+#[test]
+fn test_load_verified_extra_branch() {
+    let (root, mut store, node) = tree_and_branch();
+    let branch = store.get_branch(&node).unwrap().unwrap();
+    store.insert_branch([0xAA; 32].into(), branch).unwrap();
+    assert_eq!(
+        load(root, store).err(),
+        Some(Error::IncorrectNumberOfBranches {
+            expected: 15,
+            actual: 16
+        })
+    );
+
+    // removed leaves leave no branches behind
+    let mut smt = new_smt::<29>(leaf_pairs());
+    for i in 1u8..=4 {
+        smt.update([i; 29].into(), H256::zero()).unwrap();
+    }
+    let root = *smt.root();
+    assert!(load(root, smt.take_store()).is_ok());
+}
+
+proptest! {
+    #[cfg(feature = "borsh")]
+    #[test]
+    fn test_load_verified_borsh_store((pairs, _n) in leaves(1, 50)) {
+        let smt = new_smt::<29>(pairs.clone());
+        let bytes = borsh::to_vec(smt.store()).expect("serialize");
+        let store: Store29 = borsh::from_slice(&bytes).expect("deserialize");
+        let loaded = load(*smt.root(), store).expect("load_verified");
+        assert_eq!(loaded.root(), smt.root());
+        for (k, v) in pairs {
+            assert_eq!(loaded.get(&k).unwrap(), v);
+        }
+    }
+}
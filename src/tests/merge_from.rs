@@ -0,0 +1,120 @@
+use super::padded_key::PaddedKey;
+use super::{leaves, new_smt, Smt};
+use crate::{
+    blake2b::Blake2bHasher, compact_store::CompactStore, error::Error, SparseMerkleTree, H256,
+};
+use proptest::prelude::*;
+
+type CompactSmt =
+    SparseMerkleTree<Blake2bHasher, PaddedKey<29>, H256, CompactStore<PaddedKey<29>, H256, 29>, 29>;
+
+fn leaf_pairs(first: u8, last: u8) -> Vec<(PaddedKey<29>, H256)> {
+    (first..=last)
+        .map(|i| ([i; 29].into(), [i; 32].into()))
+        .collect()
+}
+
+#[test]
+fn test_merge_disjoint_key_spaces() {
+    let low = new_smt::<29>(leaf_pairs(1, 10));
+    let high = new_smt::<29>(leaf_pairs(0x81, 0x8A));
+    let mut merged = new_smt::<29>(leaf_pairs(1, 10));
+    merged.merge_from(&high).expect("merge");
+
+    let mut pairs = leaf_pairs(1, 10);
+    pairs.extend(leaf_pairs(0x81, 0x8A));
+    assert_eq!(merged.root(), new_smt::<29>(pairs).root());
+    assert!(merged.validate());
+    // both trees hang off the new root as they are
+    for (node, branch) in low
+        .store()
+        .branches_map()
+        .iter()
+        .chain(high.store().branches_map().iter())
+    {
+        assert_eq!(merged.store().branches_map().get(node), Some(branch));
+    }
+    assert_eq!(merged.store().branches_map().len(), 2 * 19 + 1);
+}
+
+#[test]
+fn test_merge_empty_trees() {
+    let mut smt = Smt::<29>::default();
+    let other = new_smt::<29>(leaf_pairs(1, 10));
+    smt.merge_from(&other).expect("merge");
+    assert_eq!(smt.root(), other.root());
+    assert_eq!(smt.store().branches_map(), other.store().branches_map());
+    assert_eq!(smt.store().leaves_map(), other.store().leaves_map());
+
+    let root = *smt.root();
+    smt.merge_from(&Smt::<29>::default()).expect("merge");
+    assert_eq!(smt.root(), &root);
+    smt.merge_from(&other).expect("merge");
+    assert_eq!(smt.root(), &root);
+    assert_eq!(smt.store().branches_map(), other.store().branches_map());
+}
+
+#[test]
+fn test_merge_conflicting_values() {
+    let mut smt = new_smt::<29>(leaf_pairs(1, 10));
+    let mut other = new_smt::<29>(leaf_pairs(5, 15));
+    other
+        .update([7u8; 29].into(), [0xAA; 32].into())
+        .expect("update");
+    let root = *smt.root();
+    let store = smt.store().clone();
+    let leaf_hash = *store
+        .leaves_map()
+        .iter()
+        .find(|(_, leaf)| leaf.key == [7u8; 29].into())
+        .unwrap()
+        .0;
+    assert_eq!(
+        smt.merge_from(&other).err(),
+        Some(Error::ConflictingLeaf(leaf_hash))
+    );
+    assert_eq!(smt.root(), &root);
+    assert_eq!(smt.store().branches_map(), store.branches_map());
+}
+
+#[test]
+fn test_merge_into_compact_store() {
+    // the compact store only takes a branch once the leaf below it is in
+    let mut smt = CompactSmt::default();
+    smt.update([0x81; 29].into(), [0x81; 32].into())
+        .expect("update");
+    let other = new_smt::<29>(leaf_pairs(1, 4));
+    smt.merge_from(&other).expect("merge");
+
+    let mut pairs = leaf_pairs(1, 4);
+    pairs.push(([0x81; 29].into(), [0x81; 32].into()));
+    assert_eq!(smt.root(), new_smt::<29>(pairs).root());
+    assert!(smt.validate());
+    for (k, v) in leaf_pairs(1, 4) {
+        assert_eq!(smt.get(&k), Ok(v));
+    }
+}
+
+proptest! {
+    #[test]
+    fn test_merge_matches_union(
+        (pairs, n) in leaves(1, 50),
+        (extra, m) in leaves(1, 50),
+    ) {
+        // the trees share the first n leaves with the same values
+        let mut ours = pairs.clone();
+        ours.extend(extra[m..].iter().copied());
+        let mut theirs = pairs[..n].to_vec();
+        theirs.extend(extra[..m].iter().copied());
+        let mut smt = new_smt::<29>(ours.clone());
+        let other = new_smt::<29>(theirs.clone());
+
+        smt.merge_from(&other).expect("merge");
+        ours.extend(theirs);
+        let union = new_smt::<29>(ours);
+        prop_assert_eq!(smt.root(), union.root());
+        prop_assert_eq!(smt.store().leaves_map(), union.store().leaves_map());
+        prop_assert!(smt.validate());
+        prop_assert!(Smt::<29>::load_verified(*smt.root(), smt.store().clone()).is_ok());
+    }
+}
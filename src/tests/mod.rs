@@ -1,4 +1,23 @@
+mod async_tree;
+mod batch;
+mod changeset;
+mod compiled_proof;
+mod compact_store;
+mod diff;
+mod dump;
+mod fault_injection;
+mod key_width;
+mod leaf_hashes;
+mod load_verified;
+mod merge_from;
+mod ordered_store;
 mod padded_key;
+mod proof_encoding;
+mod range_proof;
+mod shared;
+mod state_sync;
+mod subtree;
+mod untrusted_proof;
 
 use super::*;
 use crate::{
@@ -238,6 +257,31 @@ fn test_delete_a_leaf() {
     assert!(tree.validate());
 }
 
+#[test]
+fn test_default_store_reads_borrow() {
+    use crate::{borrow::Cow, traits::Store};
+
+    let tree = new_smt::<32>(vec![
+        ([1u8; 32].into(), [1u8; 32].into()),
+        ([2u8; 32].into(), [2u8; 32].into()),
+    ]);
+    let store = tree.store();
+    for (hash, branch) in store.branches_map() {
+        match store.get_branch_ref(hash).unwrap() {
+            Some(Cow::Borrowed(found)) => assert_eq!(found, branch),
+            other => panic!("expected a borrowed branch, got {:?}", other),
+        }
+    }
+    for (hash, leaf) in store.leaves_map() {
+        match store.get_leaf_ref(hash).unwrap() {
+            Some(Cow::Borrowed(found)) => assert_eq!(found, leaf),
+            other => panic!("expected a borrowed leaf, got {:?}", other),
+        }
+    }
+    assert_eq!(store.get_branch_ref(&H256::zero()).unwrap(), None);
+    assert_eq!(store.get_leaf_ref(&H256::zero()).unwrap(), None);
+}
+
 fn test_construct(key: PaddedKey<10>, value: H256) {
     // insert same value to sibling key will construct a different root
 
@@ -284,7 +328,7 @@ fn test_merkle_proof(key: PaddedKey<32>, value: H256) {
         let proof = tree.merkle_proof(vec![key]).expect("proof");
         let compiled_proof = proof
             .clone()
-            .compile(vec![(key, value)])
+            .compile_keys(vec![key])
             .expect("compile proof");
         assert!(proof.proof().len() < EXPECTED_PROOF_SIZE);
         assert!(proof
@@ -316,7 +360,6 @@ fn new_sha_smt<const N: usize>(pairs: Vec<(PaddedKey<N>, H256)>) -> ShaSmt<N> {
 fn test_ics23_non_membership_proof() {
     use rand::Rng;
     let pairs: Vec<(PaddedKey<115>, H256)> = (0u8..20)
-        .into_iter()
         .map(|i| {
             (
                 PaddedKey::<115>::try_from(vec![i; 29]).expect("Test failed"),
@@ -325,7 +368,7 @@ fn test_ics23_non_membership_proof() {
         })
         .collect();
     let smt = new_sha_smt::<115>(pairs);
-    let spec = proof_ics23::get_spec(ics23::HashOp::Sha256);
+    let spec = proof_ics23::get_spec_for::<115>(ics23::HashOp::Sha256);
     let root = smt.root().as_slice().to_vec();
     let non_existent_key =
         PaddedKey::<115>::try_from("Non existent key".as_bytes().to_vec()).expect("Test failed");
@@ -348,7 +391,6 @@ fn test_ics23_non_membership_proof() {
 fn test_ics23_membership_proof() {
     use rand::Rng;
     let pairs: Vec<(PaddedKey<115>, H256)> = (0u8..20)
-        .into_iter()
         .map(|i| {
             (
                 PaddedKey::<115>::try_from(vec![i; 29]).expect("Test failed"),
@@ -357,7 +399,7 @@ fn test_ics23_membership_proof() {
         })
         .collect();
     let mut smt = new_sha_smt::<115>(pairs);
-    let spec = proof_ics23::get_spec(ics23::HashOp::Sha256);
+    let spec = proof_ics23::get_spec_for::<115>(ics23::HashOp::Sha256);
     let existent_key =
         PaddedKey::<115>::try_from("Existent key".as_bytes().to_vec()).expect("Test failed");
     smt.update(existent_key, H256::from([42u8; 32]))
@@ -427,14 +469,14 @@ proptest! {
         let one: H256 = [255u8; 32].into();
         let target = one.copy_bits(start..(start.saturating_add(size)));
         for i in start..start.saturating_add(size) {
-            assert_eq!(one.get_bit(i as u8), target.get_bit(i as u8));
+            assert_eq!(one.get_bit(i), target.get_bit(i));
         }
         for i in 0..start {
-            assert!(!target.get_bit(i as u8));
+            assert!(!target.get_bit(i));
         }
         if let Some(start_i) = start.checked_add(size).and_then(|i| i.checked_add(1)){
             for i in start_i..=255 {
-                assert!(!target.get_bit(i as u8));
+                assert!(!target.get_bit(i));
             }
         }
     }
@@ -479,12 +521,29 @@ proptest! {
         test_merkle_proof(key.into(), value.into());
     }
 
+    #[test]
+    fn test_get_many((pairs, n) in leaves(1, 50), (absent, _n2) in leaves(1, 10)) {
+        let mut smt = new_smt::<29>(pairs.clone());
+        // deleted leaves leave behind branches that must not match
+        for (k, _v) in pairs.iter().take(n / 2) {
+            smt.update(*k, H256::zero()).unwrap();
+        }
+        let mut keys: Vec<_> = pairs.iter().map(|(k, _v)| *k).collect();
+        keys.extend(absent.into_iter().map(|(k, _v)| k));
+        keys.shuffle(&mut rand::thread_rng());
+        // duplicated keys get the value at each position
+        keys.extend(keys.clone().into_iter().take(n));
+        let expected: Vec<_> = keys.iter().map(|k| smt.get(k).unwrap()).collect();
+        assert_eq!(smt.get_many(&keys).unwrap(), expected);
+        assert_eq!(smt.get_many(&[]).unwrap(), vec![]);
+    }
+
     #[test]
     fn test_smt_single_leaf_small((pairs, _n) in leaves(1, 50)) {
         let smt = new_smt::<29>(pairs.clone());
         for (k, v) in pairs {
             let proof = smt.merkle_proof(vec![k]).expect("gen proof");
-            let compiled_proof = proof.clone().compile(vec![(k, v)]).expect("compile proof");
+            let compiled_proof = proof.clone().compile_keys(vec![k]).expect("compile proof");
             assert!(proof.verify::<Blake2bHasher, PaddedKey<29>, H256, 29>(smt.root(), vec![(k, v)]).expect("verify proof"));
             assert!(compiled_proof.verify::<Blake2bHasher, PaddedKey<29>, H256, 29>(smt.root(), vec![(k, v)]).expect("verify compiled proof"));
         }
@@ -495,7 +554,7 @@ proptest! {
         let smt = new_smt::<29>(pairs.clone());
         for (k, v) in pairs {
             let proof = smt.merkle_proof(vec![k]).expect("gen proof");
-            let compiled_proof = proof.clone().compile(vec![(k, v)]).expect("compile proof");
+            let compiled_proof = proof.clone().compile_keys(vec![k]).expect("compile proof");
             assert!(proof.verify::<Blake2bHasher, PaddedKey<29>, H256, 29>(smt.root(), vec![(k, v)]).expect("verify proof"));
             assert!(compiled_proof.verify::<Blake2bHasher, PaddedKey<29>, H256, 29>(smt.root(), vec![(k, v)]).expect("verify compiled proof"));
         }
@@ -506,7 +565,7 @@ proptest! {
         let smt = new_smt::<29>(pairs.clone());
         let proof = smt.merkle_proof(pairs.iter().take(n).map(|(k, _v)| *k).collect()).expect("gen proof");
         let data: Vec<(PaddedKey<29>, H256)> = pairs.into_iter().take(n).collect();
-        let compiled_proof = proof.clone().compile(data.clone()).expect("compile proof");
+        let compiled_proof = proof.clone().compile_keys(data.iter().map(|(k, _v)| *k).collect()).expect("compile proof");
         assert!(proof.verify::<Blake2bHasher, PaddedKey<29>, H256, 29>(smt.root(), data.clone()).expect("verify proof"));
         assert!(compiled_proof.verify::<Blake2bHasher, PaddedKey<29>, H256, 29>(smt.root(), data).expect("verify compiled proof"));
         assert!(smt.validate());
@@ -524,7 +583,7 @@ proptest! {
         let smt = new_smt::<120>(pairs.clone());
         let proof = smt.merkle_proof(pairs.iter().take(n).map(|(k, _v)| *k).collect()).expect("gen proof");
         let data: Vec<(PaddedKey<120>, H256)> = pairs.into_iter().take(n).collect();
-        let compiled_proof = proof.clone().compile(data.clone()).expect("compile proof");
+        let compiled_proof = proof.clone().compile_keys(data.iter().map(|(k, _v)| *k).collect()).expect("compile proof");
         assert!(proof.verify::<Blake2bHasher, PaddedKey<120>, H256, 120>(smt.root(), data.clone()).expect("verify proof"));
         assert!(compiled_proof.verify::<Blake2bHasher, PaddedKey<120>, H256, 120>(smt.root(), data).expect("verify compiled proof"));
         assert!(smt.validate());
@@ -535,8 +594,8 @@ proptest! {
         let smt = new_smt::<29>(pairs);
         let non_exists_keys: Vec<_> = pairs2.into_iter().map(|(k, _v)|k).collect();
         let proof = smt.merkle_proof(non_exists_keys.clone()).expect("gen proof");
+        let compiled_proof = proof.clone().compile_keys(non_exists_keys.clone()).expect("compile proof");
         let data: Vec<(PaddedKey<29>, H256)> = non_exists_keys.into_iter().map(|k|(k, H256::zero())).collect();
-        let compiled_proof = proof.clone().compile(data.clone()).expect("compile proof");
         assert!(proof.verify::<Blake2bHasher, PaddedKey<29>, H256, 29>(smt.root(), data.clone()).expect("verify proof"));
         assert!(compiled_proof.verify::<Blake2bHasher, PaddedKey<29>, H256, 29>(smt.root(), data).expect("verify compiled proof"));
     }
@@ -551,8 +610,8 @@ proptest! {
         let mut keys: Vec<_> = exists_keys.into_iter().take(exists_keys_len).chain(non_exists_keys.into_iter().take(non_exists_keys_len)).collect();
         keys.dedup();
         let proof = smt.merkle_proof(keys.clone()).expect("gen proof");
+        let compiled_proof = proof.clone().compile_keys(keys.clone()).expect("compile proof");
         let data: Vec<(PaddedKey<29>, H256)> = keys.into_iter().map(|k|(k, smt.get(&k).expect("get"))).collect();
-        let compiled_proof = proof.clone().compile(data.clone()).expect("compile proof");
         assert!(proof.verify::<Blake2bHasher, PaddedKey<29>, H256, 29>(smt.root(), data.clone()).expect("verify proof"));
         assert!(compiled_proof.verify::<Blake2bHasher, PaddedKey<29>, H256, 29>(smt.root(), data).expect("verify compiled proof"));
     }
@@ -582,6 +641,31 @@ proptest! {
         }
     }
 
+    #[test]
+    fn test_identity_hasher_store((pairs, n) in leaves(1, 30)){
+        type IdentitySmt = SparseMerkleTree<
+            Blake2bHasher,
+            PaddedKey<29>,
+            H256,
+            DefaultStore<PaddedKey<29>, H256, 29, crate::default_store::BuildIdentityHasher>,
+            29,
+        >;
+        let mut smt = IdentitySmt::default();
+        let mut expected = new_smt::<29>(pairs.clone());
+        for (k, v) in pairs.iter() {
+            smt.update(*k, *v).unwrap();
+        }
+        for (k, _v) in pairs.iter().take(n) {
+            smt.update(*k, H256::zero()).unwrap();
+            expected.update(*k, H256::zero()).unwrap();
+        }
+        assert_eq!(smt.root(), expected.root());
+        assert!(smt.validate());
+        for (k, _v) in pairs.iter() {
+            assert_eq!(smt.get(k).unwrap(), expected.get(k).unwrap());
+        }
+    }
+
     #[test]
     fn test_smt_update_with_zero_values((pairs, _n) in leaves(5, 30)){
         let mut rng = rand::thread_rng();
@@ -610,7 +694,7 @@ proptest! {
         .map(|(key, v)| (PaddedKey::<120>::try_from(<[u8; 29]>::from(key).to_vec()).expect("Test failed"), v))
         .collect();
         let smt = new_sha_smt::<120>(pairs.clone());
-        let spec = proof_ics23::get_spec(ics23::HashOp::Sha256);
+        let spec = proof_ics23::get_spec_for::<120>(ics23::HashOp::Sha256);
         let root = smt.root().as_slice().to_vec();
         for (k, v) in pairs {
             let proof = smt.membership_proof(&k).expect("gen proof");
@@ -629,7 +713,7 @@ proptest! {
         .filter_map(|(key, v)| PaddedKey::<115>::try_from(<[u8; 29]>::from(key).to_vec()).ok().map(|k| (k, v)))
         .collect();
         let smt = new_sha_smt::<115>(pairs.clone());
-        let spec = proof_ics23::get_spec(ics23::HashOp::Sha256);
+        let spec = proof_ics23::get_spec_for::<115>(ics23::HashOp::Sha256);
         let root = smt.root().as_slice().to_vec();
         let exists_key: Vec<_> = pairs.into_iter().map(|(k, _v)|k).collect();
         let non_exists_keys: Vec<_> = pairs2.into_iter().map(|(k, _v)|k).filter(|k| !exists_key.contains(k)).collect();
@@ -679,7 +763,7 @@ fn test_v0_2_broken_sample() {
     ]
     .into_iter()
     .map(parse_h256);
-    let mut pairs = keys.into_iter().zip(values.into_iter()).collect::<Vec<_>>();
+    let mut pairs = keys.into_iter().zip(values).collect::<Vec<_>>();
     let smt = new_smt::<32>(pairs.clone());
     let base_root = *smt.root();
 
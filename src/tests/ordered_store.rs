@@ -0,0 +1,79 @@
+use super::padded_key::PaddedKey;
+use super::{leaves, new_smt};
+use crate::{
+    blake2b::Blake2bHasher, ordered_store::OrderedStore, traits::Store, InternalKey,
+    SparseMerkleTree, H256,
+};
+use proptest::prelude::*;
+
+type OrderedSmt<const N: usize> =
+    SparseMerkleTree<Blake2bHasher, PaddedKey<N>, H256, OrderedStore<PaddedKey<N>, H256, N>, N>;
+
+fn new_ordered_smt<const N: usize>(pairs: Vec<(PaddedKey<N>, H256)>) -> OrderedSmt<N> {
+    let mut smt = OrderedSmt::<N>::default();
+    for (key, value) in pairs {
+        smt.update(key, value).unwrap();
+    }
+    smt
+}
+
+#[test]
+fn test_ordered_store_prefix() {
+    let keys: Vec<PaddedKey<4>> = vec![
+        [1, 2, 3, 4].into(),
+        [1, 2, 9, 9].into(),
+        [1, 3, 0, 0].into(),
+        [0, 2, 3, 4].into(),
+        [1, 2, 0, 0].into(),
+    ];
+    let smt = new_ordered_smt::<4>(keys.iter().map(|k| (*k, [7u8; 32].into())).collect());
+    let found: Vec<_> = smt.store().prefix(&[1, 2]).map(|(k, _v)| k).collect();
+    assert_eq!(
+        found,
+        vec![
+            [1, 2, 0, 0].into(),
+            [1, 2, 3, 4].into(),
+            [1, 2, 9, 9].into()
+        ]
+    );
+    assert_eq!(smt.store().prefix(&[]).count(), keys.len());
+    assert_eq!(smt.store().prefix(&[2]).count(), 0);
+    assert_eq!(smt.store().prefix(&[1, 2, 3, 4, 5]).count(), 0);
+}
+
+proptest! {
+    #[test]
+    fn test_ordered_store_matches_default_store((pairs, n) in leaves(1, 50)) {
+        let mut smt = new_ordered_smt::<29>(pairs.clone());
+        let mut expected = new_smt::<29>(pairs.clone());
+        assert_eq!(smt.root(), expected.root());
+        assert!(smt.validate());
+
+        // delete some leaves
+        for (k, _v) in pairs.iter().take(n) {
+            smt.update(*k, H256::zero()).unwrap();
+            expected.update(*k, H256::zero()).unwrap();
+        }
+        assert_eq!(smt.root(), expected.root());
+        assert_eq!(smt.store().size(), expected.store().size());
+        let sorted: Vec<_> = smt.store().sorted_leaves().collect();
+        let expected_sorted: Vec<_> = expected.store().sorted_leaves().collect();
+        assert_eq!(sorted, expected_sorted);
+        assert!(smt.validate());
+        for (k, v) in pairs.iter().skip(n) {
+            assert_eq!(smt.get(k), Ok(*v));
+        }
+    }
+
+    #[test]
+    fn test_ordered_store_range((pairs, n) in leaves(1, 50)) {
+        let smt = new_ordered_smt::<29>(pairs.clone());
+        let mut keys: Vec<InternalKey<29>> = pairs.iter().map(|(k, _v)| **k).collect();
+        keys.sort();
+        let start = keys[n - 1];
+        let found: Vec<InternalKey<29>> = smt.store().range(start..).map(|(k, _v)| *k).collect();
+        assert_eq!(found, keys[n - 1..].to_vec());
+        let found: Vec<InternalKey<29>> = smt.store().range(..start).map(|(k, _v)| *k).collect();
+        assert_eq!(found, keys[..n - 1].to_vec());
+    }
+}
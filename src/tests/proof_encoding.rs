@@ -0,0 +1,218 @@
+use super::padded_key::PaddedKey;
+use super::{leaves, new_smt};
+use crate::{
+    blake2b::Blake2bHasher,
+    error::Error,
+    merkle_proof::{CompiledMerkleProof, MerkleProof},
+    proof_encoding::{MAX_PROOF_HEIGHT, MAX_PROOF_LEAVES},
+    H256,
+};
+use borsh::BorshDeserialize;
+use proptest::prelude::*;
+
+fn leaf_pairs() -> Vec<(PaddedKey<29>, H256)> {
+    (1u8..=10)
+        .map(|i| ([i; 29].into(), [i; 32].into()))
+        .collect()
+}
+
+fn assert_same(a: &MerkleProof, b: &MerkleProof) {
+    assert_eq!(a.leaves_path(), b.leaves_path());
+    assert_eq!(a.proof(), b.proof());
+}
+
+#[test]
+fn test_wide_heights() {
+    let pairs: Vec<(PaddedKey<115>, H256)> = [0x10u8, 0x90]
+        .iter()
+        .map(|i| ([*i; 115].into(), [*i; 32].into()))
+        .collect();
+    let mut smt = super::Smt::<115>::default();
+    for (k, v) in pairs.iter() {
+        smt.update(*k, *v).expect("update");
+    }
+    let proof = smt.merkle_proof(vec![pairs[0].0]).expect("proof");
+    assert_eq!(proof.proof()[0].1, 8 * 115 - 1);
+    let bytes = proof.to_bytes();
+    // version, leaf count, path length, height, sibling count, height and
+    // sibling, with the heights in two bytes
+    assert_eq!(bytes.len(), 1 + 1 + 1 + 2 + 1 + 2 + 32);
+    assert_same(&MerkleProof::from_bytes(&bytes).expect("decode"), &proof);
+
+    let compiled = proof.compile_keys(vec![pairs[0].0]).expect("compile");
+    let bytes = compiled.to_bytes().expect("encode");
+    assert_eq!(bytes.len(), 1 + 1 + 1 + 2 + 32);
+    assert_eq!(bytes, compiled.0);
+    assert_eq!(
+        CompiledMerkleProof::from_bytes(&bytes).expect("decode").0,
+        compiled.0
+    );
+    // legacy programs are encoded in the current format
+    let legacy = compiled.to_legacy().expect("legacy");
+    assert_eq!(legacy.to_bytes().expect("encode"), compiled.0);
+    assert!(CompiledMerkleProof::from_bytes(&legacy.0).is_err());
+}
+
+#[test]
+fn test_reject_malformed() {
+    let smt = new_smt::<29>(leaf_pairs());
+    let keys = vec![leaf_pairs()[2].0, leaf_pairs()[7].0];
+    let proof = smt.merkle_proof(keys).expect("proof");
+    let bytes = proof.to_bytes();
+    let compiled = proof
+        .compile_keys(vec![leaf_pairs()[2].0, leaf_pairs()[7].0])
+        .expect("compile");
+    let compiled_bytes = compiled.to_bytes().expect("encode");
+    assert_eq!(compiled_bytes, compiled.0);
+
+    // truncated input, and trailing bytes
+    for len in 0..bytes.len() {
+        assert!(matches!(
+            MerkleProof::from_bytes(&bytes[..len]),
+            Err(Error::InvalidEncoding(_))
+        ));
+    }
+    for len in [0, compiled_bytes.len() - 1] {
+        assert!(CompiledMerkleProof::from_bytes(&compiled_bytes[..len]).is_err());
+    }
+    let mut trailing = bytes.clone();
+    trailing.push(0);
+    assert!(MerkleProof::from_bytes(&trailing).is_err());
+
+    // unknown version and instruction
+    for version in [1u8, 3] {
+        let mut tampered = bytes.clone();
+        tampered[0] = version;
+        assert!(MerkleProof::from_bytes(&tampered).is_err());
+        let mut tampered = compiled_bytes.clone();
+        tampered[0] = version;
+        assert!(CompiledMerkleProof::from_bytes(&tampered).is_err());
+    }
+    let mut tampered = compiled_bytes.clone();
+    tampered.push(0x42);
+    assert_eq!(
+        CompiledMerkleProof::from_bytes(&tampered).err(),
+        Some(Error::InvalidCode(0x42))
+    );
+
+    // a leaf count larger than the input, and an overlong one
+    for count in [vec![0xFF, 0xFF, 0xFF, 0xFF, 0x0F], vec![0x82, 0x00]] {
+        let mut tampered = vec![bytes[0]];
+        tampered.extend(count);
+        tampered.extend_from_slice(&bytes[2..]);
+        assert!(matches!(
+            MerkleProof::from_bytes(&tampered),
+            Err(Error::InvalidEncoding(_))
+        ));
+    }
+
+    // more siblings than the paths merge with
+    let forged = MerkleProof::new(vec![vec![]], vec![([1u8; 32].into(), 3)]);
+    assert!(MerkleProof::from_bytes(&forged.to_bytes()).is_err());
+    // heights out of order
+    let forged = MerkleProof::new(vec![vec![5, 3]], vec![]);
+    assert!(MerkleProof::from_bytes(&forged.to_bytes()).is_err());
+    // heights up to the cap
+    let wide = MerkleProof::new(vec![vec![MAX_PROOF_HEIGHT]], vec![]);
+    assert_same(
+        &MerkleProof::from_bytes(&wide.to_bytes()).expect("decode"),
+        &wide,
+    );
+}
+
+#[test]
+fn test_reject_oversized() {
+    let too_high = MerkleProof::new(vec![vec![MAX_PROOF_HEIGHT + 1]], vec![]);
+    assert_eq!(
+        MerkleProof::from_bytes(&too_high.to_bytes()).err(),
+        Some(Error::InvalidEncoding("height too large".into()))
+    );
+    let too_high = MerkleProof::new(
+        vec![vec![3]],
+        vec![([1u8; 32].into(), MAX_PROOF_HEIGHT + 1)],
+    );
+    assert_eq!(
+        MerkleProof::from_bytes(&too_high.to_bytes()).err(),
+        Some(Error::InvalidEncoding("height too large".into()))
+    );
+
+    // empty paths are a byte each, so only the count limits them
+    let cap = MerkleProof::new(vec![vec![]; MAX_PROOF_LEAVES], vec![]);
+    assert_eq!(
+        MerkleProof::from_bytes(&cap.to_bytes())
+            .expect("decode")
+            .leaves_path()
+            .len(),
+        MAX_PROOF_LEAVES
+    );
+    let too_many = MerkleProof::new(vec![vec![]; MAX_PROOF_LEAVES + 1], vec![]);
+    assert_eq!(
+        MerkleProof::from_bytes(&too_many.to_bytes()).err(),
+        Some(Error::InvalidEncoding("too many leaves".into()))
+    );
+}
+
+#[test]
+fn test_borsh_encoding() {
+    let smt = new_smt::<29>(leaf_pairs());
+    let proof = smt.merkle_proof(vec![leaf_pairs()[4].0]).expect("proof");
+    let bytes = borsh::to_vec(&proof).expect("serialize");
+    assert_eq!(bytes[4..], proof.to_bytes()[..]);
+    assert_same(
+        &MerkleProof::try_from_slice(&bytes).expect("decode"),
+        &proof,
+    );
+
+    let compiled = proof
+        .compile_keys(vec![leaf_pairs()[4].0])
+        .expect("compile");
+    let bytes = borsh::to_vec(&compiled).expect("serialize");
+    assert_eq!(
+        CompiledMerkleProof::try_from_slice(&bytes)
+            .expect("decode")
+            .0,
+        compiled.0
+    );
+    assert!(CompiledMerkleProof::try_from_slice(&bytes[..bytes.len() - 1]).is_err());
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn test_serde_encoding() {
+    let smt = new_smt::<29>(leaf_pairs());
+    let proof = smt.merkle_proof(vec![leaf_pairs()[4].0]).expect("proof");
+    let json = serde_json::to_string(&proof).expect("serialize");
+    assert_same(&serde_json::from_str(&json).expect("deserialize"), &proof);
+    assert!(serde_json::from_str::<MerkleProof>("[1, 1, 0]").is_err());
+
+    let compiled = proof
+        .compile_keys(vec![leaf_pairs()[4].0])
+        .expect("compile");
+    let json = serde_json::to_string(&compiled).expect("serialize");
+    let decoded: CompiledMerkleProof = serde_json::from_str(&json).expect("deserialize");
+    assert_eq!(decoded.0, compiled.0);
+}
+
+proptest! {
+    #[test]
+    fn test_encoding_round_trip((pairs, n) in leaves(1, 50)) {
+        let smt = new_smt::<29>(pairs.clone());
+        let proven = pairs[..n].to_vec();
+        let proof = smt
+            .merkle_proof(proven.iter().map(|(k, _v)| *k).collect())
+            .expect("proof");
+        let decoded = MerkleProof::from_bytes(&proof.to_bytes()).expect("decode");
+        assert_same(&decoded, &proof);
+        prop_assert!(decoded
+            .verify::<Blake2bHasher, PaddedKey<29>, H256, 29>(smt.root(), proven.clone())
+            .expect("verify"));
+
+        let compiled = proof.compile_keys(proven.iter().map(|(k, _v)| *k).collect()).expect("compile");
+        let decoded = CompiledMerkleProof::from_bytes(&compiled.to_bytes().expect("encode"))
+            .expect("decode");
+        prop_assert_eq!(&decoded.0, &compiled.0);
+        prop_assert!(decoded
+            .verify::<Blake2bHasher, PaddedKey<29>, H256, 29>(smt.root(), proven)
+            .expect("verify"));
+    }
+}
@@ -0,0 +1,155 @@
+use super::padded_key::PaddedKey;
+use super::{leaves, new_smt, Smt};
+use crate::{
+    blake2b::Blake2bHasher,
+    error::Error,
+    hash_leaf,
+    merkle_proof::{MerkleProof, RangeProof},
+    H256,
+};
+use proptest::prelude::*;
+
+fn leaf_pairs() -> Vec<(PaddedKey<29>, H256)> {
+    (1u8..=10)
+        .map(|i| ([i; 29].into(), [i; 32].into()))
+        .collect()
+}
+
+fn verify(
+    proof: &RangeProof<PaddedKey<29>, H256>,
+    root: &H256,
+    start: &PaddedKey<29>,
+    end: &PaddedKey<29>,
+) -> bool {
+    proof
+        .verify::<Blake2bHasher, 29>(root, start, end)
+        .expect("verify")
+}
+
+#[test]
+fn test_range_proof() {
+    let pairs = leaf_pairs();
+    let smt = new_smt::<29>(pairs.clone());
+    let (start, end) = ([3u8; 29].into(), [7u8; 29].into());
+    let proof = smt.range_proof(&start, &end).expect("proof");
+    assert_eq!(proof.leaves, pairs[2..6].to_vec());
+    assert_eq!(proof.left, Some(pairs[1]));
+    assert_eq!(proof.right, Some(pairs[6]));
+    assert!(verify(&proof, smt.root(), &start, &end));
+    // the leaves do not cover a larger range
+    assert!(!verify(&proof, smt.root(), &[2u8; 29].into(), &end));
+    assert!(!verify(&proof, smt.root(), &start, &[8u8; 29].into()));
+
+    // a leaf left out from the middle
+    let mut hiding = proof.clone();
+    hiding.leaves.remove(2);
+    let keys = hiding
+        .left
+        .iter()
+        .chain(hiding.leaves.iter())
+        .chain(hiding.right.iter())
+        .map(|(k, _v)| *k)
+        .collect();
+    hiding.proof = Some(smt.merkle_proof(keys).expect("proof"));
+    assert!(!verify(&hiding, smt.root(), &start, &end));
+
+    // the last leaf taken for the right neighbour
+    let mut hiding = proof;
+    hiding.right = hiding.leaves.pop();
+    let keys = hiding
+        .left
+        .iter()
+        .chain(hiding.leaves.iter())
+        .chain(hiding.right.iter())
+        .map(|(k, _v)| *k)
+        .collect();
+    hiding.proof = Some(smt.merkle_proof(keys).expect("proof"));
+    assert!(!verify(&hiding, smt.root(), &start, &end));
+}
+
+#[test]
+fn test_empty_ranges() {
+    let pairs = leaf_pairs();
+    let smt = new_smt::<29>(pairs.clone());
+    // after the last leaf
+    let (start, end) = ([0xF0; 29].into(), [0xFF; 29].into());
+    let proof = smt.range_proof(&start, &end).expect("proof");
+    assert!(proof.leaves.is_empty());
+    assert_eq!((proof.left, proof.right), (Some(pairs[9]), None));
+    assert!(verify(&proof, smt.root(), &start, &end));
+
+    // an empty range between two leaves
+    let key = [5u8; 29].into();
+    let proof = smt.range_proof(&key, &key).expect("proof");
+    assert!(proof.leaves.is_empty());
+    assert_eq!((proof.left, proof.right), (Some(pairs[3]), Some(pairs[4])));
+    assert!(verify(&proof, smt.root(), &key, &key));
+
+    // a reversed range
+    assert_eq!(
+        smt.range_proof(&end, &start).err(),
+        Some(Error::InvalidRange)
+    );
+
+    let empty = Smt::<29>::default();
+    let proof = empty.range_proof(&start, &end).expect("proof");
+    assert!(proof.proof.is_none());
+    assert!(verify(&proof, &H256::zero(), &start, &end));
+    assert!(!verify(&proof, smt.root(), &start, &end));
+}
+
+#[test]
+fn test_zero_neighbours() {
+    let pairs: Vec<(PaddedKey<32>, H256)> = [0x10u8, 0x90]
+        .iter()
+        .map(|i| ([*i; 32].into(), [*i; 32].into()))
+        .collect();
+    let smt = new_smt::<32>(pairs.clone());
+    let leaf_hash =
+        |(k, v): &(PaddedKey<32>, H256)| hash_leaf::<Blake2bHasher, PaddedKey<32>, H256, 32>(k, v);
+    let mut right = [0u8; 32];
+    right[0] = 0x20;
+    let (start, end): (PaddedKey<32>, PaddedKey<32>) = ([0x02; 32].into(), right.into());
+    let proof = smt.range_proof(&start, &end).expect("proof");
+    assert_eq!(proof.leaves, vec![pairs[0]]);
+
+    // zero leaves hash to zero and merge away, the real leaves pass for
+    // siblings outside of the neighbours
+    let forged = RangeProof {
+        leaves: vec![],
+        left: Some(([0x01; 32].into(), H256::zero())),
+        right: Some((right.into(), H256::zero())),
+        proof: Some(MerkleProof::new(
+            vec![vec![0, 253], vec![0, 253]],
+            vec![(leaf_hash(&pairs[0]), 0), (leaf_hash(&pairs[1]), 0)],
+        )),
+    };
+    assert_eq!(
+        forged.verify::<Blake2bHasher, 32>(smt.root(), &start, &end),
+        Err(Error::CorruptedProof)
+    );
+}
+
+proptest! {
+    #[test]
+    fn test_range_proof_matches_leaves(
+        (pairs, _n) in leaves(1, 50),
+        a: [u8; 29],
+        b: [u8; 29],
+    ) {
+        let smt = new_smt::<29>(pairs.clone());
+        let (start, end): (PaddedKey<29>, PaddedKey<29>) = if a <= b {
+            (a.into(), b.into())
+        } else {
+            (b.into(), a.into())
+        };
+        let mut expected: Vec<_> = pairs
+            .into_iter()
+            .filter(|(k, _v)| **k >= *start && **k < *end)
+            .collect();
+        expected.sort_by_key(|(k, _v)| **k);
+        let proof = smt.range_proof(&start, &end).expect("proof");
+        prop_assert_eq!(&proof.leaves, &expected);
+        prop_assert!(verify(&proof, smt.root(), &start, &end));
+    }
+}
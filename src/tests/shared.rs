@@ -0,0 +1,100 @@
+use super::padded_key::PaddedKey;
+use super::{leaves, new_smt};
+use crate::{
+    blake2b::Blake2bHasher, default_store::DefaultStore, shared::SharedSmt, tree::Diff, H256,
+};
+use proptest::prelude::*;
+use std::thread;
+
+type SharedSmt29 =
+    SharedSmt<Blake2bHasher, PaddedKey<29>, H256, DefaultStore<PaddedKey<29>, H256, 29>, 29>;
+
+#[test]
+fn test_concurrent_readers_see_whole_updates() {
+    const KEYS: u8 = 16;
+    const VERSIONS: u8 = 50;
+    let keys: Vec<PaddedKey<29>> = (1..=KEYS).map(|i| [i; 29].into()).collect();
+    let smt = SharedSmt29::default();
+    smt.update_all(keys.iter().map(|k| (*k, [1u8; 32].into())).collect())
+        .expect("update_all");
+
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let smt = smt.clone();
+            let keys = keys.clone();
+            thread::spawn(move || {
+                for _ in 0..200 {
+                    let snapshot = smt.snapshot().expect("snapshot");
+                    let values = snapshot.get_many(&keys).expect("get_many");
+                    // every key was written by the same update
+                    assert!(values.iter().all(|v| v == &values[0]));
+                    let proof = snapshot.merkle_proof(keys.clone()).expect("gen proof");
+                    let leaves = keys.iter().copied().zip(values).collect();
+                    assert!(proof
+                        .verify::<Blake2bHasher, PaddedKey<29>, H256, 29>(snapshot.root(), leaves)
+                        .expect("verify"));
+                }
+            })
+        })
+        .collect();
+    for version in 2..=VERSIONS {
+        smt.update_all(keys.iter().map(|k| (*k, [version; 32].into())).collect())
+            .expect("update_all");
+    }
+    for reader in readers {
+        reader.join().expect("reader");
+    }
+    let snapshot = smt.snapshot().expect("snapshot");
+    assert_eq!(snapshot.get(&keys[0]), Ok([VERSIONS; 32].into()));
+}
+
+proptest! {
+    #[test]
+    fn test_snapshot_keeps_its_root((pairs, n) in leaves(1, 30), (extra, _n2) in leaves(1, 10)) {
+        let smt = SharedSmt29::new(new_smt::<29>(pairs.clone()));
+        let snapshot = smt.snapshot().expect("snapshot");
+        let old_root = *snapshot.root();
+
+        // delete some leaves and add others
+        for (k, _v) in pairs.iter().take(n) {
+            smt.update(*k, H256::zero()).expect("update");
+        }
+        let new_root = smt.update_all(extra.clone()).expect("update_all");
+        assert_eq!(smt.root(), Ok(new_root));
+        assert!(smt.retained() > 0);
+
+        // the old snapshot still answers for the old root
+        assert_eq!(snapshot.root(), &old_root);
+        for (k, v) in pairs.iter() {
+            assert_eq!(snapshot.get(k), Ok(*v));
+        }
+        let keys: Vec<_> = pairs.iter().map(|(k, _v)| *k).collect();
+        let proof = snapshot.merkle_proof(keys.clone()).expect("gen proof");
+        assert!(proof
+            .verify::<Blake2bHasher, PaddedKey<29>, H256, 29>(&old_root, pairs.clone())
+            .expect("verify"));
+        let compiled = snapshot.compiled_merkle_proof(keys).expect("compile");
+        assert!(compiled
+            .verify::<Blake2bHasher, PaddedKey<29>, H256, 29>(&old_root, pairs.clone())
+            .expect("verify"));
+        let (k, _v) = pairs[0];
+        assert!(snapshot.membership_proof(&k).is_ok());
+
+        // a new snapshot sees the latest root
+        let latest = smt.snapshot().expect("snapshot");
+        assert_eq!(latest.root(), &new_root);
+        for (k, v) in extra.iter() {
+            assert_eq!(latest.get(k), Ok(*v));
+        }
+        let diff = snapshot.diff(&latest).expect("diff");
+        let removed = diff.iter().filter(|d| matches!(d, Diff::Removed(..))).count();
+        let added = diff.iter().filter(|d| matches!(d, Diff::Added(..))).count();
+        assert_eq!((removed, added), (n, extra.len()));
+
+        // once no snapshot is left, the next update drops the old nodes
+        drop(snapshot);
+        drop(latest);
+        smt.update_all(extra).expect("update_all");
+        assert_eq!(smt.retained(), 0);
+    }
+}
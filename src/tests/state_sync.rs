@@ -0,0 +1,237 @@
+use super::padded_key::PaddedKey;
+use super::{leaves, new_smt, Smt};
+use crate::{
+    blake2b::Blake2bHasher,
+    default_store::DefaultStore,
+    error::Error,
+    state_sync::{SnapshotChunk, SnapshotImporter},
+    H256,
+};
+use proptest::prelude::*;
+use rand::prelude::SliceRandom;
+
+type Importer =
+    SnapshotImporter<Blake2bHasher, PaddedKey<29>, H256, DefaultStore<PaddedKey<29>, H256, 29>, 29>;
+
+fn leaf_pairs() -> Vec<(PaddedKey<29>, H256)> {
+    (1u8..=10)
+        .map(|i| ([i; 29].into(), [i; 32].into()))
+        .collect()
+}
+
+fn chunks(smt: &Smt<29>, chunk_size: usize) -> Vec<SnapshotChunk<PaddedKey<29>, H256>> {
+    smt.snapshot_chunks(chunk_size)
+        .expect("chunks")
+        .collect::<Result<_, _>>()
+        .expect("chunk")
+}
+
+fn verify(chunk: &SnapshotChunk<PaddedKey<29>, H256>, root: &H256) -> bool {
+    chunk
+        .verify::<Blake2bHasher, 29>(root)
+        .expect("verify chunk")
+}
+
+#[test]
+fn test_contiguous_root_rejects_gaps() {
+    let pairs = leaf_pairs();
+    let smt = new_smt::<29>(pairs.clone());
+    let proven = vec![pairs[2], pairs[3], pairs[4]];
+    let proof = smt
+        .merkle_proof(proven.iter().map(|(k, _v)| *k).collect())
+        .expect("gen proof");
+    let root = proof
+        .clone()
+        .compute_contiguous_root::<Blake2bHasher, PaddedKey<29>, H256, 29>(
+            proven.clone(),
+            true,
+            true,
+        )
+        .expect("contiguous");
+    assert_eq!(&root, smt.root());
+    // leaves before and after the proven ones
+    for (open_start, open_end) in [(false, true), (true, false)] {
+        assert_eq!(
+            proof
+                .clone()
+                .compute_contiguous_root::<Blake2bHasher, PaddedKey<29>, H256, 29>(
+                    proven.clone(),
+                    open_start,
+                    open_end,
+                ),
+            Err(Error::NonContiguousLeaves)
+        );
+    }
+
+    // a leaf between the proven ones
+    let proven = vec![pairs[2], pairs[4]];
+    let proof = smt
+        .merkle_proof(proven.iter().map(|(k, _v)| *k).collect())
+        .expect("gen proof");
+    assert!(proof
+        .clone()
+        .verify::<Blake2bHasher, PaddedKey<29>, H256, 29>(smt.root(), proven.clone())
+        .expect("verify"));
+    assert_eq!(
+        proof.compute_contiguous_root::<Blake2bHasher, PaddedKey<29>, H256, 29>(proven, true, true),
+        Err(Error::NonContiguousLeaves)
+    );
+}
+
+#[test]
+fn test_chunk_hiding_leaves() {
+    let pairs = leaf_pairs();
+    let smt = new_smt::<29>(pairs.clone());
+    let mut chunks = chunks(&smt, 4);
+    assert_eq!(chunks.len(), 3);
+    assert_eq!(smt.snapshot_chunk_count(4), Ok(3));
+    assert!(chunks.iter().all(|chunk| verify(chunk, smt.root())));
+
+    // drop a leaf from the middle chunk and prove the rest
+    let mut chunk = chunks.remove(1);
+    chunk.leaves.remove(1);
+    let keys = chunk
+        .left
+        .iter()
+        .chain(chunk.leaves.iter())
+        .chain(chunk.right.iter())
+        .map(|(k, _v)| *k)
+        .collect();
+    chunk.proof = smt.merkle_proof(keys).expect("gen proof");
+    assert!(!verify(&chunk, smt.root()));
+
+    // claim the first chunk is the whole tree
+    let mut chunk = chunks.remove(0);
+    chunk.right = None;
+    let keys = chunk.leaves.iter().map(|(k, _v)| *k).collect();
+    chunk.proof = smt.merkle_proof(keys).expect("gen proof");
+    assert!(!verify(&chunk, smt.root()));
+    let mut importer = Importer::new(*smt.root(), 1, 4);
+    assert_eq!(importer.add_chunk(chunk), Err(Error::InvalidChunk(0)));
+    assert_eq!(importer.missing_chunks(), vec![0]);
+}
+
+/// Chunk of `pairs[range]` at `index`, with the leaves around it
+fn chunk_of(
+    smt: &Smt<29>,
+    pairs: &[(PaddedKey<29>, H256)],
+    range: core::ops::Range<usize>,
+    index: usize,
+) -> SnapshotChunk<PaddedKey<29>, H256> {
+    let left = range.start.checked_sub(1).map(|i| pairs[i]);
+    let right = pairs.get(range.end).copied();
+    let leaves = pairs[range].to_vec();
+    let keys = left
+        .iter()
+        .chain(leaves.iter())
+        .chain(right.iter())
+        .map(|(k, _v)| *k)
+        .collect();
+    SnapshotChunk {
+        index,
+        leaves,
+        left,
+        right,
+        proof: smt.merkle_proof(keys).expect("gen proof"),
+    }
+}
+
+#[test]
+fn test_import_mismatched_chunks() {
+    let pairs = leaf_pairs();
+    let smt = new_smt::<29>(pairs.clone());
+    let by_three = chunks(&smt, 3);
+    let by_four = chunks(&smt, 4);
+    let mut importer = Importer::new(*smt.root(), by_four.len(), 4);
+    // verifies on its own, but holds too few leaves
+    assert!(verify(&by_three[0], smt.root()));
+    assert_eq!(
+        importer.add_chunk(by_three[0].clone()),
+        Err(Error::InvalidChunk(0))
+    );
+    importer.add_chunk(by_four[0].clone()).expect("add chunk");
+    // verifies on its own, but does not start where the first chunk ends
+    let shifted = chunk_of(&smt, &pairs, 2..6, 1);
+    assert!(verify(&shifted, smt.root()));
+    assert_eq!(importer.add_chunk(shifted), Err(Error::InvalidChunk(1)));
+    assert_eq!(
+        importer.finish().err(),
+        Some(Error::IncompleteSnapshot { missing: 2 })
+    );
+}
+
+#[test]
+fn test_import_relabelled_chunk() {
+    let pairs: Vec<(PaddedKey<29>, H256)> = (1u8..=12)
+        .map(|i| ([i; 29].into(), [i; 32].into()))
+        .collect();
+    let smt = new_smt::<29>(pairs.clone());
+    let chunks = chunks(&smt, 3);
+    let mut importer = Importer::new(*smt.root(), chunks.len(), 3);
+    // the third chunk sent as the second one waits for the first chunk
+    let mut relabelled = chunks[2].clone();
+    relabelled.index = 1;
+    importer.add_chunk(relabelled.clone()).expect("add chunk");
+    importer.add_chunk(chunks[1].clone()).expect("add chunk");
+    assert_eq!(importer.missing_chunks(), vec![0, 1, 2, 3]);
+    // the first chunk places the second one, and leaves out the relabelled
+    importer.add_chunk(chunks[0].clone()).expect("add chunk");
+    assert_eq!(importer.missing_chunks(), vec![2, 3]);
+    assert_eq!(importer.add_chunk(relabelled), Err(Error::InvalidChunk(1)));
+    // the same chunk again is ignored
+    importer.add_chunk(chunks[1].clone()).expect("add chunk");
+    for chunk in chunks[2..].iter().rev() {
+        importer.add_chunk(chunk.clone()).expect("add chunk");
+    }
+    assert!(importer.is_complete());
+    assert_eq!(importer.finish().expect("finish").root(), smt.root());
+}
+
+#[test]
+fn test_import_zero_leaf() {
+    let pairs = leaf_pairs();
+    let smt = new_smt::<29>(pairs.clone());
+    let mut chunk = chunk_of(&smt, &pairs, 0..4, 0);
+    chunk.right = Some((pairs[4].0, H256::zero()));
+    assert_eq!(
+        chunk.verify::<Blake2bHasher, 29>(smt.root()),
+        Err(Error::CorruptedProof)
+    );
+    let mut importer = Importer::new(*smt.root(), 3, 4);
+    assert_eq!(importer.add_chunk(chunk), Err(Error::CorruptedProof));
+}
+
+#[test]
+fn test_import_empty_tree() {
+    let smt = Smt::<29>::default();
+    assert!(chunks(&smt, 4).is_empty());
+    assert_eq!(smt.snapshot_chunk_count(4), Ok(0));
+    assert_eq!(smt.snapshot_chunks(0).err(), Some(Error::ZeroChunkSize));
+    assert_eq!(smt.snapshot_chunk_count(0), Err(Error::ZeroChunkSize));
+    let importer = Importer::new(H256::zero(), 0, 4);
+    assert!(importer.is_complete());
+    assert!(importer.finish().expect("finish").is_empty());
+}
+
+proptest! {
+    #[test]
+    fn test_import_chunks_in_any_order((pairs, _n) in leaves(1, 50), chunk_size in 1usize..10) {
+        let smt = new_smt::<29>(pairs);
+        let mut chunks = chunks(&smt, chunk_size);
+        chunks.shuffle(&mut rand::thread_rng());
+        let count = chunks.len();
+        prop_assert_eq!(smt.snapshot_chunk_count(chunk_size), Ok(count));
+        let mut importer = Importer::new(*smt.root(), count, chunk_size);
+        for (imported, chunk) in chunks.into_iter().enumerate() {
+            // chunks wait until a neighbour takes its place
+            prop_assert!(importer.missing_chunks().len() >= count - imported);
+            prop_assert!(verify(&chunk, smt.root()));
+            importer.add_chunk(chunk).expect("add chunk");
+        }
+        prop_assert!(importer.is_complete());
+        let imported = importer.finish().expect("finish");
+        prop_assert_eq!(imported.root(), smt.root());
+        prop_assert_eq!(imported.store().leaves_map(), smt.store().leaves_map());
+        prop_assert!(imported.validate());
+    }
+}
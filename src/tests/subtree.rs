@@ -0,0 +1,314 @@
+use super::padded_key::PaddedKey;
+use super::{leaves, new_sha_smt, new_smt, Smt};
+use crate::{
+    blake2b::Blake2bHasher,
+    error::Error,
+    hash_leaf,
+    merkle_proof::{EmptySubtreeProof, MerkleProof, SubtreeProof},
+    proof_ics23, H256,
+};
+use ics23::CommitmentProof;
+use proptest::prelude::*;
+
+fn verify(
+    proof: &SubtreeProof<PaddedKey<29>, H256>,
+    root: &H256,
+    prefix_key: &PaddedKey<29>,
+    height: usize,
+    subtree_root: &H256,
+) -> bool {
+    proof
+        .verify::<Blake2bHasher, 29>(root, prefix_key, height, subtree_root)
+        .expect("verify")
+}
+
+fn verify_empty(
+    proof: &EmptySubtreeProof<PaddedKey<29>, H256>,
+    root: &H256,
+    prefix_key: &PaddedKey<29>,
+    height: usize,
+) -> bool {
+    proof
+        .verify::<Blake2bHasher, 29>(root, prefix_key, height)
+        .expect("verify")
+}
+
+fn verify_empty_ics23(
+    proof: &CommitmentProof,
+    root: &H256,
+    prefix_key: &PaddedKey<29>,
+    height: usize,
+) -> bool {
+    let spec = proof_ics23::get_spec_for::<29>(ics23::HashOp::Sha256);
+    proof_ics23::verify_empty_subtree::<ics23::HostFunctionsManager, PaddedKey<29>, 29>(
+        proof,
+        &spec,
+        root.as_slice(),
+        prefix_key,
+        height,
+    )
+}
+
+#[test]
+fn test_subtree_of_whole_tree() {
+    let pairs: Vec<(PaddedKey<29>, H256)> = (1u8..=10)
+        .map(|i| ([i; 29].into(), [i; 32].into()))
+        .collect();
+    let smt = new_smt::<29>(pairs);
+    let key: PaddedKey<29> = [0xFF; 29].into();
+    let height = 8 * 29 - 1;
+    assert_eq!(&smt.subtree_root(&key, height).expect("root"), smt.root());
+    let proof = smt.subtree_proof(&key, height).expect("proof");
+    assert_eq!((proof.left, proof.right), (None, None));
+    assert!(verify(&proof, smt.root(), &key, height, smt.root()));
+
+    // keys 0x01.. to 0x03.. start with six zero bits
+    let key: PaddedKey<29> = [0u8; 29].into();
+    let expected = new_smt::<29>(
+        (1u8..=3)
+            .map(|i| ([i; 29].into(), [i; 32].into()))
+            .collect(),
+    );
+    let height = 8 * 29 - 7;
+    let subtree_root = smt.subtree_root(&key, height).expect("root");
+    assert_eq!(&subtree_root, expected.root());
+    let proof = smt.subtree_proof(&key, height).expect("proof");
+    assert!(verify(&proof, smt.root(), &key, height, &subtree_root));
+    assert!(!verify(&proof, smt.root(), &key, height, &[1u8; 32].into()));
+}
+
+#[test]
+fn test_empty_subtree() {
+    let smt = new_smt::<29>(vec![([1u8; 29].into(), [1u8; 32].into())]);
+    let key: PaddedKey<29> = [0x80; 29].into();
+    assert_eq!(smt.subtree_root(&key, 8).expect("root"), H256::zero());
+    assert_eq!(
+        smt.subtree_proof(&key, 8).err(),
+        Some(Error::ExistenceProof)
+    );
+    // a zero root places nowhere in the tree
+    let proof = smt.subtree_proof(&key, 8 * 29 - 1).expect("proof");
+    assert!(!verify(&proof, smt.root(), &key, 8, &H256::zero()));
+
+    let empty = Smt::<29>::default();
+    assert_eq!(empty.subtree_root(&key, 8).expect("root"), H256::zero());
+}
+
+#[test]
+fn test_empty_subtree_proofs() {
+    let pairs: Vec<(PaddedKey<29>, H256)> = [0x10u8, 0x20, 0x50, 0x60]
+        .iter()
+        .map(|i| ([*i; 29].into(), [*i; 32].into()))
+        .collect();
+    let smt = new_smt::<29>(pairs.clone());
+    let sha_smt = new_sha_smt::<29>(pairs.clone());
+    // keys starting with 0x3 or 0x4 lie between the second and third leaf
+    let key: PaddedKey<29> = [0x30; 29].into();
+    let height = 8 * 29 - 5;
+    let proof = smt.empty_subtree_proof(&key, height).expect("proof");
+    assert_eq!(proof.left, Some(pairs[1]));
+    assert_eq!(proof.right, Some(pairs[2]));
+    assert!(verify_empty(&proof, smt.root(), &key, height));
+    let commitment_proof = sha_smt
+        .empty_subtree_commitment_proof(&key, height)
+        .expect("proof");
+    assert!(verify_empty_ics23(
+        &commitment_proof,
+        sha_smt.root(),
+        &key,
+        height
+    ));
+    // the tree holds no leaf with a zero value
+    let mut zero = commitment_proof.clone();
+    if let Some(ics23::commitment_proof::Proof::Nonexist(non_existence)) = &mut zero.proof {
+        let left = non_existence.left.as_mut().expect("left");
+        left.value = vec![0; 32];
+    }
+    assert!(!verify_empty_ics23(&zero, sha_smt.root(), &key, height));
+
+    // the neighbours fall inside a larger prefix
+    let larger = 8 * 29 - 2;
+    assert!(!verify_empty(&proof, smt.root(), &key, larger));
+    assert!(!verify_empty_ics23(
+        &commitment_proof,
+        sha_smt.root(),
+        &key,
+        larger
+    ));
+    assert_eq!(
+        smt.empty_subtree_proof(&key, larger).err(),
+        Some(Error::NonExistenceProof)
+    );
+
+    // a neighbour further away hides the leaves in between
+    let mut hiding = proof.clone();
+    hiding.left = Some(pairs[0]);
+    hiding.proof = Some(
+        smt.merkle_proof(vec![pairs[0].0, pairs[2].0])
+            .expect("proof"),
+    );
+    assert!(!verify_empty(&hiding, smt.root(), &key, height));
+    // a missing neighbour claims the subtree is the last one
+    let mut hiding = proof;
+    hiding.right = None;
+    hiding.proof = Some(smt.merkle_proof(vec![pairs[1].0]).expect("proof"));
+    assert!(!verify_empty(&hiding, smt.root(), &key, height));
+
+    // keys before the first leaf
+    let key: PaddedKey<29> = [0u8; 29].into();
+    let proof = smt.empty_subtree_proof(&key, height).expect("proof");
+    assert_eq!((proof.left, proof.right), (None, Some(pairs[0])));
+    let commitment_proof = sha_smt
+        .empty_subtree_commitment_proof(&key, height)
+        .expect("proof");
+    assert!(verify_empty_ics23(
+        &commitment_proof,
+        sha_smt.root(),
+        &key,
+        height
+    ));
+
+    // an empty tree
+    let empty = Smt::<29>::default();
+    let proof = empty.empty_subtree_proof(&key, height).expect("proof");
+    assert!(verify_empty(&proof, &H256::zero(), &key, height));
+    assert!(!verify_empty(&proof, smt.root(), &key, height));
+    assert_eq!(
+        empty.empty_subtree_commitment_proof(&key, height).err(),
+        Some(Error::EmptyProof)
+    );
+}
+
+#[test]
+fn test_subtree_proof_binds_position() {
+    let pairs: Vec<(PaddedKey<29>, H256)> = [0x10u8, 0x20, 0x50, 0x60]
+        .iter()
+        .map(|i| ([*i; 29].into(), [*i; 32].into()))
+        .collect();
+    let smt = new_smt::<29>(pairs.clone());
+    // keys starting with 0x0 to 0x3 hold the first two leaves
+    let key: PaddedKey<29> = [0u8; 29].into();
+    let height = 8 * 29 - 3;
+    let subtree_root = smt.subtree_root(&key, height).expect("root");
+    let proof = smt.subtree_proof(&key, height).expect("proof");
+    assert_eq!((proof.first, proof.last), (pairs[0], pairs[1]));
+    assert_eq!((proof.left, proof.right), (None, Some(pairs[2])));
+    assert!(verify(&proof, smt.root(), &key, height, &subtree_root));
+
+    // an ancestor of the subtree
+    assert!(!verify(&proof, smt.root(), &key, height, smt.root()));
+    let larger = smt.subtree_proof(&key, 8 * 29 - 1).expect("proof");
+    assert!(!verify(&larger, smt.root(), &key, height, smt.root()));
+    // a descendant of the subtree
+    let leaf_key = pairs[0].0;
+    let leaf = smt.subtree_root(&leaf_key, 0).expect("root");
+    let smaller = smt.subtree_proof(&leaf_key, 0).expect("proof");
+    assert!(verify(&smaller, smt.root(), &leaf_key, 0, &leaf));
+    assert!(!verify(&smaller, smt.root(), &key, height, &leaf));
+    assert!(!verify(&proof, smt.root(), &key, height, &leaf));
+
+    // a last leaf inside the subtree hides the leaves after it
+    let mut hiding = proof.clone();
+    hiding.last = pairs[0];
+    hiding.proof = smt
+        .merkle_proof(vec![pairs[0].0, pairs[2].0])
+        .expect("proof");
+    assert!(!verify(&hiding, smt.root(), &key, height, &leaf));
+    // a missing neighbour claims the subtree is the last one
+    let mut hiding = proof;
+    hiding.right = None;
+    hiding.proof = smt
+        .merkle_proof(vec![pairs[0].0, pairs[1].0])
+        .expect("proof");
+    assert!(!verify(&hiding, smt.root(), &key, height, &subtree_root));
+    assert!(!verify(&hiding, smt.root(), &key, height, smt.root()));
+}
+
+#[test]
+fn test_zero_neighbours() {
+    let pairs: Vec<(PaddedKey<32>, H256)> = [0x10u8, 0x90]
+        .iter()
+        .map(|i| ([*i; 32].into(), [*i; 32].into()))
+        .collect();
+    let smt = new_smt::<32>(pairs.clone());
+    let leaf_hash =
+        |(k, v): &(PaddedKey<32>, H256)| hash_leaf::<Blake2bHasher, PaddedKey<32>, H256, 32>(k, v);
+    let mut right = [0u8; 32];
+    right[0] = 0x20;
+    let left: PaddedKey<32> = [0x01; 32].into();
+    let (key, height) = (pairs[0].0, 251);
+
+    // zero leaves hash to zero and merge away, the real leaves pass for
+    // siblings outside of the neighbours
+    let forged = EmptySubtreeProof {
+        left: Some((left, H256::zero())),
+        right: Some((right.into(), H256::zero())),
+        proof: Some(MerkleProof::new(
+            vec![vec![0, 253], vec![0, 253]],
+            vec![(leaf_hash(&pairs[0]), 0), (leaf_hash(&pairs[1]), 0)],
+        )),
+    };
+    assert_eq!(
+        forged.verify::<Blake2bHasher, 32>(smt.root(), &key, height),
+        Err(Error::CorruptedProof)
+    );
+
+    let subtree_root = smt.subtree_root(&key, height).expect("root");
+    let proof = smt.subtree_proof(&key, height).expect("proof");
+    assert!(proof
+        .verify::<Blake2bHasher, 32>(smt.root(), &key, height, &subtree_root)
+        .expect("verify"));
+    let mut forged = proof;
+    forged.left = Some((left, H256::zero()));
+    forged.proof = smt
+        .merkle_proof(vec![left, pairs[0].0, pairs[1].0])
+        .expect("proof");
+    assert_eq!(
+        forged.verify::<Blake2bHasher, 32>(smt.root(), &key, height, &subtree_root),
+        Err(Error::CorruptedProof)
+    );
+}
+
+proptest! {
+    #[test]
+    fn test_subtree_root_matches_leaves(
+        (pairs, n) in leaves(1, 50),
+        height in 0usize..8 * 29,
+        other_key: [u8; 29],
+    ) {
+        let smt = new_smt::<29>(pairs.clone());
+        for prefix_key in [pairs[n - 1].0, other_key.into()] {
+            let prefix = prefix_key.parent_path(height);
+            let expected = new_smt::<29>(
+                pairs
+                    .iter()
+                    .filter(|(k, _v)| k.parent_path(height) == prefix)
+                    .copied()
+                    .collect(),
+            );
+            let subtree_root = smt.subtree_root(&prefix_key, height).expect("root");
+            prop_assert_eq!(&subtree_root, expected.root());
+            if subtree_root.is_zero() {
+                let proof = smt.empty_subtree_proof(&prefix_key, height).expect("proof");
+                prop_assert!(verify_empty(&proof, smt.root(), &prefix_key, height));
+                let sha_smt = new_sha_smt::<29>(pairs.clone());
+                let proof = sha_smt
+                    .empty_subtree_commitment_proof(&prefix_key, height)
+                    .expect("proof");
+                prop_assert!(verify_empty_ics23(&proof, sha_smt.root(), &prefix_key, height));
+                continue;
+            }
+            prop_assert_eq!(
+                smt.empty_subtree_proof(&prefix_key, height).err(),
+                Some(Error::NonExistenceProof)
+            );
+            let proof = smt.subtree_proof(&prefix_key, height).expect("proof");
+            prop_assert!(verify(&proof, smt.root(), &prefix_key, height, &subtree_root));
+            // the root of the tree only verifies when it covers no more
+            prop_assert_eq!(
+                verify(&proof, smt.root(), &prefix_key, height, smt.root()),
+                &subtree_root == smt.root()
+            );
+        }
+    }
+}
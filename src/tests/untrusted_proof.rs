@@ -0,0 +1,183 @@
+use super::new_smt;
+use super::padded_key::PaddedKey;
+use crate::{
+    blake2b::Blake2bHasher,
+    error::Error,
+    merkle_proof::{CompiledMerkleProof, MerkleProof, COMPILED_PROOF_VERSION},
+    testing::verify_untrusted_proof,
+    H256,
+};
+use proptest::prelude::*;
+
+/// Inputs of `verify_untrusted_proof` that used to panic, see the fuzz
+/// target in `fuzz/`
+const CORPUS: &[&str] = &[
+    // a legacy merge with a truncated height
+    "01074c480000",
+    // a legacy sibling above the root
+    "01074c50000000000000012c0101010101010101010101010101010101010101010101010101010101010101",
+    // a merge above the root
+    "020708024c4c48ac02",
+    // a leaf path without a sibling
+    "0107010101000000010000000500000000",
+    // a leaf path above the root
+    "0107010201000000010000002c01010000002c010101010101010101010101010101010101010101010101010101010101010101",
+    // the same leaf twice
+    "020707010102000000000000000000000000000000",
+];
+
+fn leaf(i: u8) -> (PaddedKey<29>, H256) {
+    ([i; 29].into(), [i; 32].into())
+}
+
+fn compute_root(proof: MerkleProof, leaves: Vec<(PaddedKey<29>, H256)>) -> Result<H256, Error> {
+    proof.compute_root::<Blake2bHasher, PaddedKey<29>, H256, 29>(leaves)
+}
+
+fn compute_compiled_root(
+    program: Vec<u8>,
+    leaves: Vec<(PaddedKey<29>, H256)>,
+) -> Result<H256, Error> {
+    CompiledMerkleProof(program).compute_root::<Blake2bHasher, PaddedKey<29>, H256, 29>(leaves)
+}
+
+#[test]
+fn test_regression_corpus() {
+    for input in CORPUS {
+        verify_untrusted_proof(&hex::decode(input).expect("hex"));
+    }
+}
+
+#[test]
+fn test_corrupted_merkle_proofs() {
+    let sibling: H256 = [1u8; 32].into();
+    for (proof, leaves) in [
+        // a missing sibling
+        (
+            MerkleProof::new(vec![vec![5], vec![5]], vec![(sibling, 5)]),
+            vec![leaf(1), leaf(2)],
+        ),
+        // heights of the path and the sibling differ
+        (
+            MerkleProof::new(vec![vec![5]], vec![(sibling, 6)]),
+            vec![leaf(1)],
+        ),
+        // heights going down
+        (
+            MerkleProof::new(vec![vec![5, 3]], vec![(sibling, 5), (sibling, 3)]),
+            vec![leaf(1)],
+        ),
+        // heights above the root
+        (
+            MerkleProof::new(vec![vec![8 * 29]], vec![(sibling, 8 * 29)]),
+            vec![leaf(1)],
+        ),
+        (
+            MerkleProof::new(vec![vec![usize::MAX]], vec![(sibling, usize::MAX)]),
+            vec![leaf(1)],
+        ),
+    ] {
+        assert_eq!(
+            compute_root(proof.clone(), leaves.clone()),
+            Err(Error::CorruptedProof)
+        );
+        assert_eq!(
+            proof
+                .compile_keys(leaves.iter().map(|(k, _v)| *k).collect())
+                .err(),
+            Some(Error::CorruptedProof)
+        );
+    }
+
+    for height in [8 * 29, 300] {
+        let mut program = vec![COMPILED_PROOF_VERSION, 0x4C, 0x50];
+        program.push(height as u8 | 0x80);
+        program.push((height >> 7) as u8);
+        program.extend_from_slice(sibling.as_slice());
+        assert_eq!(
+            compute_compiled_root(program, vec![leaf(1)]),
+            Err(Error::CorruptedProof)
+        );
+    }
+}
+
+#[test]
+fn test_duplicate_keys() {
+    let smt = new_smt::<29>(vec![leaf(1), leaf(2)]);
+    let proof = smt.merkle_proof(vec![leaf(1).0]).expect("proof");
+    let compiled = proof
+        .clone()
+        .compile_keys(vec![leaf(1).0])
+        .expect("compile");
+    let duplicated = vec![leaf(1), leaf(1)];
+    let forged = MerkleProof::new(vec![vec![], vec![]], vec![]);
+    assert_eq!(
+        compute_root(forged.clone(), duplicated.clone()),
+        Err(Error::DuplicateKeys)
+    );
+    assert_eq!(
+        forged
+            .clone()
+            .compute_contiguous_root::<Blake2bHasher, PaddedKey<29>, H256, 29>(
+                duplicated.clone(),
+                false,
+                false
+            ),
+        Err(Error::DuplicateKeys)
+    );
+    assert_eq!(
+        forged
+            .compile_keys(duplicated.iter().map(|(k, _v)| *k).collect())
+            .err(),
+        Some(Error::DuplicateKeys)
+    );
+    assert_eq!(
+        compute_compiled_root(compiled.0, duplicated),
+        Err(Error::DuplicateKeys)
+    );
+}
+
+proptest! {
+    #[test]
+    fn test_random_bytes(data: Vec<u8>) {
+        verify_untrusted_proof(&data);
+    }
+
+    #[test]
+    fn test_random_programs(
+        code in prop::collection::vec(prop::sample::select(vec![0x4Cu8, 0x50, 0x48]), 1..8),
+        operands: Vec<u8>,
+        version: bool,
+    ) {
+        let mut program = Vec::new();
+        if version {
+            program.push(COMPILED_PROOF_VERSION);
+        }
+        for (i, code) in code.into_iter().enumerate() {
+            program.push(code);
+            program.extend(operands.iter().skip(i * 3).take(3));
+        }
+        let _ = compute_compiled_root(program, vec![leaf(1), leaf(2)]);
+    }
+
+    #[test]
+    fn test_random_merkle_proofs(
+        leaves_path in prop::collection::vec(prop::collection::vec(0usize..300, 0..4), 1..4),
+        heights in prop::collection::vec(0usize..300, 0..8),
+    ) {
+        let leaves: Vec<_> = (1..=leaves_path.len() as u8).map(leaf).collect();
+        let proof = MerkleProof::new(
+            leaves_path,
+            heights.into_iter().map(|height| ([1u8; 32].into(), height)).collect(),
+        );
+        let _ = compute_root(proof.clone(), leaves.clone());
+        let _ = proof
+            .clone()
+            .compute_contiguous_root::<Blake2bHasher, PaddedKey<29>, H256, 29>(
+                leaves.clone(),
+                true,
+                true,
+            );
+        let _ = proof.compile_keys(leaves.iter().map(|(k, _v)| *k).collect());
+    }
+}
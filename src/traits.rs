@@ -1,8 +1,11 @@
 use crate::{
+    borrow::Cow,
     error::Error,
     tree::{BranchNode, LeafNode},
+    vec::Vec,
     Hash as KeyHash, InternalKey, H256,
 };
+use core::future::Future;
 use core::hash::Hash;
 use core::ops::Deref;
 
@@ -44,7 +47,7 @@ impl Key<32> for KeyHash {
     }
 
     fn try_from_bytes(bytes: &[u8]) -> Result<Self, Self::Error> {
-        use std::convert::TryInto;
+        use core::convert::TryInto;
         let bytes: [u8; 32] = bytes
             .try_into()
             .map_err(|_| crate::error::Error::KeyTooLarge)?;
@@ -70,6 +73,19 @@ impl Value for H256 {
     }
 }
 
+/// `Send + Sync` with the `parallel` feature, so that subtrees can be hashed
+/// on other threads, and implemented by every type otherwise
+#[cfg(feature = "parallel")]
+pub trait MaybeSync: Send + Sync {}
+#[cfg(feature = "parallel")]
+impl<T: Send + Sync> MaybeSync for T {}
+/// `Send + Sync` with the `parallel` feature, so that subtrees can be hashed
+/// on other threads, and implemented by every type otherwise
+#[cfg(not(feature = "parallel"))]
+pub trait MaybeSync {}
+#[cfg(not(feature = "parallel"))]
+impl<T> MaybeSync for T {}
+
 /// Trait for customize backend storage
 pub trait Store<K, V, const N: usize>: Default
 where
@@ -77,11 +93,55 @@ where
 {
     fn get_branch(&self, node: &H256) -> Result<Option<BranchNode<K, N>>, Error>;
     fn get_leaf(&self, leaf_key: &H256) -> Result<Option<LeafNode<K, V, N>>, Error>;
+    /// Like `get_branch`, but stores that own their nodes may hand out a
+    /// reference instead of a copy
+    fn get_branch_ref(&self, node: &H256) -> Result<Option<Cow<'_, BranchNode<K, N>>>, Error> {
+        Ok(self.get_branch(node)?.map(Cow::Owned))
+    }
+    /// Like `get_leaf`, but stores that own their nodes may hand out a
+    /// reference instead of a copy
+    fn get_leaf_ref(&self, leaf_key: &H256) -> Result<Option<Cow<'_, LeafNode<K, V, N>>>, Error>
+    where
+        V: Clone,
+    {
+        Ok(self.get_leaf(leaf_key)?.map(Cow::Owned))
+    }
     fn insert_branch(&mut self, node: H256, branch: BranchNode<K, N>) -> Result<(), Error>;
     fn insert_leaf(&mut self, leaf_key: H256, leaf: LeafNode<K, V, N>) -> Result<(), Error>;
     fn remove_branch(&mut self, node: &H256) -> Result<(), Error>;
     fn remove_leaf(&mut self, leaf_key: &H256) -> Result<(), Error>;
     fn sorted_leaves<'a>(&'a self) -> impl Iterator<Item=(K, &'a V)> where V: 'a;
     fn size(&self) -> usize;
+    /// Number of nodes `get_branch` finds a branch for
+    fn branch_count(&self) -> usize;
 }
 
+/// Trait for customize backend storage behind async I/O
+///
+/// Mirrors the node operations of `Store` for `AsyncSparseMerkleTree`, which
+/// shares its traversals with `SparseMerkleTree`.
+pub trait AsyncStore<K, V, const N: usize>
+where
+    K: Key<N>,
+{
+    fn get_branch(
+        &self,
+        node: &H256,
+    ) -> impl Future<Output = Result<Option<BranchNode<K, N>>, Error>> + Send;
+    fn get_leaf(
+        &self,
+        leaf_key: &H256,
+    ) -> impl Future<Output = Result<Option<LeafNode<K, V, N>>, Error>> + Send;
+    fn insert_branch(
+        &mut self,
+        node: H256,
+        branch: BranchNode<K, N>,
+    ) -> impl Future<Output = Result<(), Error>> + Send;
+    fn insert_leaf(
+        &mut self,
+        leaf_key: H256,
+        leaf: LeafNode<K, V, N>,
+    ) -> impl Future<Output = Result<(), Error>> + Send;
+    fn remove_branch(&mut self, node: &H256) -> impl Future<Output = Result<(), Error>> + Send;
+    fn remove_leaf(&mut self, leaf_key: &H256) -> impl Future<Output = Result<(), Error>> + Send;
+}
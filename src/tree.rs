@@ -1,18 +1,33 @@
 use crate::{
+    batch::{self, Item},
+    borrow::Cow,
+    changeset::{self, ChangeSet},
     collections::{BTreeMap, VecDeque},
     error::{Error, Result},
     merge::{hash_leaf, merge},
-    merkle_proof::MerkleProof,
+    merkle_proof::{
+        check_unique_keys, CompiledMerkleProof, CompiledMerkleProofBuilder, EmptySubtreeProof,
+        Instruction, MerkleProof, RangeProof, SubtreeProof,
+    },
     proof_ics23,
-    traits::{Hasher, Store, Value},
+    string::ToString,
+    traits::{Hasher, MaybeSync, Store, Value},
+    vec,
     vec::Vec,
     Key, InternalKey, EXPECTED_PATH_SIZE, H256,
 };
 #[cfg(feature = "borsh")]
 use borsh::{BorshDeserialize, BorshSerialize};
-use core::{cmp::max, marker::PhantomData};
+use core::{
+    cmp::{max, min},
+    future::Future,
+    marker::PhantomData,
+    ops::{Deref, Range},
+    pin::pin,
+    task::{Context, Poll, Waker},
+};
 use ics23::commitment_proof::Proof;
-use ics23::{CommitmentProof, NonExistenceProof};
+use ics23::{CommitmentProof, ExistenceProof, NonExistenceProof};
 use itertools::Itertools;
 
 /// A branch in the SMT
@@ -32,7 +47,7 @@ impl<K, const N: usize> BranchNode<K, N>
 where
     K: Key<N>,
 {
-    fn branch(&self, height: usize) -> (&H256, &H256) {
+    pub(crate) fn branch(&self, height: usize) -> (&H256, &H256) {
         let is_right = self.key.get_bit(height);
         if is_right {
             (&self.sibling, &self.node)
@@ -40,6 +55,66 @@ where
             (&self.node, &self.sibling)
         }
     }
+
+    /// Check if the branch stands for the leaf `node`
+    pub(crate) fn is_leaf(&self, node: &H256) -> bool {
+        self.fork_height == 0 && &self.node == node
+    }
+
+    /// Check if the subtree of the branch `node` contains the one of the
+    /// branch `other_node` below its root
+    ///
+    /// The subtree of an inner branch holds the keys that share its bits
+    /// above the fork height.
+    pub(crate) fn covers(&self, node: &H256, other: &BranchNode<K, N>, other_node: &H256) -> bool {
+        let height = self.fork_height;
+        !self.is_leaf(node)
+            && (other.is_leaf(other_node) || other.fork_height < height)
+            && other.key.parent_path(height) == self.key.parent_path(height)
+    }
+
+    /// Check that the branch `node` lies below the `parent` branch
+    ///
+    /// `parent` holds the key and fork height of the parent branch, and the
+    /// side of the parent `node` hangs off.
+    pub(crate) fn check_position(&self, node: &H256, parent: (&K, usize, bool)) -> Result<()> {
+        let (parent_key, parent_height, is_right) = parent;
+        if (!self.is_leaf(node) && self.fork_height >= parent_height)
+            || self.key.parent_path(parent_height) != parent_key.parent_path(parent_height)
+            || self.key.get_bit(parent_height) != is_right
+        {
+            return Err(Error::InvalidBranch(*node));
+        }
+        Ok(())
+    }
+
+    /// Check the leaf branch `node` against its leaf
+    pub(crate) fn check_leaf<H, V>(&self, node: &H256, leaf: &LeafNode<K, V, N>) -> Result<()>
+    where
+        H: Hasher + Default,
+        V: Value,
+    {
+        if !self.sibling.is_zero() || *leaf.key != *self.key {
+            return Err(Error::InvalidBranch(*node));
+        }
+        if hash_leaf::<H, K, V, N>(&leaf.key, &leaf.value) != *node {
+            return Err(Error::LeafHashMismatch(*node));
+        }
+        Ok(())
+    }
+
+    /// Check that the children of the inner branch `node` hash to it
+    pub(crate) fn check_children<H: Hasher + Default>(&self, node: &H256) -> Result<()> {
+        // a zero child would collapse the branch into the other one
+        if self.node.is_zero() || self.sibling.is_zero() {
+            return Err(Error::InvalidBranch(*node));
+        }
+        let (left, right) = self.branch(self.fork_height);
+        if merge::<H>(left, right) != *node {
+            return Err(Error::BranchHashMismatch(*node));
+        }
+        Ok(())
+    }
 }
 
 /// A leaf in the SMT
@@ -53,6 +128,17 @@ where
     pub value: V,
 }
 
+/// A leaf that differs between two roots, see `SparseMerkleTree::diff`
+#[derive(Debug, Eq, PartialEq, Clone)]
+pub enum Diff<K, V> {
+    /// The key only exists under the second root
+    Added(K, V),
+    /// The key only exists under the first root
+    Removed(K, V),
+    /// The key exists under both roots, with the old and the new value
+    Modified(K, V, V),
+}
+
 /// Sparse merkle tree
 #[derive(Debug)]
 pub struct SparseMerkleTree<H, K, V, S, const N: usize>
@@ -64,6 +150,8 @@ where
 {
     store: S,
     root: H256,
+    /// Writes recorded since `start_recording`
+    changes: Option<ChangeSet<K, V, N>>,
     phantom: PhantomData<(H, K, V)>,
 }
 
@@ -91,10 +179,67 @@ where
         SparseMerkleTree {
             root,
             store,
+            changes: None,
             phantom: PhantomData,
         }
     }
 
+    /// Build a merkle tree from root and an untrusted store
+    ///
+    /// Walks the whole tree under `root` and checks the hash of every
+    /// branch and leaf, that every node sits where its key belongs and that
+    /// the store holds no leaves or branches outside the tree, before
+    /// returning it.
+    pub fn load_verified(root: H256, store: S) -> Result<Self> {
+        let tree = Self::new(root, store);
+        let reached = if root.is_zero() {
+            0
+        } else {
+            tree.verify_node(root, None)?
+        };
+        if reached != tree.store.size() {
+            return Err(Error::IncorrectNumberOfLeaves {
+                expected: reached,
+                actual: tree.store.size(),
+            });
+        }
+        // every branch above the leaves has two children
+        let branches = (2 * reached).saturating_sub(1);
+        if branches != tree.store.branch_count() {
+            return Err(Error::IncorrectNumberOfBranches {
+                expected: branches,
+                actual: tree.store.branch_count(),
+            });
+        }
+        Ok(tree)
+    }
+
+    /// Check the subtree under `node`, return the number of leaves in it
+    ///
+    /// `parent` is the position of `node`, see `BranchNode::check_position`.
+    fn verify_node(&self, node: H256, parent: Option<(&K, usize, bool)>) -> Result<usize> {
+        let branch = self
+            .store
+            .get_branch_ref(&node)?
+            .ok_or(Error::MissingBranch(node))?;
+        if let Some(parent) = parent {
+            branch.check_position(&node, parent)?;
+        }
+        if branch.is_leaf(&node) {
+            let leaf = self
+                .store
+                .get_leaf_ref(&node)?
+                .ok_or(Error::MissingLeaf(node))?;
+            branch.check_leaf::<H, V>(&node, &leaf)?;
+            return Ok(1);
+        }
+        branch.check_children::<H>(&node)?;
+        let (left, right) = branch.branch(branch.fork_height);
+        let left_leaves = self.verify_node(*left, Some((&branch.key, branch.fork_height, false)))?;
+        let right_leaves = self.verify_node(*right, Some((&branch.key, branch.fork_height, true)))?;
+        Ok(left_leaves + right_leaves)
+    }
+
     /// Merkle root
     pub fn root(&self) -> &H256 {
         &self.root
@@ -120,22 +265,786 @@ where
         &mut self.store
     }
 
+    /// Record the store writes of the following updates into a `ChangeSet`
+    ///
+    /// Writes made through `store_mut` are not recorded.
+    pub fn start_recording(&mut self) {
+        self.changes = Some(ChangeSet::new(self.root));
+    }
+
+    /// Take the changes recorded so far and keep recording from the
+    /// current root, return none when not recording
+    pub fn take_changeset(&mut self) -> Option<ChangeSet<K, V, N>> {
+        let changes = self.changes.as_mut()?;
+        let mut taken = core::mem::replace(changes, ChangeSet::new(self.root));
+        taken.new_root = self.root;
+        Some(taken)
+    }
+
+    /// Stop recording, return the changes recorded since the last take
+    pub fn stop_recording(&mut self) -> Option<ChangeSet<K, V, N>> {
+        let mut taken = self.changes.take()?;
+        taken.new_root = self.root;
+        Some(taken)
+    }
+
+    /// Replay a change set recorded on another tree, return new merkle root
+    ///
+    /// The tree has to be at the old root of the change set. The writes are
+    /// checked on an overlay of the store first, and the change set is
+    /// refused unless they leave a valid tree under its new root.
+    pub fn apply_changeset(&mut self, changeset: ChangeSet<K, V, N>) -> Result<&H256> {
+        if self.root != changeset.old_root {
+            return Err(Error::RootMismatch {
+                expected: changeset.old_root,
+                actual: self.root,
+            });
+        }
+        changeset::verify::<H, K, V, S, N>(&self.store, &changeset)?;
+        // the writes come from elsewhere, so read what each one replaces
+        let writes = changeset
+            .ops
+            .into_iter()
+            .map(|op| {
+                let undo = self.undo_op(&op)?;
+                Ok(Reversible { op, undo })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        self.apply_ops(writes)?;
+        self.root = changeset.new_root;
+        Ok(&self.root)
+    }
+
+    /// Write to the store, and record the writes if recording
+    ///
+    /// A failing write is undone along with the writes before it, see
+    /// `write_all`, so on error the store still holds the tree under the
+    /// current root and nothing is recorded.
+    fn apply_ops(&mut self, writes: Vec<Reversible<K, V, N>>) -> Result<()> {
+        let applied: Option<Vec<_>> = self
+            .changes
+            .as_ref()
+            .map(|_| writes.iter().map(|write| write.op.clone()).collect());
+        resolve(write_all(&mut StoreWriter(&mut self.store), writes))?;
+        if let (Some(changes), Some(applied)) = (self.changes.as_mut(), applied) {
+            changes.ops.extend(applied);
+        }
+        Ok(())
+    }
+
+    /// The write that restores the node `op` replaces, none if `op` removes
+    /// a missing node
+    ///
+    /// The undos are read before the first write. Of several writes to the
+    /// same node, only the undo of the first sees what the node held, and
+    /// it is the last one to run.
+    fn undo_op(&self, op: &StoreOp<K, V, N>) -> Result<Option<StoreOp<K, V, N>>> {
+        let undo = match op {
+            StoreOp::InsertBranch(node, _) | StoreOp::RemoveBranch(node) => {
+                match self.store.get_branch_ref(node)? {
+                    Some(branch) => Some(StoreOp::InsertBranch(*node, branch.into_owned())),
+                    None if matches!(op, StoreOp::InsertBranch(..)) => {
+                        Some(StoreOp::RemoveBranch(*node))
+                    }
+                    None => None,
+                }
+            }
+            StoreOp::InsertLeaf(leaf_hash, _) | StoreOp::RemoveLeaf(leaf_hash) => {
+                match self.store.get_leaf_ref(leaf_hash)? {
+                    Some(leaf) => Some(StoreOp::InsertLeaf(*leaf_hash, leaf.into_owned())),
+                    None if matches!(op, StoreOp::InsertLeaf(..)) => {
+                        Some(StoreOp::RemoveLeaf(*leaf_hash))
+                    }
+                    None => None,
+                }
+            }
+        };
+        Ok(undo)
+    }
+
     /// Update a leaf, return new merkle root
     /// set to zero value to delete a key
+    ///
+    /// The new root and the writes are worked out before the first write,
+    /// and a failing write is undone, see `apply_ops`, so on error both the
+    /// store and the root are left untouched.
     pub fn update(&mut self, key: K, value: V) -> Result<&H256> {
+        let (root, writes) = resolve(self.view().plan_update(key, value))?;
+        self.apply_ops(writes)?;
+        self.root = root;
+        Ok(&self.root)
+    }
+
+    /// Get value of a leaf
+    /// return zero value if leaf not exists
+    pub fn get(&self, key: &K) -> Result<V> {
+        resolve(self.view().get(key))
+    }
+
+    /// Get the values of several leaves at once, in the order of `keys`
+    ///
+    /// Equivalent to calling `get` for every key, but the keys share a
+    /// single walk from the root and split up where their paths diverge.
+    pub fn get_many(&self, keys: &[K]) -> Result<Vec<V>> {
+        resolve(self.view().get_many(keys))
+    }
+
+    /// Generate merkle proof
+    pub fn merkle_proof(&self, keys: Vec<K>) -> Result<MerkleProof> {
+        resolve(self.view().merkle_proof(keys))
+    }
+
+    /// Generate compiled merkle proof, without building the merkle proof
+    /// first
+    pub fn compiled_merkle_proof(&self, keys: Vec<K>) -> Result<CompiledMerkleProof> {
+        resolve(self.view().compiled_merkle_proof(keys))
+    }
+
+    /// Generate ICS 23 commitment proof for the existing key
+    pub fn membership_proof(&self, key: &K) -> Result<CommitmentProof> {
+        resolve(self.view().membership_proof(key))
+    }
+
+    /// Generate ICS 23 commitment proof for the non-existing key
+    pub fn non_membership_proof(&self, key: &K) -> Result<CommitmentProof> {
+        resolve(self.view().non_membership_proof(key))
+    }
+
+    /// Get the root of the subtree covering `prefix_key.parent_path(height)`
+    /// return zero if the subtree holds no leaves
+    pub fn subtree_root(&self, prefix_key: &K, height: usize) -> Result<H256> {
+        resolve(self.view().subtree(prefix_key, height))
+    }
+
+    /// Generate proof of the root of the subtree covering
+    /// `prefix_key.parent_path(height)`
+    ///
+    /// Returns ExistenceProof error when the subtree holds no leaves.
+    pub fn subtree_proof(&self, prefix_key: &K, height: usize) -> Result<SubtreeProof<K, V>> {
+        resolve(self.view().subtree_proof(prefix_key, height))
+    }
+
+    /// Generate proof that no leaf lies in the subtree covering
+    /// `prefix_key.parent_path(height)`
+    ///
+    /// The proof holds the leaves right before and after the subtree.
+    /// Returns NonExistenceProof error when the subtree holds leaves.
+    pub fn empty_subtree_proof(
+        &self,
+        prefix_key: &K,
+        height: usize,
+    ) -> Result<EmptySubtreeProof<K, V>> {
+        resolve(self.view().empty_subtree_proof(prefix_key, height))
+    }
+
+    /// Generate ICS 23 commitment proof that no leaf lies in the subtree
+    /// covering `prefix_key.parent_path(height)`, see
+    /// `proof_ics23::verify_empty_subtree`
+    ///
+    /// Returns NonExistenceProof error when the subtree holds leaves, and
+    /// EmptyProof error for an empty tree.
+    pub fn empty_subtree_commitment_proof(
+        &self,
+        prefix_key: &K,
+        height: usize,
+    ) -> Result<CommitmentProof> {
+        resolve(self.view().empty_subtree_commitment_proof(prefix_key, height))
+    }
+
+    /// Generate proof of the leaves with keys in `start..end`
+    ///
+    /// The proof holds the leaves in the range, and the leaves right before
+    /// and after them, so that it shows no leaf of the range was left out.
+    ///
+    /// Returns InvalidRange error if `start` is greater than `end`.
+    pub fn range_proof(&self, start: &K, end: &K) -> Result<RangeProof<K, V>> {
+        resolve(self.view().range_proof(start, end))
+    }
+
+    /// List the leaves that differ between `root_a` and `root_b` in key
+    /// order
+    ///
+    /// Both trees are read from the store, which has to hold the nodes of
+    /// both roots. Subtrees with the same hash under both roots are skipped.
+    pub fn diff(&self, root_a: H256, root_b: H256) -> Result<Vec<Diff<K, V>>> {
+        resolve(self.view_at(root_a).diff(root_b))
+    }
+
+    /// Read-only access to the tree under the current root
+    fn view(&self) -> TreeView<H, K, V, StoreReader<'_, S>, N> {
+        self.view_at(self.root)
+    }
+
+    /// Read-only access to the tree under `root`, which the store must
+    /// still hold the nodes of
+    pub(crate) fn view_at(&self, root: H256) -> TreeView<H, K, V, StoreReader<'_, S>, N> {
+        TreeView::new(StoreReader(&self.store), root)
+    }
+
+    /// Recompute the root of the merkle tree from the store. Check if it agrees with the
+    /// root in `self`.
+    ///
+    /// With the `parallel` feature, disjoint subtrees are hashed on the rayon
+    /// thread pool.
+    pub fn validate(&self) -> bool
+    where
+        K: MaybeSync,
+        V: MaybeSync,
+    {
+        if cfg!(feature = "parallel") {
+            let mut items: Vec<Item<K, V, N>> = self
+                .store
+                .sorted_leaves()
+                .map(|(k, v)| Item::subtree(*k, k, hash_leaf::<H, K, V, N>(&k, v)))
+                .collect();
+            return batch::build::<H, K, V, N>(&mut items, false).0 == self.root;
+        }
+
+        // handle case when tree is empty
+        if self.store.size() == 0 {
+            return self.root == H256::zero()
+        }
+
+        let sorted_leaves = self.store
+            .sorted_leaves()
+            .map(|(k, v)| (k, v.clone()))
+            .collect::<Vec<_>>();
+        // iterator over consecutive pairs of leaves
+        let pairs = sorted_leaves
+            .iter()
+            .tuple_windows::<(_, _)>();
+
+        // construct a vector of nodes and distance to next node
+        let mut leaves = Vec::with_capacity(self.store.size());
+        for ((k1, v1), (k2, _)) in pairs {
+            let height = k1.fork_height(k2);
+            let hash = hash_leaf::<H, K, V, N>(k1, v1);
+            leaves.push((hash, height));
+        }
+        let (last_k, last_v) = sorted_leaves
+            .last()
+            .map(|(k, v)| (k, v))
+            .unwrap();
+        let last = hash_leaf::<H, K, V, N>(last_k, last_v);
+        if leaves.is_empty() {
+            return self.root == last;
+        }
+        leaves.push((last, usize::MAX));
+
+        let mut left: usize = 0;
+        let mut right: usize = 1;
+        let mut merged = Default::default();
+
+        // stack of previous `left` indexes that are yet to be merged
+        let mut prev: Vec<usize> = Vec::with_capacity(leaves.len() / 2);
+
+        // Iterate finding the first node `left` such that `left+1` (`right`) is
+        // its closest neighbor and vice versa, merging them until a single node
+        // remains.
+        while right < leaves.len() {
+            if leaves[left].1 < leaves[right].1 {
+                loop {
+                    // perform merge
+                    merged = merge::<H>(&leaves[left].0, &leaves[right].0);
+                    leaves[right].0 = merged;
+
+                    // check previous `left` node next (if present)
+                    match prev.last() {
+                        Some(&idx) if leaves[idx].1 < leaves[right].1 => {
+                            left = idx;
+                            _ = prev.pop();
+                            continue;
+                        }
+                        _ => {
+                            break;
+                        }
+                    }
+                }
+            } else {
+                prev.push(left);
+            }
+            left = right;
+            right += 1;
+        }
+        // check that the recovered root matches the precomputed one
+        merged == self.root
+    }
+}
+
+impl<H, K, V, S, const N: usize> SparseMerkleTree<H, K, V, S, N>
+where
+    H: Hasher + Default,
+    K: Key<N> + MaybeSync,
+    V: Value + core::cmp::PartialEq + MaybeSync,
+    S: Store<K, V, N>,
+{
+    /// Update several leaves at once, return new merkle root
+    /// set a value to zero to delete its key
+    ///
+    /// Gives the same root as calling `update` for each pair in turn, so the
+    /// last value of a repeated key wins. The part of the tree the batch
+    /// touches is rebuilt bottom-up, which on an empty tree is a bulk
+    /// construction. With the `parallel` feature, disjoint subtrees are
+    /// hashed on the rayon thread pool. Like in `update`, an error leaves
+    /// both the store and the root untouched.
+    pub fn update_all(&mut self, mut leaves: Vec<(K, V)>) -> Result<&H256> {
+        // stable sort, a repeated key keeps its last value
+        leaves.sort_by_key(|(k, _v)| **k);
+        let mut batch: Vec<(K, V)> = Vec::with_capacity(leaves.len());
+        for (key, value) in leaves {
+            match batch.last_mut() {
+                Some(last) if *last.0 == *key => last.1 = value,
+                _ => batch.push((key, value)),
+            }
+        }
+
+        let mut subtrees = Vec::new();
+        let mut removals = Vec::new();
+        self.collect_subtrees(self.root, &batch, &mut subtrees, &mut removals)?;
+        let mut items: Vec<_> = subtrees
+            .into_iter()
+            .merge_by(
+                batch.into_iter().map(|(k, v)| Item::leaf(k, v)),
+                |a, b| a.path() < b.path(),
+            )
+            .collect();
+        let (root, writes) = batch::build::<H, K, V, N>(&mut items, true);
+
+        // apply the changes to the store
+        removals.extend(writes.into_iter().map(Reversible::on_missing));
+        self.apply_ops(removals)?;
+        self.root = root;
+        Ok(&self.root)
+    }
+
+    /// Walk down from `node` with the keys of the sorted `batch` that may
+    /// lie below it. Subtrees none of the keys reach are collected in key
+    /// order, the nodes the keys pass through or replace become stale and
+    /// their removals are collected.
+    fn collect_subtrees(
+        &self,
+        node: H256,
+        batch: &[(K, V)],
+        subtrees: &mut Vec<Item<K, V, N>>,
+        removals: &mut Vec<Reversible<K, V, N>>,
+    ) -> Result<()> {
+        if node.is_zero() {
+            return Ok(());
+        }
+        let branch = self
+            .store
+            .get_branch_ref(&node)?
+            .ok_or_else(|| Error::Store("missing branch of a non-zero node".to_string()))?;
+        if branch.fork_height == 0 && branch.node == node {
+            // the node is a leaf
+            if batch
+                .binary_search_by_key(&*branch.key, |(k, _v)| **k)
+                .is_ok()
+            {
+                let leaf = self.store.get_leaf_ref(&node)?.map(Cow::into_owned);
+                removals.push(Reversible::remove_leaf(node, leaf));
+                removals.push(Reversible::remove_branch(node, Some(branch.into_owned())));
+            } else {
+                subtrees.push(Item::subtree(*branch.key, branch.key, node));
+            }
+            return Ok(());
+        }
+
+        let fork_height = branch.fork_height;
+        // keys below the branch share its bits above the fork height
+        let prefix = branch.key.parent_path(fork_height);
+        let start = batch.partition_point(|(k, _v)| k.parent_path(fork_height) < prefix);
+        let end = batch.partition_point(|(k, _v)| k.parent_path(fork_height) <= prefix);
+        let batch = &batch[start..end];
+        if batch.is_empty() {
+            subtrees.push(Item::subtree(*branch.key, branch.key, node));
+            return Ok(());
+        }
+
+        removals.push(Reversible::remove_branch(node, Some(BranchNode::clone(&branch))));
+        let (left, right) = branch.branch(fork_height);
+        let split = batch.partition_point(|(k, _v)| !k.get_bit(fork_height));
+        for (child, batch, is_right) in [
+            (*left, &batch[..split], false),
+            (*right, &batch[split..], true),
+        ] {
+            if !batch.is_empty() {
+                self.collect_subtrees(child, batch, subtrees, removals)?;
+            } else if !child.is_zero() {
+                let mut path = prefix;
+                if is_right {
+                    path.set_bit(fork_height);
+                }
+                subtrees.push(Item::subtree(path, branch.key, child));
+            }
+        }
+        Ok(())
+    }
+
+    /// Merge the leaves of `other` into the tree, return new merkle root
+    ///
+    /// Both trees are walked down together. A subtree only one of them has
+    /// leaves in is reused as a whole, copying its nodes over from the store
+    /// of `other` if needed, and only the branches above those subtrees are
+    /// rebuilt. Returns ConflictingLeaf error when a key has different
+    /// values in the two trees, in which case nothing is written.
+    pub fn merge_from<O>(&mut self, other: &SparseMerkleTree<H, K, V, O, N>) -> Result<&H256>
+    where
+        O: Store<K, V, N>,
+    {
+        let mut items = Vec::new();
+        let mut removals = Vec::new();
+        let mut copies = Vec::new();
+        self.collect_merged(
+            other.store(),
+            (self.root, *other.root()),
+            &mut items,
+            &mut removals,
+            &mut copies,
+        )?;
+        let (root, writes) = batch::build::<H, K, V, N>(&mut items, true);
+
+        removals.extend(copies.into_iter().chain(writes).map(Reversible::on_missing));
+        self.apply_ops(removals)?;
+        self.root = root;
+        Ok(&self.root)
+    }
+
+    /// Walk down from the node `a` of the tree and the node `b` of `other`
+    /// together. Subtrees with leaves of only one of them are collected in
+    /// key order, the branches of the tree whose subtree gains leaves become
+    /// stale and their removals are collected, and the nodes of reused
+    /// subtrees of `other` are copied.
+    fn collect_merged<O>(
+        &self,
+        other: &O,
+        (a, b): (H256, H256),
+        items: &mut Vec<Item<K, V, N>>,
+        removals: &mut Vec<Reversible<K, V, N>>,
+        copies: &mut Vec<StoreOp<K, V, N>>,
+    ) -> Result<()>
+    where
+        O: Store<K, V, N>,
+    {
+        if a == b || b.is_zero() {
+            if !a.is_zero() {
+                let branch = self.store.get_branch_ref(&a)?.ok_or(Error::MissingBranch(a))?;
+                items.push(Item::subtree(*branch.key, branch.key, a));
+            }
+            return Ok(());
+        }
+        let branch_b = other.get_branch_ref(&b)?.ok_or(Error::MissingBranch(b))?;
+        if a.is_zero() {
+            items.push(Item::subtree(*branch_b.key, branch_b.key, b));
+            return copy_subtree(other, b, copies);
+        }
+        let branch_a = self.store.get_branch_ref(&a)?.ok_or(Error::MissingBranch(a))?;
+
+        let (a_is_leaf, b_is_leaf) = (branch_a.is_leaf(&a), branch_b.is_leaf(&b));
+        let (height_a, height_b) = (branch_a.fork_height, branch_b.fork_height);
+        let prefix_a = branch_a.key.parent_path(height_a);
+        if a_is_leaf && b_is_leaf && branch_a.key == branch_b.key {
+            // same key, and the hashes differ
+            return Err(Error::ConflictingLeaf(a));
+        } else if !a_is_leaf
+            && !b_is_leaf
+            && height_a == height_b
+            && prefix_a == branch_b.key.parent_path(height_b)
+        {
+            removals.push(Reversible::remove_branch(a, Some(BranchNode::clone(&branch_a))));
+            let (left_a, right_a) = branch_a.branch(height_a);
+            let (left_b, right_b) = branch_b.branch(height_b);
+            self.collect_merged(other, (*left_a, *left_b), items, removals, copies)?;
+            self.collect_merged(other, (*right_a, *right_b), items, removals, copies)?;
+        } else if branch_a.covers(&a, &branch_b, &b) {
+            // b lies below one child of a, the other child is kept
+            removals.push(Reversible::remove_branch(a, Some(BranchNode::clone(&branch_a))));
+            let (left, right) = branch_a.branch(height_a);
+            if branch_b.key.get_bit(height_a) {
+                items.push(Item::subtree(prefix_a, branch_a.key, *left));
+                self.collect_merged(other, (*right, b), items, removals, copies)?;
+            } else {
+                self.collect_merged(other, (*left, b), items, removals, copies)?;
+                let mut path = prefix_a;
+                path.set_bit(height_a);
+                items.push(Item::subtree(path, branch_a.key, *right));
+            }
+        } else if branch_b.covers(&b, &branch_a, &a) {
+            // a lies below one child of b, the other child is copied
+            let (left, right) = branch_b.branch(height_b);
+            let mut path = branch_b.key.parent_path(height_b);
+            if branch_a.key.get_bit(height_b) {
+                items.push(Item::subtree(path, branch_b.key, *left));
+                copy_subtree(other, *left, copies)?;
+                self.collect_merged(other, (a, *right), items, removals, copies)?;
+            } else {
+                self.collect_merged(other, (a, *left), items, removals, copies)?;
+                path.set_bit(height_b);
+                items.push(Item::subtree(path, branch_b.key, *right));
+                copy_subtree(other, *right, copies)?;
+            }
+        } else {
+            // disjoint subtrees
+            let item_a = Item::subtree(*branch_a.key, branch_a.key, a);
+            let item_b = Item::subtree(*branch_b.key, branch_b.key, b);
+            if *branch_a.key < *branch_b.key {
+                items.extend([item_a, item_b]);
+            } else {
+                items.extend([item_b, item_a]);
+            }
+            copy_subtree(other, b, copies)?;
+        }
+        Ok(())
+    }
+}
+
+/// Collect the writes that copy the subtree under `node` out of `store`,
+/// children before their parents
+fn copy_subtree<K, V, S, const N: usize>(
+    store: &S,
+    node: H256,
+    copies: &mut Vec<StoreOp<K, V, N>>,
+) -> Result<()>
+where
+    K: Key<N>,
+    V: Value,
+    S: Store<K, V, N>,
+{
+    // (node, its branch once the children are copied)
+    let mut stack = vec![(node, None)];
+    while let Some((node, copied)) = stack.pop() {
+        if let Some(branch) = copied {
+            copies.push(StoreOp::InsertBranch(node, branch));
+            continue;
+        }
+        let branch = store
+            .get_branch(&node)?
+            .ok_or(Error::MissingBranch(node))?;
+        if branch.is_leaf(&node) {
+            let leaf = store.get_leaf(&node)?.ok_or(Error::MissingLeaf(node))?;
+            copies.push(StoreOp::InsertLeaf(node, leaf));
+            copies.push(StoreOp::InsertBranch(node, branch));
+        } else {
+            let (left, right) = branch.branch(branch.fork_height);
+            let children = [(*left, None), (*right, None)];
+            stack.push((node, Some(branch)));
+            stack.extend(children);
+        }
+    }
+    Ok(())
+}
+
+/// Node reads the traversals are written against
+///
+/// The traversals are async so that `AsyncStore`s can drive them too. The
+/// reads of a `Store` are always ready, and `resolve` runs them to the end
+/// on the current thread.
+pub(crate) trait NodeReader<K, V, const N: usize>
+where
+    K: Key<N>,
+{
+    /// A branch read from the store, borrowed from it where the store allows
+    type Branch: Deref<Target = BranchNode<K, N>>;
+    /// A leaf read from the store, borrowed from it where the store allows
+    type Leaf: Deref<Target = LeafNode<K, V, N>>;
+
+    async fn branch(&self, node: &H256) -> Result<Option<Self::Branch>>;
+    async fn leaf(&self, leaf_hash: &H256) -> Result<Option<Self::Leaf>>;
+}
+
+/// Reads nodes from a `Store`
+pub(crate) struct StoreReader<'a, S>(pub &'a S);
+
+impl<'a, K, V, S, const N: usize> NodeReader<K, V, N> for StoreReader<'a, S>
+where
+    K: Key<N> + 'a,
+    V: Clone + 'a,
+    S: Store<K, V, N>,
+{
+    type Branch = Cow<'a, BranchNode<K, N>>;
+    type Leaf = Cow<'a, LeafNode<K, V, N>>;
+
+    async fn branch(&self, node: &H256) -> Result<Option<Self::Branch>> {
+        self.0.get_branch_ref(node)
+    }
+    async fn leaf(&self, leaf_hash: &H256) -> Result<Option<Self::Leaf>> {
+        self.0.get_leaf_ref(leaf_hash)
+    }
+}
+
+/// Run a traversal over a `StoreReader`, which never has to wait
+pub(crate) fn resolve<F: Future>(future: F) -> F::Output {
+    let future = pin!(future);
+    match future.poll(&mut Context::from_waker(Waker::noop())) {
+        Poll::Ready(output) => output,
+        Poll::Pending => unreachable!("store reads are always ready"),
+    }
+}
+
+/// A write to the store
+#[derive(Debug, Eq, PartialEq, Clone)]
+#[cfg_attr(feature = "borsh", derive(BorshSerialize, BorshDeserialize))]
+pub enum StoreOp<K, V, const N: usize>
+where
+    K: Key<N>,
+{
+    InsertBranch(H256, BranchNode<K, N>),
+    InsertLeaf(H256, LeafNode<K, V, N>),
+    RemoveBranch(H256),
+    RemoveLeaf(H256),
+}
+
+impl<K, V, const N: usize> StoreOp<K, V, N>
+where
+    K: Key<N>,
+{
+    pub(crate) fn apply<S: Store<K, V, N>>(self, store: &mut S) -> Result<()> {
+        match self {
+            StoreOp::InsertBranch(node, branch) => store.insert_branch(node, branch),
+            StoreOp::InsertLeaf(leaf_hash, leaf) => store.insert_leaf(leaf_hash, leaf),
+            StoreOp::RemoveBranch(node) => store.remove_branch(&node),
+            StoreOp::RemoveLeaf(leaf_hash) => store.remove_leaf(&leaf_hash),
+        }
+    }
+}
+
+/// A write to the store and the write that takes it back
+///
+/// The traversals that plan writes read the nodes they replace on the way,
+/// so undoing a failed write needs no further reads.
+#[derive(Debug, Clone)]
+pub(crate) struct Reversible<K, V, const N: usize>
+where
+    K: Key<N>,
+{
+    pub(crate) op: StoreOp<K, V, N>,
+    /// None if the write changes nothing
+    pub(crate) undo: Option<StoreOp<K, V, N>>,
+}
+
+impl<K, V, const N: usize> Reversible<K, V, N>
+where
+    K: Key<N>,
+{
+    /// Remove the branch `node`, which holds `old`
+    pub(crate) fn remove_branch(node: H256, old: Option<BranchNode<K, N>>) -> Self {
+        Reversible {
+            op: StoreOp::RemoveBranch(node),
+            undo: old.map(|branch| StoreOp::InsertBranch(node, branch)),
+        }
+    }
+
+    /// Remove the leaf `leaf_hash`, which holds `old`
+    pub(crate) fn remove_leaf(leaf_hash: H256, old: Option<LeafNode<K, V, N>>) -> Self {
+        Reversible {
+            op: StoreOp::RemoveLeaf(leaf_hash),
+            undo: old.map(|leaf| StoreOp::InsertLeaf(leaf_hash, leaf)),
+        }
+    }
+
+    /// Write a node the store does not hold, either because it never did or
+    /// because an earlier write removed it
+    pub(crate) fn on_missing(op: StoreOp<K, V, N>) -> Self {
+        let undo = match &op {
+            StoreOp::InsertBranch(node, _) => Some(StoreOp::RemoveBranch(*node)),
+            StoreOp::InsertLeaf(leaf_hash, _) => Some(StoreOp::RemoveLeaf(*leaf_hash)),
+            StoreOp::RemoveBranch(_) | StoreOp::RemoveLeaf(_) => None,
+        };
+        Reversible { op, undo }
+    }
+}
+
+/// Node writes, the counterpart of `NodeReader`
+pub(crate) trait NodeWriter<K, V, const N: usize>
+where
+    K: Key<N>,
+{
+    async fn write(&mut self, op: StoreOp<K, V, N>) -> Result<()>;
+}
+
+/// Writes nodes to a `Store`
+pub(crate) struct StoreWriter<'a, S>(pub &'a mut S);
+
+impl<'a, K, V, S, const N: usize> NodeWriter<K, V, N> for StoreWriter<'a, S>
+where
+    K: Key<N>,
+    S: Store<K, V, N>,
+{
+    async fn write(&mut self, op: StoreOp<K, V, N>) -> Result<()> {
+        op.apply(self.0)
+    }
+}
+
+/// Make the writes in order
+///
+/// When a write fails, the writes made so far are undone in reverse order
+/// and the error is returned, so the store is left as it was. Only when
+/// undoing fails as well is the store left half written, and the error of
+/// the undo is returned.
+pub(crate) async fn write_all<K, V, W, const N: usize>(
+    writer: &mut W,
+    writes: impl IntoIterator<Item = Reversible<K, V, N>>,
+) -> Result<()>
+where
+    K: Key<N>,
+    W: NodeWriter<K, V, N>,
+{
+    let mut undo = Vec::new();
+    for write in writes {
+        if let Err(err) = writer.write(write.op).await {
+            for op in undo.into_iter().rev() {
+                writer.write(op).await?;
+            }
+            return Err(err);
+        }
+        undo.extend(write.undo);
+    }
+    Ok(())
+}
+
+/// Read-only traversals of the tree under a given root
+pub(crate) struct TreeView<H, K, V, R, const N: usize> {
+    reader: R,
+    root: H256,
+    phantom: PhantomData<(H, K, V)>,
+}
+
+impl<H, K, V, R, const N: usize> TreeView<H, K, V, R, N>
+where
+    H: Hasher + Default,
+    K: Key<N>,
+    V: Value + core::cmp::PartialEq,
+    R: NodeReader<K, V, N>,
+{
+    pub(crate) fn new(reader: R, root: H256) -> Self {
+        TreeView {
+            reader,
+            root,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Walk the path of `key` and compute the new root and the store writes
+    /// that `SparseMerkleTree::update` applies
+    pub(crate) async fn plan_update(
+        &self,
+        key: K,
+        value: V,
+    ) -> Result<(H256, Vec<Reversible<K, V, N>>)> {
         // store the path, sparse index will ignore zero members
         let mut path: BTreeMap<_, _> = Default::default();
+        // branches left behind by the walk, removed once all reads are done
+        let mut stale_branches: Vec<(H256, BranchNode<K, N>)> = Vec::new();
         // walk path from root to leaf
         let mut node = self.root;
-        let mut branch = self.store.get_branch(&node)?;
+        let mut branch = self.reader.branch(&node).await?;
         let mut height = branch
             .as_ref()
             .map(|b| max(b.key.fork_height(&key), b.fork_height))
             .unwrap_or(0);
         // branch.is_none() represents the descendants are zeros, so we can stop the
         // loop
-        while branch.is_some() {
-            let branch_node = branch.unwrap();
+        while let Some(branch_node) = branch.as_deref() {
             let fork_height = max(key.fork_height(&branch_node.key), branch_node.fork_height);
             if height > branch_node.fork_height {
                 // the merge height is higher than node, so we do not need to remove node's
@@ -146,7 +1055,7 @@ where
             // branch node is parent if height is less than branch_node's height
             // remove it from store
             if branch_node.fork_height > 0 {
-                self.store.remove_branch(&node)?;
+                stale_branches.push((node, branch_node.clone()));
             }
             let (left, right) = branch_node.branch(height);
             let is_right = key.get_bit(height);
@@ -165,38 +1074,23 @@ where
             };
             path.insert(height, sibling);
             // get next branch and fork_height
-            branch = self.store.get_branch(&node)?;
+            branch = self.reader.branch(&node).await?;
             if let Some(branch_node) = branch.as_ref() {
                 height = max(key.fork_height(&branch_node.key), branch_node.fork_height);
             }
         }
-        // delete previous leaf
-        if let Some(leaf) = self.store.get_leaf(&node)? {
-            if leaf.key == key {
-                self.store.remove_leaf(&node)?;
-                self.store.remove_branch(&node)?;
-            }
-        }
+        // delete previous leaf, the walk stopped at its branch
+        let stale_leaf = match self.reader.leaf(&node).await? {
+            Some(leaf) if leaf.key == key => Some((node, LeafNode::clone(&leaf))),
+            _ => None,
+        };
 
-        // compute and store new leaf
+        // compute new leaf
         let mut node = hash_leaf::<H, K, V, N>(&key, &value);
-        // notice when value is zero the leaf is deleted, so we do not need to store it
-        if !node.is_zero() {
-            self.store.insert_leaf(node, LeafNode { key, value })?;
-
-            // build at least one branch for leaf
-            self.store.insert_branch(
-                node,
-                BranchNode {
-                    key,
-                    fork_height: 0,
-                    node,
-                    sibling: H256::zero(),
-                },
-            )?;
-        }
+        let leaf_hash = node;
 
         // recompute the tree from top to bottom
+        let mut branches = Vec::with_capacity(path.len());
         while !path.is_empty() {
             // pop from path
             let height = path.iter().next().map(|(height, _)| *height).unwrap();
@@ -217,21 +1111,53 @@ where
                     node,
                     key,
                 };
-                self.store.insert_branch(parent, branch_node)?;
+                branches.push((parent, branch_node));
             }
             node = parent;
         }
-        self.root = node;
-        Ok(&self.root)
+
+        // the changes to the store, in the order they are applied
+        let mut ops = Vec::with_capacity(stale_branches.len() + branches.len() + 4);
+        ops.extend(
+            stale_branches
+                .into_iter()
+                .map(|(node, branch)| Reversible::remove_branch(node, Some(branch))),
+        );
+        if let Some((stale, leaf)) = stale_leaf {
+            ops.push(Reversible::remove_leaf(stale, Some(leaf)));
+            ops.push(Reversible::remove_branch(stale, branch.as_deref().cloned()));
+        }
+        // notice when value is zero the leaf is deleted, so we do not need to store it
+        if !leaf_hash.is_zero() {
+            ops.push(Reversible::on_missing(StoreOp::InsertLeaf(
+                leaf_hash,
+                LeafNode { key, value },
+            )));
+
+            // build at least one branch for leaf
+            ops.push(Reversible::on_missing(StoreOp::InsertBranch(
+                leaf_hash,
+                BranchNode {
+                    key,
+                    fork_height: 0,
+                    node: leaf_hash,
+                    sibling: H256::zero(),
+                },
+            )));
+        }
+        ops.extend(branches.into_iter().map(|(parent, branch_node)| {
+            Reversible::on_missing(StoreOp::InsertBranch(parent, branch_node))
+        }));
+        Ok((node, ops))
     }
 
     /// Get value of a leaf
     /// return zero value if leaf not exists
-    pub fn get(&self, key: &K) -> Result<V> {
+    pub(crate) async fn get(&self, key: &K) -> Result<V> {
         let mut node = self.root;
         // children must equal zero when parent equals zero
         while !node.is_zero() {
-            let branch_node = match self.store.get_branch(&node)? {
+            let branch_node = match self.reader.branch(&node).await? {
                 Some(branch_node) => branch_node,
                 None => {
                     break;
@@ -250,23 +1176,466 @@ where
             return Ok(V::zero());
         }
         // get leaf node
-        match self.store.get_leaf(&node)? {
-            Some(leaf) if &leaf.key == key => Ok(leaf.value),
-            _ => Ok(V::zero()),
+        let value = match self.reader.leaf(&node).await? {
+            Some(leaf) if &leaf.key == key => leaf.value.clone(),
+            _ => V::zero(),
+        };
+        Ok(value)
+    }
+
+    /// Get the values of several leaves at once, in the order of `keys`
+    ///
+    /// Equivalent to calling `get` for every key, but the keys share a
+    /// single walk from the root and split up where their paths diverge.
+    pub(crate) async fn get_many(&self, keys: &[K]) -> Result<Vec<V>> {
+        let mut values: Vec<V> = keys.iter().map(|_| V::zero()).collect();
+        let mut indices: Vec<usize> = (0..keys.len()).collect();
+        indices.sort_unstable_by_key(|i| *keys[*i]);
+
+        // (node, indices of the keys whose path goes through node, reached a leaf)
+        let mut stack = Vec::with_capacity(EXPECTED_PATH_SIZE);
+        stack.push((self.root, indices, false));
+        while let Some((node, indices, at_leaf)) = stack.pop() {
+            // children must equal zero when parent equals zero
+            if node.is_zero() || indices.is_empty() {
+                continue;
+            }
+            if !at_leaf {
+                if let Some(branch_node) = self.reader.branch(&node).await? {
+                    let fork_height = branch_node.fork_height;
+                    let (left, right) = branch_node.branch(fork_height);
+                    let (right_indices, left_indices): (Vec<_>, Vec<_>) = indices
+                        .into_iter()
+                        .partition(|i| keys[*i].get_bit(fork_height));
+                    // push right first so keys are resolved in order
+                    stack.push((*right, right_indices, fork_height == 0));
+                    stack.push((*left, left_indices, fork_height == 0));
+                    continue;
+                }
+            }
+            if let Some(leaf) = self.reader.leaf(&node).await? {
+                for i in indices {
+                    if leaf.key == keys[i] {
+                        values[i] = leaf.value.clone();
+                    }
+                }
+            }
+        }
+        Ok(values)
+    }
+
+    /// Find the root of the subtree covering `prefix_key.parent_path(height)`
+    pub(crate) async fn subtree(&self, prefix_key: &K, height: usize) -> Result<H256> {
+        let prefix = prefix_key.parent_path(height);
+        let mut node = self.root;
+        while !node.is_zero() {
+            let branch = self.branch(&node).await?;
+            let fork_height = branch.fork_height;
+            if branch.is_leaf(&node) || fork_height <= height {
+                // the whole subtree of the node lies below the prefix, or
+                // next to it
+                if branch.key.parent_path(height) != prefix {
+                    node = H256::zero();
+                }
+                break;
+            }
+            if branch.key.parent_path(fork_height) != prefix_key.parent_path(fork_height) {
+                // the prefix forks off above the branch
+                node = H256::zero();
+                break;
+            }
+            let (left, right) = branch.branch(fork_height);
+            node = if prefix_key.get_bit(fork_height) {
+                *right
+            } else {
+                *left
+            };
+        }
+        Ok(node)
+    }
+
+    /// Generate proof of the root of the subtree covering
+    /// `prefix_key.parent_path(height)`
+    pub(crate) async fn subtree_proof(
+        &self,
+        prefix_key: &K,
+        height: usize,
+    ) -> Result<SubtreeProof<K, V>> {
+        let (left, node, right) = self.prefix_neighbours(prefix_key, height).await?;
+        if node.is_zero() {
+            return Err(Error::ExistenceProof);
+        }
+        let first = self.leaf(&self.edge_leaf(node, false).await?).await?;
+        let last = self.leaf(&self.edge_leaf(node, true).await?).await?;
+        let left = match left {
+            Some(leaf_hash) => Some(self.leaf(&leaf_hash).await?),
+            None => None,
+        };
+        let right = match right {
+            Some(leaf_hash) => Some(self.leaf(&leaf_hash).await?),
+            None => None,
+        };
+        let mut keys: Vec<K> = left.iter().map(|(k, _v)| *k).collect();
+        keys.push(first.0);
+        if last.0 != first.0 {
+            keys.push(last.0);
+        }
+        keys.extend(right.iter().map(|(k, _v)| *k));
+        let proof = self.merkle_proof(keys).await?;
+        Ok(SubtreeProof {
+            first,
+            last,
+            left,
+            right,
+            proof,
+        })
+    }
+
+    /// Find the last leaf before and the first leaf after the empty subtree
+    /// covering `prefix_key.parent_path(height)`
+    ///
+    /// Returns NonExistenceProof error when the subtree holds leaves.
+    async fn subtree_neighbours(
+        &self,
+        prefix_key: &K,
+        height: usize,
+    ) -> Result<(Option<H256>, Option<H256>)> {
+        let (left, node, right) = self.prefix_neighbours(prefix_key, height).await?;
+        if !node.is_zero() {
+            return Err(Error::NonExistenceProof);
+        }
+        Ok((left, right))
+    }
+
+    /// Find the last leaf before the subtree covering
+    /// `prefix_key.parent_path(height)`, the root of the subtree and the
+    /// first leaf after it
+    async fn prefix_neighbours(
+        &self,
+        prefix_key: &K,
+        height: usize,
+    ) -> Result<(Option<H256>, H256, Option<H256>)> {
+        let prefix = prefix_key.parent_path(height);
+        // the deepest subtrees next to the prefix on either side
+        let (mut left, mut right) = (None, None);
+        let mut subtree = H256::zero();
+        let mut node = self.root;
+        while !node.is_zero() {
+            let branch = self.branch(&node).await?;
+            let fork_height = branch.fork_height;
+            if branch.is_leaf(&node)
+                || fork_height <= height
+                || branch.key.parent_path(fork_height) != prefix_key.parent_path(fork_height)
+            {
+                if branch.key.parent_path(height) == prefix {
+                    subtree = node;
+                    break;
+                }
+                // the subtree of the node lies next to the prefix
+                if *branch.key < prefix {
+                    left = Some(node);
+                } else {
+                    right = Some(node);
+                }
+                break;
+            }
+            let (left_child, right_child) = branch.branch(fork_height);
+            if prefix_key.get_bit(fork_height) {
+                left = Some(*left_child);
+                node = *right_child;
+            } else {
+                right = Some(*right_child);
+                node = *left_child;
+            }
+        }
+
+        // the leaves of those subtrees closest to the prefix
+        let left = match left {
+            Some(node) => Some(self.edge_leaf(node, true).await?),
+            None => None,
+        };
+        let right = match right {
+            Some(node) => Some(self.edge_leaf(node, false).await?),
+            None => None,
+        };
+        Ok((left, subtree, right))
+    }
+
+    /// Find the last leaf, or the first one, in the subtree under `node`
+    async fn edge_leaf(&self, mut node: H256, last: bool) -> Result<H256> {
+        loop {
+            let branch = self.branch(&node).await?;
+            if branch.is_leaf(&node) {
+                return Ok(node);
+            }
+            let (left, right) = branch.branch(branch.fork_height);
+            node = if last { *right } else { *left };
+        }
+    }
+
+    /// Find the leaves with keys in `start..end` in key order, and the last
+    /// leaf before and the first leaf after them
+    async fn range_leaves(
+        &self,
+        start: &K,
+        end: &K,
+    ) -> Result<(Option<H256>, Vec<H256>, Option<H256>)> {
+        let (mut left, mut leaves, mut right) = (None, Vec::new(), None);
+        // the nodes are popped in key order
+        let mut stack = vec![self.root];
+        while let Some(node) = stack.pop() {
+            if node.is_zero() {
+                continue;
+            }
+            let branch = self.branch(&node).await?;
+            let fork_height = branch.fork_height;
+            // the subtree of the node lies before the range, or after it
+            let (before, after) = if branch.is_leaf(&node) {
+                (*branch.key < **start, *branch.key >= **end)
+            } else {
+                let prefix = branch.key.parent_path(fork_height);
+                (prefix < start.parent_path(fork_height), prefix >= **end)
+            };
+            if before {
+                left = Some(node);
+            } else if after {
+                right = Some(node);
+                break;
+            } else if branch.is_leaf(&node) {
+                leaves.push(node);
+            } else {
+                let (left_child, right_child) = branch.branch(fork_height);
+                stack.extend([*right_child, *left_child]);
+            }
+        }
+        let left = match left {
+            Some(node) => Some(self.edge_leaf(node, true).await?),
+            None => None,
+        };
+        let right = match right {
+            Some(node) => Some(self.edge_leaf(node, false).await?),
+            None => None,
+        };
+        Ok((left, leaves, right))
+    }
+
+    /// Generate proof of the leaves with keys in `start..end`
+    pub(crate) async fn range_proof(&self, start: &K, end: &K) -> Result<RangeProof<K, V>> {
+        if **start > **end {
+            return Err(Error::InvalidRange);
+        }
+        let (left, leaf_hashes, right) = self.range_leaves(start, end).await?;
+        let mut leaves = Vec::with_capacity(leaf_hashes.len());
+        for leaf_hash in leaf_hashes.iter() {
+            leaves.push(self.leaf(leaf_hash).await?);
+        }
+        let left = match left {
+            Some(leaf_hash) => Some(self.leaf(&leaf_hash).await?),
+            None => None,
+        };
+        let right = match right {
+            Some(leaf_hash) => Some(self.leaf(&leaf_hash).await?),
+            None => None,
+        };
+        let keys: Vec<K> = left
+            .iter()
+            .chain(leaves.iter())
+            .chain(right.iter())
+            .map(|(k, _v)| *k)
+            .collect();
+        let proof = if keys.is_empty() {
+            None
+        } else {
+            Some(self.merkle_proof(keys).await?)
+        };
+        Ok(RangeProof {
+            leaves,
+            left,
+            right,
+            proof,
+        })
+    }
+
+    /// Generate proof that no leaf lies in the subtree covering
+    /// `prefix_key.parent_path(height)`
+    pub(crate) async fn empty_subtree_proof(
+        &self,
+        prefix_key: &K,
+        height: usize,
+    ) -> Result<EmptySubtreeProof<K, V>> {
+        let (left, right) = self.subtree_neighbours(prefix_key, height).await?;
+        let left = match left {
+            Some(leaf_hash) => Some(self.leaf(&leaf_hash).await?),
+            None => None,
+        };
+        let right = match right {
+            Some(leaf_hash) => Some(self.leaf(&leaf_hash).await?),
+            None => None,
+        };
+        let keys: Vec<K> = left.iter().chain(right.iter()).map(|(k, _v)| *k).collect();
+        let proof = if keys.is_empty() {
+            None
+        } else {
+            Some(self.merkle_proof(keys).await?)
+        };
+        Ok(EmptySubtreeProof { left, right, proof })
+    }
+
+    /// Generate ICS 23 commitment proof that no leaf lies in the subtree
+    /// covering `prefix_key.parent_path(height)`
+    pub(crate) async fn empty_subtree_commitment_proof(
+        &self,
+        prefix_key: &K,
+        height: usize,
+    ) -> Result<CommitmentProof> {
+        let (left, right) = self.subtree_neighbours(prefix_key, height).await?;
+        if left.is_none() && right.is_none() {
+            // an empty tree has no leaves to prove
+            return Err(Error::EmptyProof);
         }
+        let left = match left {
+            Some(leaf_hash) => Some(self.existence_proof(&leaf_hash).await?),
+            None => None,
+        };
+        let right = match right {
+            Some(leaf_hash) => Some(self.existence_proof(&leaf_hash).await?),
+            None => None,
+        };
+        let proof = NonExistenceProof {
+            key: prefix_key.to_vec(),
+            left,
+            right,
+        };
+        Ok(CommitmentProof {
+            proof: Some(Proof::Nonexist(proof)),
+        })
+    }
+
+    /// List the leaves that differ between this root and `other`
+    pub(crate) async fn diff(&self, other: H256) -> Result<Vec<Diff<K, V>>> {
+        enum Walk {
+            Compare(H256, H256),
+            Removed(H256),
+            Added(H256),
+        }
+
+        let mut diffs = Vec::new();
+        // the tasks are popped in key order
+        let mut stack = vec![Walk::Compare(self.root, other)];
+        while let Some(walk) = stack.pop() {
+            let (a, b) = match walk {
+                Walk::Compare(a, b) => (a, b),
+                Walk::Removed(node) | Walk::Added(node) if node.is_zero() => continue,
+                Walk::Removed(node) | Walk::Added(node) => {
+                    let branch = self.branch(&node).await?;
+                    let added = matches!(walk, Walk::Added(_));
+                    if branch.is_leaf(&node) {
+                        let (key, value) = self.leaf(&node).await?;
+                        diffs.push(if added {
+                            Diff::Added(key, value)
+                        } else {
+                            Diff::Removed(key, value)
+                        });
+                    } else {
+                        let (left, right) = branch.branch(branch.fork_height);
+                        for child in [*right, *left] {
+                            stack.push(if added {
+                                Walk::Added(child)
+                            } else {
+                                Walk::Removed(child)
+                            });
+                        }
+                    }
+                    continue;
+                }
+            };
+            if a == b {
+                continue;
+            } else if a.is_zero() {
+                stack.push(Walk::Added(b));
+                continue;
+            } else if b.is_zero() {
+                stack.push(Walk::Removed(a));
+                continue;
+            }
+
+            let branch_a = self.branch(&a).await?;
+            let branch_b = self.branch(&b).await?;
+            let (a_is_leaf, b_is_leaf) = (branch_a.is_leaf(&a), branch_b.is_leaf(&b));
+            if a_is_leaf && b_is_leaf && branch_a.key == branch_b.key {
+                let (key, old) = self.leaf(&a).await?;
+                let (_key, new) = self.leaf(&b).await?;
+                diffs.push(Diff::Modified(key, old, new));
+            } else if !a_is_leaf
+                && !b_is_leaf
+                && branch_a.fork_height == branch_b.fork_height
+                && branch_a.key.parent_path(branch_a.fork_height)
+                    == branch_b.key.parent_path(branch_b.fork_height)
+            {
+                let (left_a, right_a) = branch_a.branch(branch_a.fork_height);
+                let (left_b, right_b) = branch_b.branch(branch_b.fork_height);
+                stack.push(Walk::Compare(*right_a, *right_b));
+                stack.push(Walk::Compare(*left_a, *left_b));
+            } else if branch_a.covers(&a, &branch_b, &b) {
+                // b lies below one child of a, the other child is gone
+                let (left, right) = branch_a.branch(branch_a.fork_height);
+                if branch_b.key.get_bit(branch_a.fork_height) {
+                    stack.push(Walk::Compare(*right, b));
+                    stack.push(Walk::Removed(*left));
+                } else {
+                    stack.push(Walk::Removed(*right));
+                    stack.push(Walk::Compare(*left, b));
+                }
+            } else if branch_b.covers(&b, &branch_a, &a) {
+                // a lies below one child of b, the other child is new
+                let (left, right) = branch_b.branch(branch_b.fork_height);
+                if branch_a.key.get_bit(branch_b.fork_height) {
+                    stack.push(Walk::Compare(a, *right));
+                    stack.push(Walk::Added(*left));
+                } else {
+                    stack.push(Walk::Added(*right));
+                    stack.push(Walk::Compare(a, *left));
+                }
+            } else if *branch_a.key < *branch_b.key {
+                // disjoint subtrees
+                stack.push(Walk::Added(b));
+                stack.push(Walk::Removed(a));
+            } else {
+                stack.push(Walk::Removed(a));
+                stack.push(Walk::Added(b));
+            }
+        }
+        Ok(diffs)
+    }
+
+    async fn branch(&self, node: &H256) -> Result<R::Branch> {
+        self.reader
+            .branch(node)
+            .await?
+            .ok_or(Error::MissingBranch(*node))
+    }
+
+    async fn leaf(&self, leaf_hash: &H256) -> Result<(K, V)> {
+        let leaf = self
+            .reader
+            .leaf(leaf_hash)
+            .await?
+            .ok_or(Error::MissingLeaf(*leaf_hash))?;
+        Ok((leaf.key, leaf.value.clone()))
     }
 
     /// fetch merkle path of key into cache
     /// cache: (height, key) -> node
-    fn fetch_merkle_path(
+    async fn fetch_merkle_path(
         &self,
         key: &K,
         cache: &mut BTreeMap<(usize, InternalKey<N>), H256>,
     ) -> Result<()> {
         let mut node = self.root;
         let mut height = self
-            .store
-            .get_branch(&node)?
+            .reader
+            .branch(&node)
+            .await?
             .map(|b| max(b.key.fork_height(key), b.fork_height))
             .unwrap_or(0);
         while !node.is_zero() {
@@ -274,7 +1643,7 @@ where
             if node.is_zero() {
                 break;
             }
-            match self.store.get_branch(&node)? {
+            match self.reader.branch(&node).await? {
                 Some(branch_node) => {
                     if height > branch_node.fork_height {
                         let fork_height =
@@ -313,8 +1682,8 @@ where
                         // mark sibling's index, sibling on the right path.
                         sibling_key.set_bit(height);
                     };
-                    cache.insert((height as usize, sibling_key), sibling);
-                    if let Some(branch_node) = self.store.get_branch(&node)? {
+                    cache.insert((height, sibling_key), sibling);
+                    if let Some(branch_node) = self.reader.branch(&node).await? {
                         let fork_height =
                             max(key.fork_height(&branch_node.key), branch_node.fork_height);
                         height = fork_height;
@@ -327,7 +1696,7 @@ where
     }
 
     /// Generate merkle proof
-    pub fn merkle_proof(&self, mut keys: Vec<K>) -> Result<MerkleProof> {
+    pub(crate) async fn merkle_proof(&self, mut keys: Vec<K>) -> Result<MerkleProof> {
         if keys.is_empty() {
             return Err(Error::EmptyKeys);
         }
@@ -338,7 +1707,7 @@ where
         // fetch all merkle path
         let mut cache: BTreeMap<(usize, _), H256> = Default::default();
         for k in &keys {
-            self.fetch_merkle_path(k, &mut cache)?;
+            self.fetch_merkle_path(k, &mut cache).await?;
         }
 
         // (node, height)
@@ -413,13 +1782,102 @@ where
         Ok(MerkleProof::new(leaves_path, proof))
     }
 
+    /// Generate compiled merkle proof, in a single walk down to the keys
+    ///
+    /// The program is the one `compile_keys` gives for the `merkle_proof`
+    /// of the same keys.
+    pub(crate) async fn compiled_merkle_proof(
+        &self,
+        mut keys: Vec<K>,
+    ) -> Result<CompiledMerkleProof> {
+        enum Task {
+            /// Emit the program of the keys in the subtree of a node
+            Visit(H256, Range<usize>),
+            Emit(Instruction),
+        }
+
+        if keys.is_empty() {
+            return Err(Error::EmptyKeys);
+        }
+        keys.sort_unstable_by_key(|k| **k);
+        check_unique_keys(keys.iter().map(|k| &**k))?;
+
+        let mut program = CompiledMerkleProofBuilder::new();
+        // the tasks are popped in the order of the program
+        let mut tasks = vec![Task::Visit(self.root, 0..keys.len())];
+        while let Some(task) = tasks.pop() {
+            let (node, range) = match task {
+                Task::Visit(node, range) => (node, range),
+                Task::Emit(instruction) => {
+                    program.push(instruction);
+                    continue;
+                }
+            };
+            let (first, last) = (*keys[range.start], *keys[range.end - 1]);
+            // the highest height the keys and the subtree differ at, and the
+            // nodes on both sides of it
+            let (height, left, right) = if node.is_zero() {
+                if range.len() == 1 {
+                    program.leaf();
+                    continue;
+                }
+                (first.fork_height(&last), H256::zero(), H256::zero())
+            } else {
+                let branch = self.branch(&node).await?;
+                let fork_height = branch.fork_height;
+                let prefix = branch.key.parent_path(fork_height);
+                if !branch.is_leaf(&node)
+                    && first.parent_path(fork_height) == prefix
+                    && last.parent_path(fork_height) == prefix
+                {
+                    // the keys lie in the subtree, split it at its fork
+                    let (left, right) = branch.branch(fork_height);
+                    (fork_height, *left, *right)
+                } else {
+                    let (lowest, highest) = (min(first, *branch.key), max(last, *branch.key));
+                    if lowest == highest {
+                        program.leaf();
+                        continue;
+                    }
+                    let height = lowest.fork_height(&highest);
+                    if branch.key.get_bit(height) {
+                        (height, H256::zero(), node)
+                    } else {
+                        (height, node, H256::zero())
+                    }
+                }
+            };
+            let split = range.start
+                + keys[range.clone()].partition_point(|k| !k.get_bit(height));
+            let (left_keys, right_keys) = (range.start..split, split..range.end);
+            if left_keys.is_empty() {
+                tasks.push(Task::Emit(Instruction::Proof {
+                    height,
+                    sibling: left,
+                }));
+                tasks.push(Task::Visit(right, right_keys));
+            } else if right_keys.is_empty() {
+                tasks.push(Task::Emit(Instruction::Proof {
+                    height,
+                    sibling: right,
+                }));
+                tasks.push(Task::Visit(left, left_keys));
+            } else {
+                tasks.push(Task::Emit(Instruction::Merge { height }));
+                tasks.push(Task::Visit(right, right_keys));
+                tasks.push(Task::Visit(left, left_keys));
+            }
+        }
+        Ok(program.build())
+    }
+
     /// Generate ICS 23 commitment proof for the existing key
-    pub fn membership_proof(&self, key: &K) -> Result<CommitmentProof> {
-        let value = self.get(key)?;
+    pub(crate) async fn membership_proof(&self, key: &K) -> Result<CommitmentProof> {
+        let value = self.get(key).await?;
         if value == V::zero() {
             return Err(Error::ExistenceProof);
         }
-        let merkle_proof = self.merkle_proof(vec![*key])?;
+        let merkle_proof = self.merkle_proof(vec![*key]).await?;
         let existence_proof =
             proof_ics23::convert(merkle_proof, key, &value, H::hash_op())?;
         Ok(CommitmentProof {
@@ -428,28 +1886,29 @@ where
     }
 
     /// Generate ICS 23 commitment proof for the non-existing key
-    pub fn non_membership_proof(&self, key: &K) -> Result<CommitmentProof> {
-        let value = self.get(key)?;
+    pub(crate) async fn non_membership_proof(&self, key: &K) -> Result<CommitmentProof> {
+        let value = self.get(key).await?;
         if value != V::zero() {
             return Err(Error::NonExistenceProof);
         }
 
         // fetch all merkle path
         let mut cache: BTreeMap<(usize, _), H256> = Default::default();
-        self.fetch_merkle_path(key, &mut cache)?;
+        self.fetch_merkle_path(key, &mut cache).await?;
         let mut left = None;
         let mut right = None;
         for (_, node) in cache.iter() {
             let branch = self
-                .store
-                .get_branch(node)?
+                .reader
+                .branch(node)
+                .await?
                 .expect("the forked branch should exist");
             let fork_height = key.fork_height(&branch.key);
             let is_right = key.get_bit(fork_height);
             if is_right && left.is_none() {
                 // get the left which is the most right in the left subtree
                 let mut n = *node;
-                while let Some(branch) = self.store.get_branch(&n)? {
+                while let Some(branch) = self.reader.branch(&n).await? {
                     if branch.fork_height == 0 {
                         break;
                     }
@@ -460,18 +1919,11 @@ where
                         *right_node
                     };
                 }
-                let leaf = self.store.get_leaf(&n)?.expect("the leaf should exist");
-                let merkle_proof = self.merkle_proof(vec![leaf.key])?;
-                left = Some(proof_ics23::convert(
-                    merkle_proof,
-                    &leaf.key,
-                    &leaf.value,
-                    H::hash_op(),
-                )?);
+                left = Some(self.existence_proof(&n).await?);
             } else if !is_right && right.is_none() {
                 // get the right which is the most left in the right subtree
                 let mut n = *node;
-                while let Some(branch) = self.store.get_branch(&n)? {
+                while let Some(branch) = self.reader.branch(&n).await? {
                     if branch.fork_height == 0 {
                         break;
                     }
@@ -482,14 +1934,7 @@ where
                         *left_node
                     };
                 }
-                let leaf = self.store.get_leaf(&n)?.expect("the leaf should exist");
-                let merkle_proof = self.merkle_proof(vec![leaf.key])?;
-                right = Some(proof_ics23::convert(
-                    merkle_proof,
-                    &leaf.key,
-                    &leaf.value,
-                    H::hash_op(),
-                )?);
+                right = Some(self.existence_proof(&n).await?);
             }
             if left.is_some() && right.is_some() {
                 break;
@@ -505,76 +1950,14 @@ where
         })
     }
 
-    /// Recompute the root of the merkle tree from the store. Check if it agrees with the
-    /// root in `self`.
-    pub fn validate(&self) -> bool {
-        // handle case when tree is empty
-        if self.store.size() == 0 {
-            return self.root == H256::zero()
-        }
-
-        let sorted_leaves = self.store
-            .sorted_leaves()
-            .map(|(k, v)| (k, v.clone()))
-            .collect::<Vec<_>>();
-        // iterator over consecutive pairs of leaves
-        let pairs = sorted_leaves
-            .iter()
-            .tuple_windows::<(_, _)>();
-
-        // construct a vector of nodes and distance to next node
-        let mut leaves = Vec::with_capacity(self.store.size());
-        for ((k1, v1), (k2, _)) in pairs {
-            let height = k1.fork_height(k2);
-            let hash = hash_leaf::<H, K, V, N>(k1, v1);
-            leaves.push((hash, height));
-        }
-        let (last_k, last_v) = sorted_leaves
-            .last()
-            .map(|(k, v)| (k, v))
-            .unwrap();
-        let last = hash_leaf::<H, K, V, N>(last_k, last_v);
-        if leaves.is_empty() {
-            return self.root == last;
-        }
-        leaves.push((last, usize::MAX));
-
-        let mut left: usize = 0;
-        let mut right: usize = 1;
-        let mut merged = Default::default();
-
-        // stack of previous `left` indexes that are yet to be merged
-        let mut prev: Vec<usize> = Vec::with_capacity(leaves.len() / 2);
-
-        // Iterate finding the first node `left` such that `left+1` (`right`) is
-        // its closest neighbor and vice versa, merging them until a single node
-        // remains.
-        while right < leaves.len() {
-            if leaves[left].1 < leaves[right].1 {
-                loop {
-                    // perform merge
-                    merged = merge::<H>(&leaves[left].0, &leaves[right].0);
-                    leaves[right].0 = merged;
-
-                    // check previous `left` node next (if present)
-                    match prev.last() {
-                        Some(&idx) if leaves[idx].1 < leaves[right].1 => {
-                            left = idx;
-                            _ = prev.pop();
-                            continue;
-                        }
-                        _ => {
-                            break;
-                        }
-                    }
-                }
-            } else {
-                prev.push(left);
-            }
-            left = right;
-            right += 1;
-        }
-        // check that the recovered root matches the precomputed one
-        merged == self.root
+    /// ICS 23 existence proof of the leaf stored under `leaf_hash`
+    async fn existence_proof(&self, leaf_hash: &H256) -> Result<ExistenceProof> {
+        let leaf = self
+            .reader
+            .leaf(leaf_hash)
+            .await?
+            .expect("the leaf should exist");
+        let merkle_proof = self.merkle_proof(vec![leaf.key]).await?;
+        proof_ics23::convert(merkle_proof, &leaf.key, &leaf.value, H::hash_op())
     }
 }